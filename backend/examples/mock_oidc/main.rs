@@ -0,0 +1,251 @@
+//! A minimal OpenID Connect provider for trying single sign-on locally, never use it for anything else.
+//!
+//! It signs in whoever's email address is typed into its login form (or passed as `login_hint`), with
+//! the authorization code flow and PKCE. ID tokens are signed with the RSA key next to this file.
+//! Adding `email_verified=false` next to `login_hint` vouches for the address without verifying it.
+//!
+//! ```sh
+//! cargo run --example mock_oidc
+//! OIDC_ISSUER=http://127.0.0.1:4000 OIDC_CLIENT_ID=ecc cargo run
+//! ```
+//!
+//! `MOCK_OIDC_ADDR` changes where it listens, `127.0.0.1:4000` by default. The backend's tests
+//! include this file as a module and serve [`app`] to log in against.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const KEY: &str = include_str!("key.pem");
+const JWKS: &str = include_str!("jwks.json");
+
+/// What a code was issued for, checked again when it's redeemed
+struct Grant {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: Option<String>,
+    email: String,
+    email_verified: bool,
+}
+
+#[derive(Clone)]
+struct Provider {
+    issuer: String,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    login_hint: Option<String>,
+    email_verified: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    code_verifier: String,
+}
+
+#[tokio::main]
+async fn main() {
+    let addr: SocketAddr = std::env::var("MOCK_OIDC_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:4000".to_string())
+        .parse()
+        .expect("MOCK_OIDC_ADDR is host:port");
+
+    println!("Mock OpenID Connect provider on http://{}", addr);
+    axum::Server::bind(&addr)
+        .serve(app(format!("http://{}", addr)).into_make_service())
+        .await
+        .unwrap();
+}
+
+/// The provider, calling itself `issuer`
+pub fn app(issuer: String) -> Router {
+    let provider = Provider {
+        issuer,
+        grants: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/jwks", get(jwks))
+        .with_state(provider)
+}
+
+async fn discovery(State(provider): State<Provider>) -> Json<Value> {
+    Json(json!({
+        "issuer": provider.issuer,
+        "authorization_endpoint": format!("{}/authorize", provider.issuer),
+        "token_endpoint": format!("{}/token", provider.issuer),
+        "jwks_uri": format!("{}/jwks", provider.issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+fn error(status: StatusCode, error: &str) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+/// Without `login_hint` this asks for an email address, and comes back here with it
+async fn authorize(
+    State(provider): State<Provider>,
+    Query(query): Query<AuthorizeQuery>,
+) -> Response {
+    if query.response_type != "code" {
+        return error(StatusCode::BAD_REQUEST, "unsupported_response_type");
+    }
+    let code_challenge = match (
+        &query.code_challenge,
+        query.code_challenge_method.as_deref(),
+    ) {
+        (Some(challenge), Some("S256")) => challenge.clone(),
+        _ => return error(StatusCode::BAD_REQUEST, "invalid_request"),
+    };
+
+    let email = match query.login_hint.as_deref().map(str::trim) {
+        Some(email) if !email.is_empty() => email.to_string(),
+        _ => return login_form(&query),
+    };
+
+    let code = Uuid::new_v4().to_string();
+    provider.grants.lock().unwrap().insert(
+        code.clone(),
+        Grant {
+            client_id: query.client_id,
+            redirect_uri: query.redirect_uri.clone(),
+            code_challenge,
+            nonce: query.nonce,
+            email,
+            email_verified: query.email_verified.unwrap_or(true),
+        },
+    );
+
+    let mut redirect = reqwest::Url::parse(&query.redirect_uri).expect("redirect_uri is a URL");
+    redirect.query_pairs_mut().append_pair("code", &code);
+    if let Some(state) = &query.state {
+        redirect.query_pairs_mut().append_pair("state", state);
+    }
+    Redirect::to(redirect.as_str()).into_response()
+}
+
+fn login_form(query: &AuthorizeQuery) -> Response {
+    let hidden = [
+        ("response_type", Some(&query.response_type)),
+        ("client_id", Some(&query.client_id)),
+        ("redirect_uri", Some(&query.redirect_uri)),
+        ("state", query.state.as_ref()),
+        ("nonce", query.nonce.as_ref()),
+        ("code_challenge", query.code_challenge.as_ref()),
+        (
+            "code_challenge_method",
+            query.code_challenge_method.as_ref(),
+        ),
+    ]
+    .iter()
+    .filter_map(|(name, value)| {
+        value.map(|value| {
+            format!(
+                r#"<input type="hidden" name="{}" value="{}">"#,
+                name,
+                html_escape::encode_double_quoted_attribute(value)
+            )
+        })
+    })
+    .collect::<String>();
+
+    Html(format!(
+        r#"<!DOCTYPE html><html><body><h1>Mock identity provider</h1>
+<form action="/authorize" method="get">{}
+<label for="login_hint">Sign in as:</label> <input type="email" id="login_hint" name="login_hint">
+<input type="submit" value="Sign in"></form></body></html>"#,
+        hidden
+    ))
+    .into_response()
+}
+
+async fn token(State(provider): State<Provider>, Form(form): Form<TokenForm>) -> Response {
+    if form.grant_type != "authorization_code" {
+        return error(StatusCode::BAD_REQUEST, "unsupported_grant_type");
+    }
+    // codes work once
+    let grant = match provider.grants.lock().unwrap().remove(&form.code) {
+        Some(grant) => grant,
+        None => return error(StatusCode::BAD_REQUEST, "invalid_grant"),
+    };
+    let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(form.code_verifier.as_bytes()));
+    if grant.client_id != form.client_id
+        || grant.redirect_uri != form.redirect_uri
+        || grant.code_challenge != challenge
+    {
+        return error(StatusCode::BAD_REQUEST, "invalid_grant");
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = json!({
+        "iss": provider.issuer,
+        "aud": grant.client_id,
+        "sub": format!("mock-{}", &hex_digest(&grant.email)[..16]),
+        "email": grant.email,
+        "email_verified": grant.email_verified,
+        "nonce": grant.nonce,
+        "iat": now,
+        "exp": now + 300,
+    });
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("mock".to_string());
+    let id_token = encode(
+        &header,
+        &claims,
+        &EncodingKey::from_rsa_pem(KEY.as_bytes()).expect("key.pem is an RSA key"),
+    )
+    .unwrap();
+
+    Json(json!({
+        "access_token": Uuid::new_v4().to_string(),
+        "token_type": "Bearer",
+        "expires_in": 300,
+        "id_token": id_token,
+    }))
+    .into_response()
+}
+
+fn hex_digest(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}
+
+async fn jwks() -> Response {
+    (
+        [(axum::http::header::CONTENT_TYPE, "application/json")],
+        JWKS,
+    )
+        .into_response()
+}
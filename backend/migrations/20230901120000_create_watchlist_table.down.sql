@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE watchlist;
+DROP INDEX IF EXISTS neos_api_id_idx;
+ALTER TABLE neos DROP COLUMN ingested_at;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE neos ADD COLUMN IF NOT EXISTS ingested_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE TABLE IF NOT EXISTS watchlist
+(
+    id  serial PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    api_id INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, api_id)
+);
+
+CREATE INDEX IF NOT EXISTS neos_api_id_idx ON neos(api_id);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE inbox_messages;
+DROP TABLE alerts;
+DROP TABLE alert_rules;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE users DROP COLUMN calendar_token;
+ALTER TABLE neos DROP COLUMN close_approach_at;
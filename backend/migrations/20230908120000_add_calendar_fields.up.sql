@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE neos ADD COLUMN IF NOT EXISTS close_approach_at TIMESTAMPTZ;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS calendar_token VARCHAR(64) UNIQUE;
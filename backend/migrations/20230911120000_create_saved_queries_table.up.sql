@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS saved_queries
+(
+    id  serial PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    begin_date VARCHAR(32) NOT NULL,
+    end_date VARCHAR(32),
+    hazardous BOOLEAN,
+    orbiting_body VARCHAR(255),
+    max_miss_distance REAL,
+    share_token VARCHAR(64) UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, name)
+);
@@ -0,0 +1,8 @@
+-- Add down migration script here
+ALTER TABLE users ADD COLUMN admin BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE users SET admin = TRUE WHERE role = 'admin';
+ALTER TABLE users DROP COLUMN role;
+
+DROP TABLE role_permissions;
+DROP TABLE permissions;
+DROP TABLE roles;
@@ -0,0 +1,54 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS roles
+(
+    name VARCHAR(32) PRIMARY KEY,
+    description VARCHAR(255) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS permissions
+(
+    name VARCHAR(64) PRIMARY KEY,
+    description VARCHAR(255) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions
+(
+    role VARCHAR(32) NOT NULL REFERENCES roles(name) ON DELETE CASCADE,
+    permission VARCHAR(64) NOT NULL REFERENCES permissions(name) ON DELETE CASCADE,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO roles(name, description) VALUES
+    ('viewer', 'Reads close approach data and takes part in discussions'),
+    ('analyst', 'Viewer who can also add, edit and import close approaches'),
+    ('moderator', 'Viewer who can moderate comments and ban users'),
+    ('admin', 'Everything, including managing users and their roles');
+
+INSERT INTO permissions(name, description) VALUES
+    ('read_neos', 'View close approaches, watchlists, saved queries and alerts'),
+    ('write_neos', 'Create, edit, delete and import close approaches'),
+    ('comment', 'Post, edit and delete your own comments'),
+    ('moderate_comments', 'Delete anyone''s comments'),
+    ('ban_users', 'Ban users'),
+    ('manage_users', 'Create admins and change user roles');
+
+INSERT INTO role_permissions(role, permission) VALUES
+    ('viewer', 'read_neos'),
+    ('viewer', 'comment'),
+    ('analyst', 'read_neos'),
+    ('analyst', 'comment'),
+    ('analyst', 'write_neos'),
+    ('moderator', 'read_neos'),
+    ('moderator', 'comment'),
+    ('moderator', 'moderate_comments'),
+    ('moderator', 'ban_users'),
+    ('admin', 'read_neos'),
+    ('admin', 'comment'),
+    ('admin', 'write_neos'),
+    ('admin', 'moderate_comments'),
+    ('admin', 'ban_users'),
+    ('admin', 'manage_users');
+
+ALTER TABLE users ADD COLUMN role VARCHAR(32) NOT NULL DEFAULT 'viewer' REFERENCES roles(name);
+UPDATE users SET role = 'admin' WHERE admin;
+ALTER TABLE users DROP COLUMN admin;
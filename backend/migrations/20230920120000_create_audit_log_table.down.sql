@@ -0,0 +1,5 @@
+-- Add down migration script here
+DELETE FROM permissions WHERE name = 'view_audit_log';
+
+DROP TABLE audit_log;
+DROP FUNCTION audit_log_is_append_only;
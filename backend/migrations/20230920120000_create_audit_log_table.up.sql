@@ -0,0 +1,34 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS audit_log
+(
+    id  bigserial PRIMARY KEY,
+    -- No foreign key on purpose, entries have to outlive the accounts they mention
+    actor VARCHAR(255) NOT NULL,
+    action VARCHAR(64) NOT NULL,
+    target VARCHAR(255) NOT NULL,
+    reason TEXT,
+    request_id VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
+
+CREATE OR REPLACE FUNCTION audit_log_is_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_no_changes
+    BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_is_append_only();
+
+CREATE TRIGGER audit_log_no_truncate
+    BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_log_is_append_only();
+
+INSERT INTO permissions(name, description) VALUES
+    ('view_audit_log', 'Read and export the audit log');
+
+INSERT INTO role_permissions(role, permission) VALUES
+    ('admin', 'view_audit_log');
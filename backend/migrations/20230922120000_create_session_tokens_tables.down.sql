@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE revoked_access_tokens;
+DROP TABLE refresh_tokens;
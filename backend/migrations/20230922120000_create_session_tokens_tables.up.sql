@@ -0,0 +1,23 @@
+-- Add up migration script here
+-- Refresh tokens are only ever stored as their SHA-256 hash. Every rotation stays in the family of the login
+-- it started from, so a reused (stolen) token can revoke the whole chain
+CREATE TABLE IF NOT EXISTS refresh_tokens
+(
+    id          SERIAL PRIMARY KEY,
+    user_id     INTEGER      NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash  VARCHAR(64)  NOT NULL UNIQUE,
+    family      VARCHAR(36)  NOT NULL,
+    expires_at  TIMESTAMPTZ  NOT NULL,
+    revoked_at  TIMESTAMPTZ,
+    created_at  TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_idx ON refresh_tokens (family);
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_idx ON refresh_tokens (user_id);
+
+-- Access tokens revoked before they expire, by jti. Rows are useless once expires_at has passed
+CREATE TABLE IF NOT EXISTS revoked_access_tokens
+(
+    jti         VARCHAR(36)  PRIMARY KEY,
+    expires_at  TIMESTAMPTZ  NOT NULL
+);
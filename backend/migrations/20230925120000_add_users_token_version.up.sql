@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Copied into every access token. Bumping it makes all of the user's outstanding tokens invalid
+ALTER TABLE users ADD COLUMN IF NOT EXISTS token_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,16 @@
+-- Add up migration script here
+-- Only the SHA-256 of each key is stored, the key itself is shown once when it is created
+CREATE TABLE IF NOT EXISTS api_keys
+(
+    id            SERIAL PRIMARY KEY,
+    user_id       INTEGER       NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name          VARCHAR(100)  NOT NULL,
+    prefix        VARCHAR(16)   NOT NULL,
+    key_hash      VARCHAR(64)   NOT NULL UNIQUE,
+    scopes        TEXT[]        NOT NULL,
+    expires_at    TIMESTAMPTZ,
+    last_used_at  TIMESTAMPTZ,
+    created_at    TIMESTAMPTZ   NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS api_keys_user_id_idx ON api_keys (user_id);
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- The TOTP secret is stored as soon as setup starts, but only counts once totp_enabled_at is set.
+-- totp_last_step is the last time step a code was accepted for, so a code can't be used twice
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT;
+
+-- One-time codes for when the authenticator is lost, only stored as their SHA-256 hash
+CREATE TABLE IF NOT EXISTS recovery_codes
+(
+    id          SERIAL PRIMARY KEY,
+    user_id     INTEGER      NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash   VARCHAR(64)  NOT NULL,
+    used_at     TIMESTAMPTZ,
+    created_at  TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id_idx ON recovery_codes (user_id);
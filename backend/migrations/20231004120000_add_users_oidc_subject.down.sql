@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS users_oidc_subject_idx;
+ALTER TABLE users DROP COLUMN oidc_subject;
+ALTER TABLE users DROP COLUMN oidc_issuer;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- Accounts signed in through single sign-on are found by the provider's subject, not their email,
+-- which the provider may let people change
+ALTER TABLE users ADD COLUMN IF NOT EXISTS oidc_issuer TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS oidc_subject TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_oidc_subject_idx ON users (oidc_issuer, oidc_subject);
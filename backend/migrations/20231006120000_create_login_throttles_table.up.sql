@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- Failed logins per account ("account:<email>") and per client address ("ip:<address>").
+-- Emails that have no account are tracked too, so the answers are the same either way
+CREATE TABLE IF NOT EXISTS login_throttles
+(
+    key              TEXT         PRIMARY KEY,
+    failures         INTEGER      NOT NULL,
+    last_failure_at  TIMESTAMPTZ  NOT NULL,
+    locked_until     TIMESTAMPTZ
+);
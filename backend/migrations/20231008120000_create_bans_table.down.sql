@@ -0,0 +1,8 @@
+-- Add down migration script here
+ALTER TABLE users ADD COLUMN banned BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE users SET banned = TRUE
+WHERE id IN (SELECT user_id FROM active_bans);
+
+DROP VIEW IF EXISTS active_bans;
+DROP TABLE IF EXISTS bans;
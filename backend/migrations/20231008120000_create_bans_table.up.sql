@@ -0,0 +1,28 @@
+-- Add up migration script here
+-- Every ban there has been, so lifted and expired ones stay on record
+CREATE TABLE IF NOT EXISTS bans
+(
+    id           SERIAL        PRIMARY KEY,
+    user_id      INTEGER       NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    reason       TEXT,
+    banned_by    VARCHAR(255)  NOT NULL,
+    created_at   TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+    -- NULL bans are permanent
+    expires_at   TIMESTAMPTZ,
+    lifted_at    TIMESTAMPTZ,
+    lifted_by    VARCHAR(255),
+    lift_reason  TEXT
+);
+
+CREATE INDEX IF NOT EXISTS bans_user_id_idx ON bans (user_id, created_at DESC);
+
+-- NOW() is evaluated when the view is queried, so timed bans drop out on their own
+CREATE VIEW active_bans AS
+SELECT * FROM bans
+WHERE lifted_at IS NULL AND (expires_at IS NULL OR expires_at > NOW());
+
+-- Users banned so far stay banned, with no end and no recorded reason
+INSERT INTO bans (user_id, banned_by)
+SELECT id, 'unknown' FROM users WHERE banned;
+
+ALTER TABLE users DROP COLUMN banned;
@@ -0,0 +1,8 @@
+-- Add down migration script here
+-- Comments of deleted accounts have nobody to belong to, replies to them go too
+DELETE FROM comments WHERE user_id IS NULL;
+
+ALTER TABLE comments DROP CONSTRAINT IF EXISTS comments_user_id_fkey;
+ALTER TABLE comments
+    ADD CONSTRAINT comments_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE comments ALTER COLUMN user_id SET NOT NULL;
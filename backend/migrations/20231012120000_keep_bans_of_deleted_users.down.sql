@@ -0,0 +1,10 @@
+-- Add down migration script here
+-- Bans of deleted accounts have nobody to belong to
+DELETE FROM bans WHERE user_id IS NULL;
+
+ALTER TABLE bans DROP CONSTRAINT IF EXISTS bans_user_id_fkey;
+ALTER TABLE bans
+    ADD CONSTRAINT bans_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE bans ALTER COLUMN user_id SET NOT NULL;
+
+ALTER TABLE bans DROP COLUMN IF EXISTS email_hash;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- Bans outlive the banned user's account, so deleting it doesn't wipe the record. The row loses its user
+-- and keeps a SHA-256 of the lowercased email address instead, which the ban history can still be searched by
+ALTER TABLE bans ADD COLUMN IF NOT EXISTS email_hash TEXT;
+
+ALTER TABLE bans ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE bans DROP CONSTRAINT IF EXISTS bans_user_id_fkey;
+ALTER TABLE bans
+    ADD CONSTRAINT bans_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE SET NULL;
@@ -0,0 +1,191 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::neo::Neo;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CalendarQuery {
+    pub hazardous: Option<bool>,
+    #[serde(default)]
+    pub watchlist: bool,
+    /// Calendar clients can't log in, so the feed owner is identified by their secret token
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalendarToken {
+    pub token: String,
+    pub url: String,
+}
+
+/// Renders approaches as an RFC 5545 calendar
+pub fn render(neos: &[Neo], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//Earths Close Calls//Close Approaches//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        "X-WR-CALNAME:Near Earth Object close approaches".to_string(),
+    ];
+
+    let stamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    for neo in neos {
+        lines.extend(event(neo, &stamp));
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+
+    lines
+        .iter()
+        .map(|line| fold(line))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        + "\r\n"
+}
+
+fn event(neo: &Neo, stamp: &str) -> Vec<String> {
+    let mut lines = vec![
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}", uid(neo)),
+        format!("DTSTAMP:{}", stamp),
+    ];
+
+    match (
+        neo.approach_time,
+        NaiveDate::parse_from_str(&neo.approach_date, "%Y-%m-%d"),
+    ) {
+        (Some(time), _) => {
+            lines.push(format!("DTSTART:{}", time.format("%Y%m%dT%H%M%SZ")));
+        }
+        // No exact time stored, fall back to an all day event
+        (None, Ok(date)) => {
+            lines.push(format!("DTSTART;VALUE=DATE:{}", date.format("%Y%m%d")));
+            lines.push(format!(
+                "DTEND;VALUE=DATE:{}",
+                (date + Duration::days(1)).format("%Y%m%d")
+            ));
+        }
+        (None, Err(_)) => {}
+    }
+
+    let hazard = if neo.hazardous_asteroid {
+        " (potentially hazardous)"
+    } else {
+        ""
+    };
+    lines.push(format!(
+        "SUMMARY:{}",
+        escape(&format!("{} close approach{}", neo.designation, hazard))
+    ));
+    lines.push(format!(
+        "DESCRIPTION:{}",
+        escape(&format!(
+            "Designation: {}\nNASA id: {}\nMiss distance: {} miles\nVelocity: {} mph\nOrbiting body: {}\nPotentially hazardous: {}",
+            neo.designation,
+            neo.api_id,
+            neo.miss_distance,
+            neo.velocity,
+            neo.orbiting_body,
+            neo.hazardous_asteroid
+        ))
+    ));
+    lines.push("TRANSP:TRANSPARENT".to_string());
+    lines.push("END:VEVENT".to_string());
+
+    lines
+}
+
+/// Built from what identifies an approach rather than the row id, so re-ingested
+/// rows keep the same UID and calendar clients update the event in place
+fn uid(neo: &Neo) -> String {
+    let body: String = neo
+        .orbiting_body
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect();
+    format!(
+        "{}-{}-{}@earths-close-calls",
+        neo.api_id,
+        neo.approach_date.replace('-', ""),
+        body.to_lowercase()
+    )
+}
+
+/// TEXT value escaping from RFC 5545 section 3.3.11
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Lines longer than 75 octets are folded onto continuation lines starting with a space
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut width = 0;
+
+    for c in line.chars() {
+        let len = c.len_utf8();
+        if width + len > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += len;
+    }
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::neo::NeoId;
+    use chrono::TimeZone;
+
+    fn neo(id: i32, approach_time: Option<DateTime<Utc>>) -> Neo {
+        Neo::new(
+            NeoId(id),
+            3542519,
+            "(2010 PK9)".to_string(),
+            0.06,
+            0.15,
+            true,
+            "2024-03-20".to_string(),
+            69201.99,
+            4140648.5,
+            "Earth".to_string(),
+            approach_time,
+        )
+    }
+
+    #[test]
+    fn renders_timed_and_all_day_events() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let timed = Utc.with_ymd_and_hms(2024, 3, 20, 10, 15, 0).unwrap();
+
+        let ics = render(&[neo(1, Some(timed)), neo(2, None)], now);
+
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.contains("DTSTART:20240320T101500Z\r\n"));
+        assert!(ics.contains("DTSTART;VALUE=DATE:20240320\r\nDTEND;VALUE=DATE:20240321\r\n"));
+        assert!(ics.contains("DTSTAMP:20240301T120000Z\r\n"));
+        assert!(ics.lines().all(|line| line.len() <= 75));
+    }
+
+    #[test]
+    fn uid_does_not_depend_on_row_id() {
+        assert_eq!(uid(&neo(1, None)), uid(&neo(99, None)));
+        assert_eq!(
+            uid(&neo(1, None)),
+            "3542519-20240320-earth@earths-close-calls"
+        );
+    }
+
+    #[test]
+    fn escapes_text_values() {
+        assert_eq!(escape("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+    }
+}
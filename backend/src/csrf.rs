@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use http::{HeaderMap, HeaderValue, Method};
+
+use crate::generate_secret_token;
+use crate::session::{cookie_header, cookie_value};
+
+/// Cookie holding the token the forms have to echo back, the "double submit"
+pub const COOKIE: &str = "csrf_token";
+/// Form field the templates put the token in
+pub const FIELD: &str = "csrf_token";
+/// Header scripts that post with the session cookie can send the token in instead
+pub const HEADER: &str = "x-csrf-token";
+
+const TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+tokio::task_local! {
+    /// The token of the request being handled, for templates rendering forms
+    static TOKEN: String;
+}
+
+/// The request's token from its cookie, or a new one when there is none yet.
+/// The flag says whether it's new and the cookie still has to be set
+pub fn request_token(headers: &HeaderMap) -> (String, bool) {
+    match cookie_value(headers, COOKIE).filter(|token| !token.is_empty()) {
+        Some(token) => (token, false),
+        None => (generate_secret_token(), true),
+    }
+}
+
+pub fn cookie(token: &str) -> HeaderValue {
+    cookie_header(COOKIE, token, TOKEN_TTL)
+}
+
+/// Runs `future`, the rest of the request, with `token` available to [`template_function`]
+pub async fn scope<F: std::future::Future>(token: String, future: F) -> F::Output {
+    TOKEN.scope(token, future).await
+}
+
+/// Whether a cross-site page could have sent this request with the user's cookies.
+/// That's any write with a body an HTML form can make, or no body at all. Anything else needs a
+/// CORS preflight that fails, and requests with an `Authorization` header don't rely on cookies
+pub fn needs_check(method: &Method, headers: &HeaderMap) -> bool {
+    if matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) || headers.contains_key(http::header::AUTHORIZATION)
+    {
+        return false;
+    }
+
+    match headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => {
+            let essence = content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            matches!(
+                essence.as_str(),
+                "application/x-www-form-urlencoded" | "multipart/form-data" | "text/plain"
+            )
+        }
+        None => true,
+    }
+}
+
+/// The token a url encoded form body carries in [`FIELD`]
+pub fn form_token(body: &[u8]) -> Option<String> {
+    form_urlencoded::parse(body)
+        .find(|(name, _)| name == FIELD)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Compares in constant time, so the response time doesn't give away how much of a guess was right
+pub fn tokens_match(expected: &str, submitted: &str) -> bool {
+    expected.len() == submitted.len()
+        && expected
+            .bytes()
+            .zip(submitted.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// `csrf_token()` in templates, the token of the request being rendered
+pub fn template_function(_args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    TOKEN
+        .try_with(|token| tera::Value::String(token.clone()))
+        .map_err(|_| "csrf_token() only works while handling a request".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE as COOKIE_HEADER};
+
+    fn headers(pairs: &[(http::header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn only_writes_a_browser_could_forge_are_checked() {
+        let form = headers(&[(CONTENT_TYPE, "application/x-www-form-urlencoded")]);
+        assert!(needs_check(&Method::POST, &form));
+        assert!(needs_check(&Method::POST, &HeaderMap::new()));
+        assert!(needs_check(
+            &Method::DELETE,
+            &headers(&[(CONTENT_TYPE, "text/plain; charset=utf-8")])
+        ));
+        assert!(!needs_check(&Method::GET, &form));
+        assert!(!needs_check(
+            &Method::POST,
+            &headers(&[(CONTENT_TYPE, "application/json")])
+        ));
+        assert!(!needs_check(
+            &Method::POST,
+            &headers(&[(AUTHORIZATION, "Bearer key")])
+        ));
+    }
+
+    #[test]
+    fn tokens_come_from_the_cookie_and_the_form() {
+        let (token, new) = request_token(&headers(&[(COOKIE_HEADER, "jwt=a; csrf_token=abc")]));
+        assert_eq!((token.as_str(), new), ("abc", false));
+        let (token, new) = request_token(&HeaderMap::new());
+        assert_eq!((token.len(), new), (40, true));
+
+        assert_eq!(
+            form_token(b"email=a%40b.c&csrf_token=abc&password=x").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(form_token(b"email=a%40b.c"), None);
+    }
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abc", "abd"));
+        assert!(!tokens_match("abc", "ab"));
+        assert!(!tokens_match("abc", ""));
+    }
+
+    #[tokio::test]
+    async fn templates_see_the_token_of_the_request() {
+        assert!(template_function(&HashMap::new()).is_err());
+        let value = scope("abc".to_string(), async {
+            template_function(&HashMap::new()).unwrap()
+        })
+        .await;
+        assert_eq!(value, tera::Value::String("abc".to_string()));
+    }
+}
@@ -1,20 +1,245 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use async_stream::try_stream;
 use axum::Json;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use serde_json::Value;
 
-use chrono::NaiveDate;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
-use tracing::info;
+use sqlx::{PgConnection, PgPool};
+use tokio::sync::{broadcast, OnceCell};
+use tracing::{error, info};
 
 use crate::error::AppError;
-use crate::models::neo::{IntoNeoId, Neo, NeoId};
+use crate::generate_secret_token;
+use crate::import::{self, RowChange};
+use crate::mailer::{self, Mailer};
+use crate::models::alert::{AlertRule, AlertRuleOwner, CreateAlertRule, InboxMessage};
+use crate::models::api_key::{ApiKey, ApiKeyUser, GeneratedKey, ValidApiKey};
+use crate::models::audit::{Audit, AuditAction, AuditEntry, AuditFilter, NewAuditEntry};
+use crate::models::ban::{Ban, BanFilter};
+use crate::models::comment::CommentRow;
+use crate::models::date_range::DateRange;
+use crate::models::event::{NeoEvent, NeoEventKind};
+use crate::models::neo::{
+    CreateNeo, IngestedNeoRow, IntoNeoId, Neo, NeoFilter, NeoId, NeoRow, UpdateNeo,
+};
 use crate::models::neo_id_json::NeoJson;
 use crate::models::page::PagePackageNeo;
-use crate::models::user::{User, UserSignup};
+use crate::models::role::{Role, UserSummary};
+use crate::models::saved_query::{CreateSavedQuery, SavedQuery};
+use crate::models::settings::{AccountExport, OwnComment, Profile, WatchedAsteroid};
+use crate::models::user::{Account, Claims, SessionUser, TwoFactorUser, User, UserSignup};
+use crate::models::watchlist::{WatchlistEntry, WatchlistRow};
+use crate::notify::{self, Notifier};
+use crate::oidc::{self, OidcConfig};
+use crate::throttle::{LoginThrottle, Subject};
+
+/// How long after a refresh token is rotated it may still show up without being treated as stolen
+const REFRESH_REUSE_GRACE_SECONDS: i64 = 10;
+
+/// The NeoWs feed answers at most this many days per call
+const FEED_WINDOW_DAYS: i64 = 7;
+
+/// Longest range fetched from the feed for one request, enough for `this-month`
+const MAX_FEED_DAYS: i64 = 31;
+
+/// Splits `dates` into the windows the NeoWs feed accepts
+fn feed_windows(dates: DateRange) -> Result<Vec<(NaiveDate, NaiveDate)>, AppError> {
+    let days = (dates.end - dates.begin).num_days() + 1;
+    if days > MAX_FEED_DAYS {
+        return Err(AppError::InvalidDateRange(format!(
+            "{} days is too long to fetch from NeoWs, ask for {} days or fewer",
+            days, MAX_FEED_DAYS
+        )));
+    }
+
+    let mut windows = Vec::new();
+    let mut begin = dates.begin;
+    while begin <= dates.end {
+        let end = (begin + Duration::days(FEED_WINDOW_DAYS - 1)).min(dates.end);
+        windows.push((begin, end));
+        begin = end + Duration::days(1);
+    }
+    Ok(windows)
+}
+
+/// The value at `path` in a NeoWs response, or an error naming what's missing
+fn api_field<'a>(value: &'a Value, path: &[&str]) -> Result<&'a Value, AppError> {
+    path.iter()
+        .try_fold(value, |value, key| value.get(key))
+        .ok_or_else(|| AppError::InvalidApiData(format!("{} is missing", path.join("."))))
+}
+
+fn api_str<'a>(value: &'a Value, path: &[&str]) -> Result<&'a str, AppError> {
+    api_field(value, path)?
+        .as_str()
+        .ok_or_else(|| AppError::InvalidApiData(format!("{} is not a string", path.join("."))))
+}
+
+/// The approaches in a page of the NeoWs feed, which lists asteroids under each day
+fn neos_from_feed(feed: &Value) -> Result<Vec<Neo>, AppError> {
+    let days = api_field(feed, &["near_earth_objects"])?
+        .as_object()
+        .ok_or_else(|| {
+            AppError::InvalidApiData("near_earth_objects is not an object".to_string())
+        })?;
+
+    let mut neos = Vec::new();
+    for asteroids in days.values() {
+        let asteroids = asteroids.as_array().ok_or_else(|| {
+            AppError::InvalidApiData("a day in near_earth_objects is not a list".to_string())
+        })?;
+        for asteroid in asteroids {
+            let api_id = parse_api_number(api_str(asteroid, &["id"])?, "id")?;
+            let diameter = |bound| {
+                api_field(asteroid, &["estimated_diameter", "miles", bound])?
+                    .as_f64()
+                    .map(|miles| miles as f32)
+                    .ok_or_else(|| AppError::InvalidApiData(format!("{} is not a number", bound)))
+            };
+            let hazardous_asteroid = api_field(asteroid, &["is_potentially_hazardous_asteroid"])?
+                .as_bool()
+                .ok_or_else(|| {
+                    AppError::InvalidApiData(
+                        "is_potentially_hazardous_asteroid is not true or false".to_string(),
+                    )
+                })?;
+            let approaches = api_field(asteroid, &["close_approach_data"])?
+                .as_array()
+                .ok_or_else(|| {
+                    AppError::InvalidApiData("close_approach_data is not a list".to_string())
+                })?;
+
+            for approach_data in approaches {
+                neos.push(Neo {
+                    id: NeoId(0),
+                    api_id,
+                    designation: api_str(asteroid, &["name"])?.to_string(),
+                    diameter_min: diameter("estimated_diameter_min")?,
+                    diameter_max: diameter("estimated_diameter_max")?,
+                    hazardous_asteroid,
+                    approach_date: api_str(approach_data, &["close_approach_date"])?.to_string(),
+                    velocity: parse_api_number(
+                        api_str(approach_data, &["relative_velocity", "miles_per_hour"])?,
+                        "miles_per_hour",
+                    )?,
+                    miss_distance: parse_api_number(
+                        api_str(approach_data, &["miss_distance", "miles"])?,
+                        "miss_distance",
+                    )?,
+                    orbiting_body: api_str(approach_data, &["orbiting_body"])?.to_string(),
+                    approach_time: approach_data["epoch_date_close_approach"]
+                        .as_i64()
+                        .and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+                });
+            }
+        }
+    }
+
+    Ok(neos)
+}
+
+/// The NeoWs key from `API_KEY`
+fn neows_api_key() -> Result<String, AppError> {
+    std::env::var("API_KEY").map_err(|_| AppError::Any(anyhow::anyhow!("API_KEY is not set")))
+}
+
+/// Appends to the audit log, on the connection the audited change was made on
+async fn record_audit(conn: &mut PgConnection, entry: NewAuditEntry) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"INSERT INTO audit_log(actor, action, target, reason, request_id)
+           VALUES ($1, $2, $3, $4, $5)
+        "#,
+        entry.actor,
+        entry.action.as_str(),
+        entry.target,
+        entry.reason,
+        entry.request_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// NeoWs sends its numbers as strings
+fn parse_api_number<T: std::str::FromStr>(value: &str, field: &str) -> Result<T, AppError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| AppError::InvalidApiData(format!("{} '{}' is not a number", field, value)))
+}
+
+/// Updates the stored approach with the same asteroid, date and orbiting body as `neo`, or inserts it
+async fn upsert_neo(
+    conn: &mut PgConnection,
+    neo: &Neo,
+    date: NaiveDate,
+) -> Result<(NeoEventKind, NeoRow), AppError> {
+    let updated = sqlx::query_as::<_, NeoRow>(
+        r#"UPDATE neos SET designation = $4, diameter_min = $5, diameter_max = $6,
+               is_potentially_hazardous_asteroid = $7, relative_velocity = $8,
+               miss_distance = $9, close_approach_at = COALESCE($10, close_approach_at),
+               ingested_at = now()
+           WHERE api_id = $1 AND close_approach_date = $2 AND orbiting_body = $3
+           RETURNING *
+        "#,
+    )
+    .bind(neo.api_id)
+    .bind(date)
+    .bind(&neo.orbiting_body)
+    .bind(&neo.designation)
+    .bind(neo.diameter_min)
+    .bind(neo.diameter_max)
+    .bind(neo.hazardous_asteroid)
+    .bind(neo.velocity)
+    .bind(neo.miss_distance)
+    .bind(neo.approach_time)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(row) = updated {
+        return Ok((NeoEventKind::Updated, row));
+    }
+
+    let row = sqlx::query_as::<_, NeoRow>(
+        r#"INSERT INTO neos(api_id, designation, diameter_min, diameter_max, is_potentially_hazardous_asteroid, close_approach_date, relative_velocity, miss_distance, orbiting_body, close_approach_at)
+           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+           RETURNING *
+        "#,
+    )
+    .bind(neo.api_id)
+    .bind(&neo.designation)
+    .bind(neo.diameter_min)
+    .bind(neo.diameter_max)
+    .bind(neo.hazardous_asteroid)
+    .bind(date)
+    .bind(neo.velocity)
+    .bind(neo.miss_distance)
+    .bind(&neo.orbiting_body)
+    .bind(neo.approach_time)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok((NeoEventKind::Created, row))
+}
+
+/// `WHERE` clause for [`NeoFilter`], its fields bind to `$1`..`$3` in declaration order
+const NEO_FILTER: &str = "($1::BOOLEAN IS NULL OR is_potentially_hazardous_asteroid = $1)
+    AND ($2::TEXT IS NULL OR orbiting_body = $2)
+    AND ($3::REAL IS NULL OR miss_distance <= $3)";
 
 #[derive(Clone)]
 pub struct Store {
     pub conn_pool: PgPool,
+    pub notifiers: Arc<Vec<Arc<dyn Notifier>>>,
+    pub mailer: Arc<dyn Mailer>,
+    pub oidc: Option<Arc<OidcConfig>>,
+    pub events: broadcast::Sender<NeoEvent>,
+    /// `(role, permission)` rows of `role_permissions`, see [`Store::role_allows`]
+    pub role_permissions: Arc<OnceCell<Vec<(String, String)>>>,
 }
 
 pub async fn new_pool() -> PgPool {
@@ -28,7 +253,16 @@ pub async fn new_pool() -> PgPool {
 
 impl Store {
     pub fn with_pool(pool: PgPool) -> Self {
-        Self { conn_pool: pool }
+        let (events, _) = broadcast::channel(256);
+
+        Self {
+            notifiers: Arc::new(notify::notifiers_from_env(pool.clone())),
+            mailer: mailer::mailer_from_env(),
+            oidc: oidc::config_from_env(),
+            conn_pool: pool,
+            events,
+            role_permissions: Arc::default(),
+        }
     }
 
     pub async fn test_database(&self) -> Result<(), sqlx::Error> {
@@ -43,42 +277,297 @@ impl Store {
         Ok(())
     }
 
-    pub async fn check_admin(&self, email: String) -> Result<bool, sqlx::Error> {
-        let row = sqlx::query!("SELECT * FROM users WHERE email = $1", email)
-            .fetch_one(&self.conn_pool)
+    /// Whether `role` grants `permission`. `role_permissions` only changes with a migration, so it's
+    /// read the first time it's needed and kept
+    pub async fn role_allows(&self, role: Role, permission: &str) -> Result<bool, AppError> {
+        let grants = self
+            .role_permissions
+            .get_or_try_init(|| async {
+                sqlx::query!("SELECT role, permission FROM role_permissions")
+                    .fetch_all(&self.conn_pool)
+                    .await
+                    .map(|rows| {
+                        rows.into_iter()
+                            .map(|row| (row.role, row.permission))
+                            .collect::<Vec<_>>()
+                    })
+            })
             .await?;
 
-        Ok(row.admin)
+        Ok(grants
+            .iter()
+            .any(|(granted_to, granted)| granted_to == role.as_str() && granted == permission))
     }
 
-    pub async fn check_banned(&self, email: String) -> Result<bool, sqlx::Error> {
-        let row = sqlx::query!("SELECT * FROM users WHERE email = $1", email)
-            .fetch_one(&self.conn_pool)
-            .await?;
+    /// Creates the first admin, already verified, unless an admin exists. Returns whether it did
+    pub async fn seed_admin(
+        &self,
+        email: &str,
+        password_hash: &str,
+        audit: Audit,
+    ) -> Result<bool, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let admin_exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE role = 'admin') AS "exists!""#
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if admin_exists {
+            return Ok(false);
+        }
 
-        Ok(row.banned)
+        sqlx::query_scalar!(
+            r#"INSERT INTO users(email, password, role, email_verified) VALUES ($1, $2, $3, TRUE)
+               ON CONFLICT (email) DO NOTHING
+               RETURNING id
+            "#,
+            email,
+            password_hash,
+            Role::Admin.as_str(),
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::UserAlreadyExists)?;
+        record_audit(&mut tx, audit.entry(AuditAction::CreateAdmin, email)).await?;
+
+        tx.commit().await?;
+        Ok(true)
     }
 
-    pub async fn ban_user(&self, email: String) -> Result<(), sqlx::Error> {
-        sqlx::query!("UPDATE users SET banned = true WHERE email = $1", email)
-            .execute(&self.conn_pool)
-            .await?;
+    /// Moves the user to `role`. Their tokens stop working, so the next refresh carries the new role
+    pub async fn set_role(&self, user_id: i32, role: Role, audit: Audit) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let email = sqlx::query_scalar!(
+            r#"UPDATE users SET role = $1, token_version = token_version + 1 WHERE id = $2
+               RETURNING email
+            "#,
+            role.as_str(),
+            user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)?;
+
+        let audit = Audit {
+            reason: Some(format!("role set to {}", role)),
+            ..audit
+        };
+        record_audit(&mut tx, audit.entry(AuditAction::SetRole, &email)).await?;
 
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn get_all_users(&self) -> Result<Vec<User>, AppError> {
-        let rows = sqlx::query!("SELECT email, password FROM users",)
-            .fetch_all(&self.conn_pool)
+    /// The ban keeping the user out right now. With several, the one that lasts longest
+    pub async fn get_active_ban(&self, user_id: i32) -> Result<Option<Ban>, AppError> {
+        let ban = sqlx::query_as::<_, Ban>(
+            r#"SELECT b.id, u.email, b.reason, b.banned_by, b.created_at, b.expires_at,
+                      b.lifted_at, b.lifted_by, b.lift_reason, TRUE AS active
+               FROM active_bans b JOIN users u ON u.id = b.user_id
+               WHERE b.user_id = $1
+               ORDER BY b.expires_at DESC NULLS FIRST
+               LIMIT 1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.conn_pool)
+        .await?;
+
+        Ok(ban)
+    }
+
+    /// Bans the user until `expires_at`, or for good, and ends their sessions. `actor` can only ban
+    /// users whose role is below their own, which also means the last admin can't be banned
+    pub async fn ban_user(
+        &self,
+        actor: &Claims,
+        user_id: i32,
+        expires_at: Option<DateTime<Utc>>,
+        audit: Audit,
+    ) -> Result<Ban, AppError> {
+        if user_id == actor.id {
+            return Err(AppError::InvalidBan("You can't ban yourself".to_string()));
+        }
+        let mut tx = self.conn_pool.begin().await?;
+
+        // Locked so the role can't change between the check and the ban
+        let target = sqlx::query!(
+            "SELECT email, role FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)?;
+        let role: Role = target.role.parse().map_err(AppError::InvalidRole)?;
+        if role >= actor.role {
+            return Err(AppError::Forbidden);
+        }
+        let email = target.email;
+
+        // Tokens issued before the ban stop working
+        sqlx::query!(
+            "UPDATE users SET token_version = token_version + 1 WHERE id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let ban = sqlx::query_as::<_, Ban>(
+            r#"INSERT INTO bans (user_id, reason, banned_by, expires_at)
+               VALUES ($1, $3, $4, $5)
+               RETURNING id, $2 AS email, reason, banned_by, created_at, expires_at,
+                         lifted_at, lifted_by, lift_reason, TRUE AS active
+            "#,
+        )
+        .bind(user_id)
+        .bind(&email)
+        .bind(&audit.reason)
+        .bind(&audit.actor)
+        .bind(expires_at)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // A banned user shouldn't be able to renew their session either
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // the log keeps how long the ban was for, the bans table can't be edited
+        let reason = match ban.expires_at {
+            Some(expires_at) => Some(format!(
+                "{} (until {})",
+                audit.reason.as_deref().unwrap_or("no reason given"),
+                expires_at.format("%Y-%m-%d %H:%M UTC")
+            )),
+            None => audit.reason.clone(),
+        };
+        let audit = Audit { reason, ..audit };
+        record_audit(&mut tx, audit.entry(AuditAction::BanUser, &email)).await?;
+
+        tx.commit().await?;
+        Ok(ban)
+    }
+
+    /// Lifts every active ban of the user, returning how many there were. Their tokens are reissued
+    /// without the ban on the next refresh. Like bans, only for users ranked below `actor`, and never
+    /// their own
+    pub async fn unban_user(
+        &self,
+        actor: &Claims,
+        user_id: i32,
+        audit: Audit,
+    ) -> Result<u64, AppError> {
+        if user_id == actor.id {
+            return Err(AppError::Forbidden);
+        }
+        let mut tx = self.conn_pool.begin().await?;
+
+        // Locked so the role can't change between the check and the unban
+        let target = sqlx::query!(
+            "SELECT email, role FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)?;
+        let role: Role = target.role.parse().map_err(AppError::InvalidRole)?;
+        if role >= actor.role {
+            return Err(AppError::Forbidden);
+        }
+        let email = target.email;
+
+        let result = sqlx::query!(
+            r#"UPDATE bans SET lifted_at = NOW(), lifted_by = $2, lift_reason = $3
+               WHERE id IN (SELECT id FROM active_bans WHERE user_id = $1)
+            "#,
+            user_id,
+            audit.actor,
+            audit.reason,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            sqlx::query!(
+                "UPDATE users SET token_version = token_version + 1 WHERE id = $1",
+                user_id
+            )
+            .execute(&mut *tx)
             .await?;
+            record_audit(&mut tx, audit.entry(AuditAction::UnbanUser, &email)).await?;
+        }
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
 
-        let users: Vec<_> = rows
-            .into_iter()
-            .map(|row| User {
-                email: row.email,
-                password: row.password,
-            })
-            .collect();
+    /// Bans newest first, lifted and expired ones included
+    pub async fn get_bans(&self, filter: &BanFilter, limit: i64) -> Result<Vec<Ban>, AppError> {
+        let bans = sqlx::query_as::<_, Ban>(
+            r#"SELECT b.id, COALESCE(u.email, '(deleted account)') AS email, b.reason, b.banned_by,
+                      b.created_at, b.expires_at, b.lifted_at, b.lifted_by, b.lift_reason,
+                      b.id IN (SELECT id FROM active_bans) AS active
+               FROM bans b LEFT JOIN users u ON u.id = b.user_id
+               WHERE ($1::TEXT IS NULL OR u.email = $1
+                      OR b.email_hash = encode(sha256(convert_to(lower($1), 'UTF8')), 'hex'))
+               ORDER BY b.created_at DESC, b.id DESC
+               LIMIT $2
+            "#,
+        )
+        .bind(filter.email())
+        .bind(limit)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(bans)
+    }
+
+    /// Matching audit entries, newest first. `limit` caps the page, the CSV export passes `None`
+    pub async fn get_audit_log(
+        &self,
+        filter: &AuditFilter,
+        limit: Option<i64>,
+    ) -> Result<Vec<AuditEntry>, AppError> {
+        let entries = sqlx::query_as::<_, AuditEntry>(
+            r#"SELECT * FROM audit_log
+               WHERE ($1::TEXT IS NULL OR actor = $1)
+                 AND ($2::TEXT IS NULL OR action = $2)
+                 AND ($3::TEXT IS NULL OR target ILIKE '%' || $3 || '%')
+                 AND ($4::DATE IS NULL OR created_at >= $4)
+                 AND ($5::DATE IS NULL OR created_at < $5 + 1)
+               ORDER BY created_at DESC, id DESC
+               LIMIT $6
+            "#,
+        )
+        .bind(filter.actor())
+        .bind(filter.action())
+        .bind(filter.target())
+        .bind(filter.since()?)
+        .bind(filter.until()?)
+        .bind(limit)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(entries)
+    }
+
+    pub async fn get_all_users(&self) -> Result<Vec<UserSummary>, AppError> {
+        let users = sqlx::query_as::<_, UserSummary>(
+            r#"SELECT u.id, u.email, u.role, b.user_id IS NOT NULL AS banned, b.expires_at AS banned_until
+               FROM users u
+               LEFT JOIN LATERAL (
+                   SELECT user_id, expires_at FROM active_bans
+                   WHERE active_bans.user_id = u.id
+                   ORDER BY expires_at DESC NULLS FIRST
+                   LIMIT 1
+               ) b ON TRUE
+               ORDER BY u.email
+            "#,
+        )
+        .fetch_all(&self.conn_pool)
+        .await?;
 
         Ok(users)
     }
@@ -97,7 +586,6 @@ impl Store {
     }
 
     pub async fn create_user(&self, user: UserSignup) -> Result<Json<Value>, AppError> {
-        // TODO: Encrypt/bcrypt user passwords
         let result = sqlx::query("INSERT INTO users(email, password) values ($1, $2)")
             .bind(&user.email)
             .bind(&user.password)
@@ -109,340 +597,2433 @@ impl Store {
             Err(AppError::InternalServerError)
         } else {
             Ok(Json(
-                serde_json::json!({"message": "User created successfully!"}),
+                serde_json::json!({"message": "User created! Follow the link we emailed you to verify your address before logging in."}),
             ))
         }
     }
 
-    pub async fn create_admin(&self, user: UserSignup) -> Result<Json<Value>, AppError> {
-        // TODO: Encrypt/bcrypt user passwords
-        let result = sqlx::query("INSERT INTO users(email, password, admin) values ($1, $2, $3)")
-            .bind(&user.email)
-            .bind(&user.password)
-            .bind(true)
-            .execute(&self.conn_pool)
-            .await
-            .map_err(|_| AppError::InternalServerError)?;
+    pub async fn update_password(&self, email: &str, hash: &str) -> Result<(), AppError> {
+        sqlx::query!(
+            "UPDATE users SET password = $1 WHERE email = $2",
+            hash,
+            email
+        )
+        .execute(&self.conn_pool)
+        .await?;
 
-        if result.rows_affected() < 1 {
-            Err(AppError::InternalServerError)
-        } else {
-            Ok(Json(
-                serde_json::json!({"message": "User created successfully!"}),
-            ))
-        }
+        Ok(())
     }
 
-    pub async fn get_all_neos(&self) -> Result<Vec<Neo>, AppError> {
-        let neo_rows = sqlx::query!("SELECT * from neos",)
-            .fetch_all(&self.conn_pool)
-            .await?;
+    pub async fn get_account(&self, email: &str) -> Result<Option<Account>, AppError> {
+        let account = sqlx::query_as::<_, Account>(
+            "SELECT id, email, password, email_verified FROM users WHERE email = $1",
+        )
+        .bind(email)
+        .fetch_optional(&self.conn_pool)
+        .await?;
 
-        let neos: Vec<_> = neo_rows
-            .into_iter()
-            .map(|row| {
-                Neo {
-                    id: row.id.into(), // Assuming you have a From<u32> for NeoId
-                    api_id: row.api_id,
-                    designation: row.designation,
-                    diameter_min: row.diameter_min,
-                    diameter_max: row.diameter_max,
-                    hazardous_asteroid: row.is_potentially_hazardous_asteroid,
-                    approach_date: row.close_approach_date.to_string(),
-                    velocity: row.relative_velocity,
-                    miss_distance: row.miss_distance,
-                    orbiting_body: row.orbiting_body,
-                }
-            })
-            .collect();
+        Ok(account)
+    }
 
-        Ok(neos)
+    pub async fn get_account_by_id(&self, user_id: i32) -> Result<Account, AppError> {
+        let account = sqlx::query_as::<_, Account>(
+            "SELECT id, email, password, email_verified FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)?;
+
+        Ok(account)
     }
 
-    pub async fn add_neo(
-        &mut self,
-        api_id: i32,
-        designation: String,
-        diameter_min: f32,
-        diameter_max: f32,
-        hazardous_asteroid: bool,
-        approach_date: String,
-        velocity: f32,
-        miss_distance: f32,
-        orbiting_body: String,
-    ) -> Result<Neo, AppError> {
-        let date = NaiveDate::parse_from_str(&approach_date, "%Y-%m-%d")?;
-        let res = sqlx::query!(
-            r#"INSERT INTO "neos"(api_id, designation, diameter_min, diameter_max, is_potentially_hazardous_asteroid, close_approach_date, relative_velocity, miss_distance, orbiting_body)
-           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
-           RETURNING *
-        "#,
-            api_id,
-            designation,
-            diameter_min,
-            diameter_max,
-            hazardous_asteroid,
-            date,
-            velocity,
-            miss_distance,
-            orbiting_body,
+    pub async fn verify_email(&self, user_id: i32) -> Result<(), AppError> {
+        sqlx::query!(
+            "UPDATE users SET email_verified = TRUE WHERE id = $1",
+            user_id
         )
-        .fetch_one(&self.conn_pool)
+        .execute(&self.conn_pool)
         .await?;
 
-        let neo = Neo {
-            id: NeoId(res.id),
-            api_id: res.api_id,
-            designation: res.designation,
-            diameter_min: res.diameter_min,
-            diameter_max: res.diameter_max,
-            hazardous_asteroid: res.is_potentially_hazardous_asteroid,
-            approach_date: res.close_approach_date.to_string(),
-            velocity: res.relative_velocity,
-            miss_distance: res.miss_distance,
-            orbiting_body: res.orbiting_body,
-        };
-
-        Ok(neo)
+        Ok(())
     }
 
-    pub async fn get_all_neo_pages(&self) -> Result<PagePackageNeo, AppError> {
-        let neo_rows = sqlx::query!("SELECT * from neos",)
-            .fetch_all(&self.conn_pool)
-            .await?;
+    /// Sets a new password from a reset link. Following the link proves the address, and every
+    /// session from before the reset is ended
+    pub async fn reset_password(&self, user_id: i32, hash: &str) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        sqlx::query!(
+            r#"UPDATE users SET password = $1, email_verified = TRUE, token_version = token_version + 1
+               WHERE id = $2
+            "#,
+            hash,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
 
-        let neos: Vec<_> = neo_rows
-            .into_iter()
-            .map(|row| {
-                Neo {
-                    id: row.id.into(), // Assuming you have a From<u32> for NeoId
-                    api_id: row.api_id,
-                    designation: row.designation,
-                    diameter_min: row.diameter_min,
-                    diameter_max: row.diameter_max,
-                    hazardous_asteroid: row.is_potentially_hazardous_asteroid,
-                    approach_date: row.close_approach_date.to_string(),
-                    velocity: row.relative_velocity,
-                    miss_distance: row.miss_distance,
-                    orbiting_body: row.orbiting_body,
-                }
-            })
-            .collect();
+        Ok(())
+    }
 
-        let package = PagePackageNeo { neos: neos };
+    /// Sets a new password and logs out every session, the caller starts a new one for the current browser
+    pub async fn change_password(&self, user_id: i32, hash: &str) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        sqlx::query!(
+            "UPDATE users SET password = $1, token_version = token_version + 1 WHERE id = $2",
+            hash,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
 
-        Ok(package)
+        Ok(())
     }
 
-    pub async fn get_neo_by_date(
-        &mut self,
-        begin: String,
-        end: String,
-    ) -> Result<Vec<Neo>, AppError> {
-        let begin_date = NaiveDate::parse_from_str(&begin, "%Y-%m-%d")?;
-        let end_date = NaiveDate::parse_from_str(&end, "%Y-%m-%d")?;
-        let neo_rows = sqlx::query!(
-            "SELECT * from neos WHERE close_approach_date >= $1 AND close_approach_date <= $2",
-            begin_date,
-            end_date,
+    pub async fn get_profile(&self, user_id: i32) -> Result<Profile, AppError> {
+        sqlx::query_as::<_, Profile>(
+            r#"SELECT email, role, email_verified, totp_enabled_at IS NOT NULL AS two_factor_enabled, oidc_issuer
+               FROM users WHERE id = $1
+            "#,
         )
+        .bind(user_id)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)
+    }
+
+    /// A copy of everything the user has stored with us
+    pub async fn export_account(&self, user_id: i32) -> Result<AccountExport, AppError> {
+        let watchlist = sqlx::query_as::<_, WatchedAsteroid>(
+            "SELECT api_id, created_at FROM watchlist WHERE user_id = $1 ORDER BY created_at, api_id",
+        )
+        .bind(user_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+        let comments = sqlx::query_as::<_, OwnComment>(
+            r#"SELECT id, api_id, parent_id, body, created_at, edited_at, deleted_at
+               FROM comments WHERE user_id = $1 ORDER BY created_at, id
+            "#,
+        )
+        .bind(user_id)
         .fetch_all(&self.conn_pool)
         .await?;
 
-        if neo_rows.len() > 0 {
-            let neos: Vec<_> = neo_rows
-                .into_iter()
-                .map(|row| {
-                    Neo {
-                        id: row.id.into(), // Assuming you have a From<u32> for NeoId
-                        api_id: row.api_id,
-                        designation: row.designation,
-                        diameter_min: row.diameter_min,
-                        diameter_max: row.diameter_max,
+        Ok(AccountExport {
+            exported_at: Utc::now(),
+            profile: self.get_profile(user_id).await?,
+            watchlist,
+            saved_queries: self.get_saved_queries(user_id).await?,
+            comments,
+            alert_rules: self.get_alert_rules(user_id).await?,
+            api_keys: self.get_api_keys(user_id).await?,
+        })
+    }
+
+    /// Whether the user is the only admin, who has to hand the role on before leaving
+    pub async fn is_last_admin(&self, user_id: i32) -> Result<bool, AppError> {
+        let last = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) = 1 AND BOOL_OR(id = $1) AS "last!" FROM users WHERE role = 'admin'"#,
+            user_id
+        )
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        Ok(last)
+    }
+
+    /// Deletes the account and everything that belongs to it. Comments are blanked rather than
+    /// deleted, so replies from other people keep their place in the thread. Bans stay on record
+    /// under a hash of the email address
+    pub async fn delete_account(&self, user_id: i32) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        sqlx::query!(
+            r#"UPDATE comments SET body = '', deleted_at = COALESCE(deleted_at, NOW())
+               WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"UPDATE bans SET email_hash = encode(sha256(convert_to(lower(u.email), 'UTF8')), 'hex')
+               FROM users u WHERE u.id = bans.user_id AND bans.user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        // every other table referencing users cascades, comments and bans are left with no user
+        let result = sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() < 1 {
+            return Err(AppError::UserDoesNotExist);
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The account a single sign-on subject is linked to
+    pub async fn get_oidc_account(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<Account>, AppError> {
+        let account = sqlx::query_as::<_, Account>(
+            r#"SELECT id, email, password, email_verified FROM users
+               WHERE oidc_issuer = $1 AND oidc_subject = $2
+            "#,
+        )
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(&self.conn_pool)
+        .await?;
+
+        Ok(account)
+    }
+
+    /// Links an existing account to a single sign-on subject. The provider vouched for the email address,
+    /// so it counts as verified. False when the account is already linked to another subject
+    pub async fn link_oidc_subject(
+        &self,
+        user_id: i32,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<bool, AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE users SET oidc_issuer = $1, oidc_subject = $2, email_verified = TRUE
+               WHERE id = $3 AND oidc_subject IS NULL
+            "#,
+            issuer,
+            subject,
+            user_id
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Creates the account for someone's first single sign-on, once the provider has verified the
+    /// email address. `password` is a hash nobody knows the password to, until they set one with a reset link
+    pub async fn create_oidc_user(
+        &self,
+        email: &str,
+        password: &str,
+        role: Role,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Account, AppError> {
+        let account = sqlx::query_as::<_, Account>(
+            r#"INSERT INTO users (email, password, role, email_verified, oidc_issuer, oidc_subject)
+               VALUES ($1, $2, $3, TRUE, $4, $5)
+               RETURNING id, email, password, email_verified
+            "#,
+        )
+        .bind(email)
+        .bind(password)
+        .bind(role.as_str())
+        .bind(issuer)
+        .bind(subject)
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        Ok(account)
+    }
+
+    /// Counts an attempt against each of `keys` before its password or code is checked, so attempts
+    /// made at the same time can't all get in before the first failure is counted. Refused, and not
+    /// counted, while any of them has to wait. Failures older than `window` are forgotten.
+    /// An attempt that turns out to be good is taken back with [`Store::release_login_attempt`]
+    pub async fn reserve_login_attempt(
+        &self,
+        keys: &[String],
+        window: std::time::Duration,
+    ) -> Result<Vec<LoginThrottle>, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        // Rows for keys seen for the first time are created first, so the lock below covers them too.
+        // Both go in key order, two logins sharing keys never wait on each other the other way round
+        sqlx::query(
+            r#"INSERT INTO login_throttles (key, failures, last_failure_at)
+               SELECT key, 0, NOW() FROM UNNEST($1::TEXT[]) AS key ORDER BY key
+               ON CONFLICT (key) DO NOTHING
+            "#,
+        )
+        .bind(keys)
+        .execute(&mut *tx)
+        .await?;
+        let current = sqlx::query_as::<_, LoginThrottle>(
+            r#"SELECT key, failures, last_failure_at, locked_until FROM login_throttles
+               WHERE key = ANY($1) ORDER BY key FOR UPDATE
+            "#,
+        )
+        .bind(keys)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let now = Utc::now();
+        if let Some(wait) = current
+            .iter()
+            .filter_map(|throttle| throttle.retry_after(now))
+            .max()
+        {
+            return Err(AppError::LoginThrottled(wait));
+        }
+
+        let reserved = sqlx::query_as::<_, LoginThrottle>(
+            r#"UPDATE login_throttles SET
+                   failures = CASE
+                       WHEN last_failure_at < NOW() - make_interval(secs => $2) THEN 1
+                       ELSE failures + 1
+                   END,
+                   last_failure_at = NOW()
+               WHERE key = ANY($1)
+               RETURNING key, failures, last_failure_at, locked_until
+            "#,
+        )
+        .bind(keys)
+        .bind(window.as_secs_f64())
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(reserved)
+    }
+
+    /// Takes back an attempt [`Store::reserve_login_attempt`] counted, once it turned out to be good
+    pub async fn release_login_attempt(&self, keys: &[String]) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE login_throttles SET failures = failures - 1 WHERE key = ANY($1) AND failures > 0",
+        )
+        .bind(keys)
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn lock_login(
+        &self,
+        key: &str,
+        lockout: std::time::Duration,
+    ) -> Result<(), AppError> {
+        sqlx::query("UPDATE login_throttles SET locked_until = NOW() + make_interval(secs => $2) WHERE key = $1")
+            .bind(key)
+            .bind(lockout.as_secs_f64())
+            .execute(&self.conn_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forgets the failures after a successful login. False when there was nothing to forget
+    pub async fn clear_login_throttle(&self, key: &str) -> Result<bool, AppError> {
+        let result = sqlx::query!("DELETE FROM login_throttles WHERE key = $1", key)
+            .execute(&self.conn_pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Lifts a lockout of `email` after failed logins. False when it wasn't locked out
+    pub async fn unlock_account(&self, email: &str, audit: Audit) -> Result<bool, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let result = sqlx::query!(
+            "DELETE FROM login_throttles WHERE key = $1",
+            Subject::Account(email).key()
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            return Ok(false);
+        }
+        record_audit(&mut tx, audit.entry(AuditAction::UnlockAccount, email)).await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_locked_accounts(&self) -> Result<Vec<LoginThrottle>, AppError> {
+        let throttles = sqlx::query_as::<_, LoginThrottle>(
+            r#"SELECT key, failures, last_failure_at, locked_until FROM login_throttles
+               WHERE key LIKE 'account:%' AND locked_until > NOW()
+               ORDER BY locked_until DESC
+            "#,
+        )
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(throttles)
+    }
+
+    pub async fn get_two_factor_user(&self, user_id: i32) -> Result<TwoFactorUser, AppError> {
+        let user = sqlx::query_as::<_, TwoFactorUser>(
+            r#"SELECT id, email, role, totp_secret, totp_enabled_at IS NOT NULL AS totp_enabled, totp_last_step,
+                      (SELECT COUNT(*) FROM recovery_codes r WHERE r.user_id = users.id AND r.used_at IS NULL)
+                          AS recovery_codes_left
+               FROM users WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)?;
+
+        Ok(user)
+    }
+
+    /// Starts two-factor setup with a new secret. It isn't used for logins until [`Store::enable_totp`]
+    pub async fn set_pending_totp_secret(
+        &self,
+        user_id: i32,
+        secret: &str,
+    ) -> Result<(), AppError> {
+        sqlx::query!(
+            "UPDATE users SET totp_secret = $1 WHERE id = $2 AND totp_enabled_at IS NULL",
+            secret,
+            user_id
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Turns on two-factor authentication once the pending secret produced a valid code for `step`,
+    /// replacing any recovery codes with `code_hashes`
+    pub async fn enable_totp(
+        &self,
+        user_id: i32,
+        step: i64,
+        code_hashes: &[String],
+    ) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        sqlx::query!(
+            r#"UPDATE users SET totp_enabled_at = NOW(), totp_last_step = $1
+               WHERE id = $2 AND totp_secret IS NOT NULL AND totp_enabled_at IS NULL
+            "#,
+            step,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        Self::insert_recovery_codes(&mut tx, user_id, code_hashes).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Records that the code for `step` was used. False when it, or a later one, already was
+    pub async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE users SET totp_last_step = $1
+               WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)
+            "#,
+            step,
+            user_id
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Spends a recovery code. False when there is no unused code with that hash
+    pub async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE recovery_codes SET used_at = NOW()
+               WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            code_hash
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn replace_recovery_codes(
+        &self,
+        user_id: i32,
+        code_hashes: &[String],
+    ) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        Self::insert_recovery_codes(&mut tx, user_id, code_hashes).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn insert_recovery_codes(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        user_id: i32,
+        code_hashes: &[String],
+    ) -> Result<(), AppError> {
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut **tx)
+            .await?;
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, * FROM UNNEST($2::TEXT[])",
+            user_id,
+            code_hashes
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn disable_totp(&self, user_id: i32) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        sqlx::query!(
+            r#"UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+               WHERE id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_session_user(&self, email: &str) -> Result<SessionUser, AppError> {
+        let user = sqlx::query_as::<_, SessionUser>(
+            r#"SELECT id, email, role, token_version, totp_enabled_at IS NOT NULL AS two_factor_enabled,
+                      EXISTS(SELECT 1 FROM active_bans WHERE active_bans.user_id = users.id) AS banned
+               FROM users WHERE email = $1
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::UserDoesNotExist)?;
+
+        Ok(user)
+    }
+
+    pub async fn create_refresh_token(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        family: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query!(
+            r#"INSERT INTO refresh_tokens(user_id, token_hash, family, expires_at)
+               VALUES ($1, $2, $3, $4)
+            "#,
+            user_id,
+            token_hash,
+            family,
+            expires_at
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes the refresh token with `token_hash` and stores its successor in the same family,
+    /// returning the owner. `None` when the token was rotated away moments ago by a racing request.
+    /// Reusing it any later revokes the family.
+    pub async fn rotate_refresh_token(
+        &self,
+        token_hash: &str,
+        next_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Option<SessionUser>, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+
+        let current = sqlx::query!(
+            r#"SELECT r.id, r.family, r.expires_at, r.revoked_at, u.id AS user_id, u.email, u.role, u.token_version,
+                      u.totp_enabled_at IS NOT NULL AS "two_factor_enabled!",
+                      EXISTS(SELECT 1 FROM active_bans b WHERE b.user_id = u.id) AS "banned!"
+               FROM refresh_tokens r JOIN users u ON u.id = r.user_id
+               WHERE r.token_hash = $1
+               FOR UPDATE OF r
+            "#,
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+        if let Some(revoked_at) = current.revoked_at {
+            // Two requests racing to refresh the same session is normal, the one that lost gets no new
+            // session and the winner's stands. A token showing up again later means someone else has a copy of it
+            if revoked_at >= Utc::now() - Duration::seconds(REFRESH_REUSE_GRACE_SECONDS) {
+                return Ok(None);
+            }
+            sqlx::query!(
+                "UPDATE refresh_tokens SET revoked_at = NOW() WHERE family = $1 AND revoked_at IS NULL",
+                current.family
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Err(AppError::InvalidToken);
+        }
+        if current.expires_at < Utc::now() {
+            return Err(AppError::InvalidToken);
+        }
+
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE id = $1",
+            current.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"INSERT INTO refresh_tokens(user_id, token_hash, family, expires_at)
+               SELECT user_id, $2, family, $3 FROM refresh_tokens WHERE id = $1
+            "#,
+            current.id,
+            next_hash,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(SessionUser {
+            id: current.user_id,
+            email: current.email,
+            role: current.role,
+            token_version: current.token_version,
+            two_factor_enabled: current.two_factor_enabled,
+            banned: current.banned,
+        }))
+    }
+
+    pub async fn revoke_refresh_token_family(&self, token_hash: &str) -> Result<(), AppError> {
+        sqlx::query!(
+            r#"UPDATE refresh_tokens SET revoked_at = NOW()
+               WHERE family = (SELECT family FROM refresh_tokens WHERE token_hash = $1) AND revoked_at IS NULL
+            "#,
+            token_hash
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_access_token(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query!(
+            "INSERT INTO revoked_access_tokens(jti, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            jti,
+            expires_at
+        )
+        .execute(&self.conn_pool)
+        .await?;
+        // Expired tokens fail validation anyway, no need to keep their rows
+        sqlx::query!("DELETE FROM revoked_access_tokens WHERE expires_at < NOW()")
+            .execute(&self.conn_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether an access token with valid signature and expiry may still be used: it hasn't been revoked
+    /// by logging out and its user's token version hasn't moved on since it was issued
+    pub async fn is_token_current(&self, claims: &Claims) -> Result<bool, AppError> {
+        let current = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE id = $1 AND token_version = $2)
+                  AND NOT EXISTS(SELECT 1 FROM revoked_access_tokens WHERE jti = $3) AS "current!"
+            "#,
+            claims.id,
+            claims.token_version,
+            claims.jti
+        )
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        Ok(current)
+    }
+
+    pub async fn create_admin(
+        &self,
+        user: UserSignup,
+        audit: Audit,
+    ) -> Result<Json<Value>, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let result = sqlx::query("INSERT INTO users(email, password, role) values ($1, $2, $3)")
+            .bind(&user.email)
+            .bind(&user.password)
+            .bind(Role::Admin.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| AppError::InternalServerError)?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::InternalServerError)
+        } else {
+            record_audit(&mut tx, audit.entry(AuditAction::CreateAdmin, &user.email)).await?;
+            tx.commit().await?;
+            Ok(Json(
+                serde_json::json!({"message": "User created! Follow the link we emailed you to verify your address before logging in."}),
+            ))
+        }
+    }
+
+    pub async fn get_all_neos(&self, filter: &NeoFilter) -> Result<Vec<Neo>, AppError> {
+        let neo_rows = sqlx::query_as::<_, NeoRow>(&format!(
+            "SELECT * FROM neos WHERE {} ORDER BY id",
+            NEO_FILTER
+        ))
+        .bind(filter.hazardous)
+        .bind(filter.orbiting_body.as_deref())
+        .bind(filter.max_miss_distance)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(neo_rows.into_iter().map(Neo::from).collect())
+    }
+
+    /// Streams every stored approach matching the filter straight off a database cursor
+    pub fn stream_all_neos(
+        &self,
+        filter: NeoFilter,
+    ) -> BoxStream<'static, Result<Neo, sqlx::Error>> {
+        let pool = self.conn_pool.clone();
+
+        Box::pin(try_stream! {
+            let query = format!("SELECT * FROM neos WHERE {} ORDER BY id", NEO_FILTER);
+            let mut rows = sqlx::query_as::<_, NeoRow>(&query)
+                .bind(filter.hazardous)
+                .bind(filter.orbiting_body.as_deref())
+                .bind(filter.max_miss_distance)
+                .fetch(&pool);
+            while let Some(row) = rows.try_next().await? {
+                yield Neo::from(row);
+            }
+        })
+    }
+
+    /// The most recently ingested approaches matching the filter, newest first
+    pub async fn get_recently_ingested(
+        &self,
+        filter: &NeoFilter,
+        limit: i64,
+    ) -> Result<Vec<(Neo, DateTime<Utc>)>, AppError> {
+        let rows = sqlx::query_as::<_, IngestedNeoRow>(&format!(
+            "SELECT * FROM neos WHERE {} ORDER BY ingested_at DESC, id DESC LIMIT $4",
+            NEO_FILTER
+        ))
+        .bind(filter.hazardous)
+        .bind(filter.orbiting_body.as_deref())
+        .bind(filter.max_miss_distance)
+        .bind(limit)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (Neo::from(row.neo), row.ingested_at))
+            .collect())
+    }
+
+    /// Streams the approaches in a date range, asking NeoWs first if we have none stored yet
+    pub async fn stream_neo_by_date(
+        &mut self,
+        dates: DateRange,
+    ) -> Result<BoxStream<'static, Result<Neo, sqlx::Error>>, AppError> {
+        let (begin_date, end_date) = (dates.begin, dates.end);
+
+        let stored = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM neos WHERE close_approach_date >= $1 AND close_approach_date <= $2) AS "exists!""#,
+            begin_date,
+            end_date,
+        )
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        if !stored {
+            // Populates the table from the API
+            self.get_neo_by_date(dates).await?;
+        }
+
+        let pool = self.conn_pool.clone();
+
+        Ok(Box::pin(try_stream! {
+            let mut rows = sqlx::query_as::<_, NeoRow>(
+                "SELECT * FROM neos WHERE close_approach_date >= $1 AND close_approach_date <= $2 ORDER BY close_approach_date, id",
+            )
+            .bind(begin_date)
+            .bind(end_date)
+            .fetch(&pool);
+            while let Some(row) = rows.try_next().await? {
+                yield Neo::from(row);
+            }
+        }))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_neo(
+        &mut self,
+        api_id: i32,
+        designation: String,
+        diameter_min: f32,
+        diameter_max: f32,
+        hazardous_asteroid: bool,
+        approach_date: String,
+        velocity: f32,
+        miss_distance: f32,
+        orbiting_body: String,
+        audit: Audit,
+    ) -> Result<Neo, AppError> {
+        let date = NaiveDate::parse_from_str(&approach_date, "%Y-%m-%d")?;
+        let mut tx = self.conn_pool.begin().await?;
+        let res = sqlx::query!(
+            r#"INSERT INTO "neos"(api_id, designation, diameter_min, diameter_max, is_potentially_hazardous_asteroid, close_approach_date, relative_velocity, miss_distance, orbiting_body)
+           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+           RETURNING *
+        "#,
+            api_id,
+            designation,
+            diameter_min,
+            diameter_max,
+            hazardous_asteroid,
+            date,
+            velocity,
+            miss_distance,
+            orbiting_body,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let target = format!("neo {} ({})", res.id, res.designation);
+        record_audit(&mut tx, audit.entry(AuditAction::CreateNeo, target)).await?;
+        tx.commit().await?;
+
+        let neo = Neo {
+            id: NeoId(res.id),
+            api_id: res.api_id,
+            designation: res.designation,
+            diameter_min: res.diameter_min,
+            diameter_max: res.diameter_max,
+            hazardous_asteroid: res.is_potentially_hazardous_asteroid,
+            approach_date: res.close_approach_date.to_string(),
+            velocity: res.relative_velocity,
+            miss_distance: res.miss_distance,
+            orbiting_body: res.orbiting_body,
+            approach_time: res.close_approach_at,
+        };
+        self.neos_stored(vec![NeoEvent {
+            kind: NeoEventKind::Created,
+            neo: neo.clone(),
+        }])
+        .await;
+
+        Ok(neo)
+    }
+
+    /// Looks up a single stored record by its database id, not its NASA api_id
+    pub async fn get_neo_record(&self, id: NeoId) -> Result<Neo, AppError> {
+        let row = sqlx::query_as::<_, NeoRow>("SELECT * FROM neos WHERE id = $1")
+            .bind(id.0)
+            .fetch_optional(&self.conn_pool)
+            .await?
+            .ok_or(AppError::NotFound)?;
+
+        Ok(row.into())
+    }
+
+    pub async fn update_neo(
+        &self,
+        id: NeoId,
+        update: UpdateNeo,
+        audit: Audit,
+    ) -> Result<Neo, AppError> {
+        let existing = self.get_neo_record(id).await?;
+        let neo = update.apply(existing);
+        let date = neo.validate().map_err(AppError::InvalidNeo)?;
+
+        let mut tx = self.conn_pool.begin().await?;
+        let row = sqlx::query_as::<_, NeoRow>(
+            r#"UPDATE neos SET api_id = $2, designation = $3, diameter_min = $4, diameter_max = $5,
+                   is_potentially_hazardous_asteroid = $6, close_approach_date = $7,
+                   relative_velocity = $8, miss_distance = $9, orbiting_body = $10
+               WHERE id = $1
+               RETURNING *
+            "#,
+        )
+        .bind(id.0)
+        .bind(neo.api_id)
+        .bind(&neo.designation)
+        .bind(neo.diameter_min)
+        .bind(neo.diameter_max)
+        .bind(neo.hazardous_asteroid)
+        .bind(date)
+        .bind(neo.velocity)
+        .bind(neo.miss_distance)
+        .bind(&neo.orbiting_body)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::NotFound)?;
+        let target = format!("neo {} ({})", row.id, row.designation);
+        record_audit(&mut tx, audit.entry(AuditAction::UpdateNeo, target)).await?;
+        tx.commit().await?;
+
+        let neo = Neo::from(row);
+        self.neos_stored(vec![NeoEvent {
+            kind: NeoEventKind::Updated,
+            neo: neo.clone(),
+        }])
+        .await;
+
+        Ok(neo)
+    }
+
+    pub async fn delete_neo(&self, id: NeoId, audit: Audit) -> Result<(), AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let row = sqlx::query_as::<_, NeoRow>("DELETE FROM neos WHERE id = $1 RETURNING *")
+            .bind(id.0)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(AppError::NotFound)?;
+        record_audit(
+            &mut tx,
+            audit.entry(AuditAction::DeleteNeo, format!("neo {}", id)),
+        )
+        .await?;
+        tx.commit().await?;
+
+        self.publish([NeoEvent {
+            kind: NeoEventKind::Deleted,
+            neo: row.into(),
+        }]);
+
+        Ok(())
+    }
+
+    /// Stores every row in a single transaction, rolling it back instead when `dry_run` is set.
+    /// Rows for an approach we already have (same asteroid, date and orbiting body) update it, like ingestion does.
+    /// A row repeating an approach created earlier in the same upload still counts as created
+    pub async fn import_neos(
+        &self,
+        neos: Vec<(usize, CreateNeo)>,
+        dry_run: bool,
+        audit: Audit,
+    ) -> Result<Vec<RowChange>, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let mut changes = Vec::with_capacity(neos.len());
+        let mut events = Vec::with_capacity(neos.len());
+        let mut created = HashSet::new();
+
+        for (row, neo) in neos {
+            let date = NaiveDate::parse_from_str(&neo.approach_date, "%Y-%m-%d")?;
+            let (kind, neo) = upsert_neo(&mut tx, &Neo::from(neo), date).await?;
+            if kind == NeoEventKind::Created {
+                created.insert(neo.id);
+            }
+            let reported = if created.contains(&neo.id) {
+                NeoEventKind::Created
+            } else {
+                kind
+            };
+            changes.push(RowChange {
+                row,
+                kind: reported,
+            });
+            events.push(NeoEvent {
+                kind,
+                neo: neo.into(),
+            });
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            let (created, updated) = import::tally(&changes);
+            let target = format!(
+                "{} rows, {} new and {} updated",
+                changes.len(),
+                created,
+                updated
+            );
+            record_audit(&mut tx, audit.entry(AuditAction::ImportNeos, target)).await?;
+            tx.commit().await?;
+            self.neos_stored(events).await;
+        }
+
+        Ok(changes)
+    }
+
+    pub async fn get_all_neo_pages(&self) -> Result<PagePackageNeo, AppError> {
+        let neo_rows = sqlx::query!("SELECT * from neos",)
+            .fetch_all(&self.conn_pool)
+            .await?;
+
+        let neos: Vec<_> = neo_rows
+            .into_iter()
+            .map(|row| {
+                Neo {
+                    id: row.id.into(), // Assuming you have a From<u32> for NeoId
+                    api_id: row.api_id,
+                    designation: row.designation,
+                    diameter_min: row.diameter_min,
+                    diameter_max: row.diameter_max,
+                    hazardous_asteroid: row.is_potentially_hazardous_asteroid,
+                    approach_date: row.close_approach_date.to_string(),
+                    velocity: row.relative_velocity,
+                    miss_distance: row.miss_distance,
+                    orbiting_body: row.orbiting_body,
+                    approach_time: row.close_approach_at,
+                }
+            })
+            .collect();
+
+        let package = PagePackageNeo { neos };
+
+        Ok(package)
+    }
+
+    pub async fn get_neo_by_date(&mut self, dates: DateRange) -> Result<Vec<Neo>, AppError> {
+        let (begin_date, end_date) = (dates.begin, dates.end);
+        let neo_rows = sqlx::query!(
+            "SELECT * from neos WHERE close_approach_date >= $1 AND close_approach_date <= $2",
+            begin_date,
+            end_date,
+        )
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        if !neo_rows.is_empty() {
+            let neos: Vec<_> = neo_rows
+                .into_iter()
+                .map(|row| {
+                    Neo {
+                        id: row.id.into(), // Assuming you have a From<u32> for NeoId
+                        api_id: row.api_id,
+                        designation: row.designation,
+                        diameter_min: row.diameter_min,
+                        diameter_max: row.diameter_max,
+                        hazardous_asteroid: row.is_potentially_hazardous_asteroid,
+                        approach_date: row.close_approach_date.to_string(),
+                        velocity: row.relative_velocity,
+                        miss_distance: row.miss_distance,
+                        orbiting_body: row.orbiting_body,
+                        approach_time: row.close_approach_at,
+                    }
+                })
+                .collect();
+
+            Ok(neos)
+        } else {
+            let mut neos_list: Vec<Neo> = Vec::new();
+
+            for (begin_date, end_date) in feed_windows(dates)? {
+                let url = format!(
+                    "https://api.nasa.gov/neo/rest/v1/feed?start_date={}&end_date={}&api_key={}",
+                    begin_date,
+                    end_date,
+                    neows_api_key()?
+                );
+                // NeoWs explains a rejected request in a body without `near_earth_objects`
+                let res = reqwest::get(url).await?.error_for_status()?.text().await?;
+                let res_json: Value = serde_json::from_str(&res)?;
+
+                neos_list.extend(neos_from_feed(&res_json)?);
+            }
+
+            self.ingest_neos(neos_list).await
+        }
+    }
+
+    pub async fn get_neo_by_id<T: IntoNeoId>(&mut self, id: T) -> Result<Vec<Neo>, AppError> {
+        let id = id.into_neo_id();
+
+        let neo_row = sqlx::query!(
+            r#"
+        SELECT * FROM neos WHERE api_id = $1
+        "#,
+            id.0,
+        )
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        if !neo_row.is_empty() {
+            let neos: Vec<_> = neo_row
+                .into_iter()
+                .map(|row| {
+                    Neo {
+                        id: row.id.into(), // Assuming you have a From<u32> for NeoId
+                        api_id: row.api_id,
+                        designation: row.designation,
+                        diameter_min: row.diameter_min,
+                        diameter_max: row.diameter_max,
                         hazardous_asteroid: row.is_potentially_hazardous_asteroid,
                         approach_date: row.close_approach_date.to_string(),
                         velocity: row.relative_velocity,
                         miss_distance: row.miss_distance,
                         orbiting_body: row.orbiting_body,
+                        approach_time: row.close_approach_at,
                     }
                 })
                 .collect();
 
-            Ok(neos)
+            Ok(neos)
+        } else {
+            let neos = Self::fetch_neo_from_api(id).await?;
+            self.ingest_neos(neos).await
+        }
+    }
+
+    /// Every close approach NeoWs knows for one asteroid, not yet stored
+    pub async fn fetch_neo_from_api(id: NeoId) -> Result<Vec<Neo>, AppError> {
+        let url = format!(
+            "https://api.nasa.gov/neo/rest/v1/neo/{}?api_key={}",
+            id.0,
+            neows_api_key()?
+        );
+        let res = reqwest::get(url).await?.text().await?;
+        let res_json: NeoJson = serde_json::from_str(&res)?;
+        let api_id = parse_api_number(&res_json.id, "id")?;
+
+        let mut neos: Vec<Neo> = Vec::new();
+
+        for approach_data in res_json.close_approach_data {
+            let neo = Neo {
+                id: NeoId(0),
+                api_id,
+                designation: res_json.designation.clone(),
+                diameter_min: res_json.estimated_diameter.miles.estimated_diameter_min,
+                diameter_max: res_json.estimated_diameter.miles.estimated_diameter_max,
+                hazardous_asteroid: res_json.is_potentially_hazardous_asteroid,
+                approach_date: approach_data.close_approach_date.clone(),
+                velocity: parse_api_number(
+                    &approach_data.relative_velocity.miles_per_hour,
+                    "miles_per_hour",
+                )?,
+                miss_distance: parse_api_number(
+                    &approach_data.miss_distance.miles,
+                    "miss_distance",
+                )?,
+                orbiting_body: approach_data.orbiting_body.clone(),
+                approach_time: approach_data
+                    .epoch_date_close_approach
+                    .and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+            };
+
+            neos.push(neo);
+        }
+
+        Ok(neos)
+    }
+
+    /// Stores approaches fetched from NeoWs. An approach we already have (same asteroid,
+    /// date and orbiting body) is updated in place instead of being duplicated.
+    pub async fn ingest_neos(&mut self, neos: Vec<Neo>) -> Result<Vec<Neo>, AppError> {
+        let mut tx = self.conn_pool.begin().await?;
+        let mut events = Vec::with_capacity(neos.len());
+
+        for neo in neos {
+            let date = NaiveDate::parse_from_str(&neo.approach_date, "%Y-%m-%d")?;
+            let (kind, row) = upsert_neo(&mut tx, &neo, date).await?;
+            events.push(NeoEvent {
+                kind,
+                neo: row.into(),
+            });
+        }
+
+        tx.commit().await?;
+
+        let stored = events.iter().map(|event| event.neo.clone()).collect();
+        self.neos_stored(events).await;
+
+        Ok(stored)
+    }
+
+    /// Every path that stores approaches ends here, once they are committed. Subscribers to
+    /// `/events` hear about them and alert rules are checked against them
+    async fn neos_stored(&self, events: Vec<NeoEvent>) {
+        let neos: Vec<Neo> = events.iter().map(|event| event.neo.clone()).collect();
+        self.publish(events);
+
+        // A broken rule shouldn't fail the write that already happened
+        if let Err(err) = self.evaluate_alerts(&neos).await {
+            error!("Alert evaluation failed: {:?}", err);
+        }
+    }
+
+    fn publish(&self, events: impl IntoIterator<Item = NeoEvent>) {
+        for event in events {
+            // Only fails when nobody is listening
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Checks freshly ingested approaches against every alert rule. Each (rule, approach)
+    /// pair only ever fires once, the unique key on `alerts` takes care of that.
+    pub async fn evaluate_alerts(&self, neos: &[Neo]) -> Result<usize, AppError> {
+        if neos.is_empty() {
+            return Ok(0);
+        }
+
+        let rules = sqlx::query_as::<_, AlertRuleOwner>(
+            r#"SELECT r.*, u.email FROM alert_rules r
+               JOIN users u ON u.id = r.user_id
+               WHERE NOT EXISTS(SELECT 1 FROM active_bans b WHERE b.user_id = u.id)
+            "#,
+        )
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        let today = Utc::now().date_naive();
+        let mut fired = 0;
+
+        for owner in &rules {
+            for neo in neos.iter().filter(|neo| owner.rule.matches(neo, today)) {
+                let alert_id = sqlx::query_scalar!(
+                    r#"INSERT INTO alerts(rule_id, user_id, neo_id) VALUES ($1, $2, $3)
+                       ON CONFLICT (rule_id, neo_id) DO NOTHING
+                       RETURNING id
+                    "#,
+                    owner.rule.id,
+                    owner.rule.user_id,
+                    neo.id.0,
+                )
+                .fetch_optional(&self.conn_pool)
+                .await?;
+
+                // Already alerted on this approach
+                let Some(alert_id) = alert_id else { continue };
+                fired += 1;
+
+                for notifier in self.notifiers.iter() {
+                    if let Err(err) = notifier
+                        .notify(alert_id, &owner.email, &owner.rule, neo)
+                        .await
+                    {
+                        error!("Failed to deliver alert {}: {:?}", alert_id, err);
+                    }
+                }
+            }
+        }
+
+        Ok(fired)
+    }
+
+    pub async fn get_alert_rules(&self, user_id: i32) -> Result<Vec<AlertRule>, AppError> {
+        let rules = sqlx::query_as::<_, AlertRule>(
+            "SELECT * FROM alert_rules WHERE user_id = $1 ORDER BY id",
+        )
+        .bind(user_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(rules)
+    }
+
+    pub async fn add_alert_rule(
+        &self,
+        user_id: i32,
+        rule: CreateAlertRule,
+    ) -> Result<AlertRule, AppError> {
+        let rule = sqlx::query_as::<_, AlertRule>(
+            r#"INSERT INTO alert_rules(user_id, name, hazardous_only, max_miss_distance_au, within_days)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(rule.name)
+        .bind(rule.hazardous_only)
+        .bind(rule.max_miss_distance_au)
+        .bind(rule.within_days)
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        Ok(rule)
+    }
+
+    pub async fn delete_alert_rule(&self, user_id: i32, rule_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            "DELETE FROM alert_rules WHERE id = $1 AND user_id = $2",
+            rule_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn get_saved_queries(&self, user_id: i32) -> Result<Vec<SavedQuery>, AppError> {
+        let queries = sqlx::query_as::<_, SavedQuery>(
+            "SELECT * FROM saved_queries WHERE user_id = $1 ORDER BY name",
+        )
+        .bind(user_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(queries)
+    }
+
+    pub async fn get_saved_query(
+        &self,
+        user_id: i32,
+        query_id: i32,
+    ) -> Result<SavedQuery, AppError> {
+        sqlx::query_as::<_, SavedQuery>(
+            "SELECT * FROM saved_queries WHERE id = $1 AND user_id = $2",
+        )
+        .bind(query_id)
+        .bind(user_id)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::NotFound)
+    }
+
+    pub async fn add_saved_query(
+        &self,
+        user_id: i32,
+        query: CreateSavedQuery,
+    ) -> Result<SavedQuery, AppError> {
+        let result = sqlx::query_as::<_, SavedQuery>(
+            r#"INSERT INTO saved_queries(user_id, name, begin_date, end_date, hazardous, orbiting_body, max_miss_distance)
+               VALUES ($1, $2, $3, $4, $5, $6, $7)
+               RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(query.name)
+        .bind(query.begin_date)
+        .bind(query.end_date)
+        .bind(query.hazardous)
+        .bind(query.orbiting_body)
+        .bind(query.max_miss_distance)
+        .fetch_one(&self.conn_pool)
+        .await;
+
+        match result {
+            Ok(query) => Ok(query),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Err(
+                AppError::InvalidQuery("You already have a query with that name".to_string()),
+            ),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn delete_saved_query(&self, user_id: i32, query_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            "DELETE FROM saved_queries WHERE id = $1 AND user_id = $2",
+            query_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, AppError> {
+        let keys = sqlx::query_as::<_, ApiKey>(
+            r#"SELECT id, name, prefix, scopes, expires_at, last_used_at, created_at
+               FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    pub async fn add_api_key(
+        &self,
+        user_id: i32,
+        key: ValidApiKey,
+        generated: &GeneratedKey,
+    ) -> Result<ApiKey, AppError> {
+        let key = sqlx::query_as::<_, ApiKey>(
+            r#"INSERT INTO api_keys(user_id, name, prefix, key_hash, scopes, expires_at)
+               VALUES ($1, $2, $3, $4, $5, $6)
+               RETURNING id, name, prefix, scopes, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(key.name)
+        .bind(&generated.prefix)
+        .bind(&generated.hash)
+        .bind(key.scopes)
+        .bind(key.expires_at)
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        Ok(key)
+    }
+
+    pub async fn delete_api_key(&self, user_id: i32, key_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            "DELETE FROM api_keys WHERE id = $1 AND user_id = $2",
+            key_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Looks up the owner of an unexpired key and records the use. Unknown and expired keys are `InvalidToken`
+    pub async fn use_api_key(&self, key_hash: &str) -> Result<ApiKeyUser, AppError> {
+        let user = sqlx::query_as::<_, ApiKeyUser>(
+            r#"UPDATE api_keys k SET last_used_at = NOW()
+               FROM users u
+               WHERE u.id = k.user_id AND k.key_hash = $1 AND (k.expires_at IS NULL OR k.expires_at > NOW())
+               RETURNING k.id AS key_id, u.id AS user_id, u.email, u.role, u.token_version, k.scopes, k.expires_at,
+                         EXISTS(SELECT 1 FROM active_bans b WHERE b.user_id = u.id) AS banned
+            "#,
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+        Ok(user)
+    }
+
+    /// Hands out the query's share token, reusing the current one so links already sent keep working
+    pub async fn share_saved_query(&self, user_id: i32, query_id: i32) -> Result<String, AppError> {
+        let token = sqlx::query_scalar!(
+            r#"UPDATE saved_queries SET share_token = COALESCE(share_token, $3)
+               WHERE id = $1 AND user_id = $2
+               RETURNING share_token AS "share_token!"
+            "#,
+            query_id,
+            user_id,
+            generate_secret_token(),
+        )
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+        Ok(token)
+    }
+
+    /// Forgets the share token, after which the old permalink answers 404
+    pub async fn revoke_saved_query_share(
+        &self,
+        user_id: i32,
+        query_id: i32,
+    ) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            "UPDATE saved_queries SET share_token = NULL WHERE id = $1 AND user_id = $2",
+            query_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn get_shared_query(&self, token: &str) -> Result<SavedQuery, AppError> {
+        sqlx::query_as::<_, SavedQuery>("SELECT * FROM saved_queries WHERE share_token = $1")
+            .bind(token)
+            .fetch_optional(&self.conn_pool)
+            .await?
+            .ok_or(AppError::NotFound)
+    }
+
+    /// Every comment on an asteroid, oldest first, including deleted ones so threads stay intact
+    pub async fn get_comments(&self, api_id: i32) -> Result<Vec<CommentRow>, AppError> {
+        let comments = sqlx::query_as::<_, CommentRow>(
+            r#"SELECT comments.*, users.email FROM comments
+               LEFT JOIN users ON users.id = comments.user_id
+               WHERE comments.api_id = $1
+               ORDER BY comments.created_at, comments.id
+            "#,
+        )
+        .bind(api_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(comments)
+    }
+
+    pub async fn get_comment(&self, comment_id: i32) -> Result<CommentRow, AppError> {
+        sqlx::query_as::<_, CommentRow>(
+            r#"SELECT comments.*, users.email FROM comments
+               LEFT JOIN users ON users.id = comments.user_id
+               WHERE comments.id = $1
+            "#,
+        )
+        .bind(comment_id)
+        .fetch_optional(&self.conn_pool)
+        .await?
+        .ok_or(AppError::NotFound)
+    }
+
+    /// Adds a comment, replies have to be to a live comment on the same asteroid
+    pub async fn add_comment(
+        &self,
+        user_id: i32,
+        api_id: i32,
+        parent_id: Option<i32>,
+        body: String,
+    ) -> Result<CommentRow, AppError> {
+        if let Some(parent_id) = parent_id {
+            let parent = self.get_comment(parent_id).await?;
+            if parent.api_id != api_id || parent.deleted_at.is_some() {
+                return Err(AppError::InvalidComment(
+                    "You can only reply to a comment on the same asteroid".to_string(),
+                ));
+            }
+        }
+
+        let comment_id = sqlx::query_scalar!(
+            "INSERT INTO comments(api_id, user_id, parent_id, body) VALUES ($1, $2, $3, $4) RETURNING id",
+            api_id,
+            user_id,
+            parent_id,
+            body,
+        )
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+        self.get_comment(comment_id).await
+    }
+
+    pub async fn update_comment(
+        &self,
+        user_id: i32,
+        comment_id: i32,
+        body: String,
+    ) -> Result<CommentRow, AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE comments SET body = $1, edited_at = now()
+               WHERE id = $2 AND user_id = $3 AND deleted_at IS NULL
+            "#,
+            body,
+            comment_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            return Err(AppError::NotFound);
+        }
+
+        self.get_comment(comment_id).await
+    }
+
+    /// Moderator version of `delete_comment` that works on anyone's comment
+    pub async fn moderate_comment(&self, comment_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE comments SET body = '', deleted_at = now()
+               WHERE id = $1 AND deleted_at IS NULL
+            "#,
+            comment_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
         } else {
-            let api_key = std::env::var("API_KEY").unwrap();
-            let url = format!(
-                "https://api.nasa.gov/neo/rest/v1/feed?start_date={}&end_date={}&api_key={}",
-                begin, end, api_key
-            );
-            let res = reqwest::get(url).await?.text().await?;
-            let res_json: Value = serde_json::from_str(&res)?;
+            Ok(())
+        }
+    }
 
-            let mut neos_list: Vec<Neo> = Vec::new();
+    /// Blanks the comment instead of removing the row, so replies keep their place in the thread
+    pub async fn delete_comment(&self, user_id: i32, comment_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            r#"UPDATE comments SET body = '', deleted_at = now()
+               WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL
+            "#,
+            comment_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
 
-            for neos in res_json["near_earth_objects"].as_object().unwrap() {
-                for neo_date in neos.1.as_array().unwrap() {
-                    for approach_data in neo_date["close_approach_data"].as_array().unwrap() {
-                        let neo = Neo {
-                            id: NeoId(0),
-                            api_id: neo_date["id"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", "")
-                                .parse()
-                                .unwrap(),
-                            designation: neo_date["name"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", ""),
-                            diameter_min: neo_date["estimated_diameter"]["miles"]
-                                ["estimated_diameter_min"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", "")
-                                .parse()
-                                .unwrap(),
-                            diameter_max: neo_date["estimated_diameter"]["miles"]
-                                ["estimated_diameter_max"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", "")
-                                .parse()
-                                .unwrap(),
-                            hazardous_asteroid: neo_date["is_potentially_hazardous_asteroid"]
-                                .as_bool()
-                                .unwrap(),
-                            approach_date: approach_data["close_approach_date"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", ""),
-                            velocity: approach_data["relative_velocity"]["miles_per_hour"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", "")
-                                .parse()
-                                .unwrap(),
-                            miss_distance: approach_data["miss_distance"]["miles"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", "")
-                                .parse()
-                                .unwrap(),
-                            orbiting_body: approach_data["orbiting_body"]
-                                .to_string()
-                                .replace("\\", "")
-                                .replace("\"", ""),
-                        };
-
-                        self.add_neo(
-                            neo.api_id.clone(),
-                            neo.designation.clone(),
-                            neo.diameter_min.clone(),
-                            neo.diameter_max.clone(),
-                            neo.hazardous_asteroid.clone(),
-                            neo.approach_date.clone(),
-                            neo.velocity.clone(),
-                            neo.miss_distance.clone(),
-                            neo.orbiting_body.clone(),
-                        )
-                        .await?;
-
-                        neos_list.push(neo);
-                    }
-                }
-            }
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn get_inbox(&self, user_id: i32) -> Result<Vec<InboxMessage>, AppError> {
+        let messages = sqlx::query_as::<_, InboxMessage>(
+            "SELECT * FROM inbox_messages WHERE user_id = $1 ORDER BY created_at DESC, id DESC",
+        )
+        .bind(user_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        Ok(messages)
+    }
 
-            Ok(neos_list)
+    pub async fn mark_inbox_read(&self, user_id: i32, message_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            "UPDATE inbox_messages SET read = true WHERE id = $1 AND user_id = $2",
+            message_id,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
+        } else {
+            Ok(())
         }
     }
 
-    pub async fn get_neo_by_id<T: IntoNeoId>(&mut self, id: T) -> Result<Vec<Neo>, AppError> {
-        let id = id.into_neo_id();
+    /// Approaches from today on, optionally only hazardous ones or only a user's watchlist
+    pub async fn get_upcoming_neos(
+        &self,
+        hazardous: Option<bool>,
+        watchlist_user: Option<i32>,
+    ) -> Result<Vec<Neo>, AppError> {
+        let rows = sqlx::query_as::<_, NeoRow>(
+            r#"SELECT * FROM neos
+               WHERE close_approach_date >= CURRENT_DATE
+                 AND ($1::BOOLEAN IS NULL OR is_potentially_hazardous_asteroid = $1)
+                 AND ($2::INTEGER IS NULL OR api_id IN (SELECT api_id FROM watchlist WHERE user_id = $2))
+               ORDER BY close_approach_date, id
+               LIMIT 1000
+            "#,
+        )
+        .bind(hazardous)
+        .bind(watchlist_user)
+        .fetch_all(&self.conn_pool)
+        .await?;
 
-        let neo_row = sqlx::query!(
-            r#"
-        SELECT * FROM neos WHERE api_id = $1
-        "#,
-            id.0,
+        Ok(rows.into_iter().map(Neo::from).collect())
+    }
+
+    /// The user's calendar feed token, created the first time it is asked for
+    pub async fn get_calendar_token(&self, user_id: i32) -> Result<String, AppError> {
+        let token = sqlx::query_scalar!("SELECT calendar_token FROM users WHERE id = $1", user_id)
+            .fetch_optional(&self.conn_pool)
+            .await?
+            .ok_or(AppError::UserDoesNotExist)?;
+
+        match token {
+            Some(token) => Ok(token),
+            None => self.rotate_calendar_token(user_id).await,
+        }
+    }
+
+    /// Replaces the calendar token, which stops every old feed URL from working
+    pub async fn rotate_calendar_token(&self, user_id: i32) -> Result<String, AppError> {
+        let token = generate_secret_token();
+        sqlx::query!(
+            "UPDATE users SET calendar_token = $1 WHERE id = $2",
+            token,
+            user_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    pub async fn get_user_id_by_calendar_token(&self, token: &str) -> Result<i32, AppError> {
+        let row = sqlx::query!("SELECT id FROM users WHERE calendar_token = $1", token)
+            .fetch_optional(&self.conn_pool)
+            .await?
+            .ok_or(AppError::InvalidToken)?;
+
+        Ok(row.id)
+    }
+
+    pub async fn get_watched_ids(&self, user_id: i32) -> Result<Vec<i32>, AppError> {
+        let ids = sqlx::query_scalar!("SELECT api_id FROM watchlist WHERE user_id = $1", user_id)
+            .fetch_all(&self.conn_pool)
+            .await?;
+
+        Ok(ids)
+    }
+
+    pub async fn get_watchlist(&self, user_id: i32) -> Result<Vec<WatchlistEntry>, AppError> {
+        let rows = sqlx::query_as::<_, WatchlistRow>(
+            r#"SELECT w.api_id, n.designation, n.close_approach_date, n.miss_distance,
+                   n.relative_velocity, n.is_potentially_hazardous_asteroid
+               FROM watchlist w
+               LEFT JOIN LATERAL (
+                   SELECT * FROM neos
+                   WHERE neos.api_id = w.api_id AND neos.close_approach_date >= CURRENT_DATE
+                   ORDER BY neos.close_approach_date
+                   LIMIT 1
+               ) n ON true
+               WHERE w.user_id = $1
+               ORDER BY n.close_approach_date NULLS LAST, w.api_id
+            "#,
         )
+        .bind(user_id)
         .fetch_all(&self.conn_pool)
         .await?;
 
-        if neo_row.len() > 0 {
-            let neos: Vec<_> = neo_row
-                .into_iter()
-                .map(|row| {
-                    Neo {
-                        id: row.id.into(), // Assuming you have a From<u32> for NeoId
-                        api_id: row.api_id,
-                        designation: row.designation,
-                        diameter_min: row.diameter_min,
-                        diameter_max: row.diameter_max,
-                        hazardous_asteroid: row.is_potentially_hazardous_asteroid,
-                        approach_date: row.close_approach_date.to_string(),
-                        velocity: row.relative_velocity,
-                        miss_distance: row.miss_distance,
-                        orbiting_body: row.orbiting_body,
-                    }
-                })
-                .collect();
+        Ok(rows.into_iter().map(WatchlistEntry::from).collect())
+    }
 
-            Ok(neos)
+    pub async fn add_to_watchlist(&self, user_id: i32, api_id: i32) -> Result<(), AppError> {
+        sqlx::query!(
+            "INSERT INTO watchlist(user_id, api_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            user_id,
+            api_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_from_watchlist(&self, user_id: i32, api_id: i32) -> Result<(), AppError> {
+        let result = sqlx::query!(
+            "DELETE FROM watchlist WHERE user_id = $1 AND api_id = $2",
+            user_id,
+            api_id,
+        )
+        .execute(&self.conn_pool)
+        .await?;
+
+        if result.rows_affected() < 1 {
+            Err(AppError::NotFound)
         } else {
-            let api_key = std::env::var("API_KEY").unwrap();
-            let url = format!(
-                "https://api.nasa.gov/neo/rest/v1/neo/{}?api_key={}",
-                id.0, api_key
-            );
-            let res = reqwest::get(url).await?.text().await?;
-            let res_json: NeoJson = serde_json::from_str(&res)?;
+            Ok(())
+        }
+    }
 
-            let mut neos: Vec<Neo> = Vec::new();
+    /// Watched asteroids whose newest stored data is older than `max_age`, or that we have never stored
+    pub async fn get_stale_watched(&self, max_age: Duration) -> Result<Vec<NeoId>, AppError> {
+        let cutoff = Utc::now() - max_age;
+        let rows = sqlx::query!(
+            r#"SELECT DISTINCT w.api_id
+               FROM watchlist w
+               WHERE NOT EXISTS (
+                   SELECT 1 FROM neos WHERE neos.api_id = w.api_id AND neos.ingested_at > $1
+               )
+            "#,
+            cutoff,
+        )
+        .fetch_all(&self.conn_pool)
+        .await?;
 
-            for approach_data in res_json.close_approach_data {
-                let neo = Neo {
-                    id: NeoId(0),
-                    api_id: res_json.id.parse().unwrap(),
-                    designation: res_json.designation.parse().unwrap(),
-                    diameter_min: res_json.estimated_diameter.miles.estimated_diameter_min,
-                    diameter_max: res_json.estimated_diameter.miles.estimated_diameter_max,
-                    hazardous_asteroid: res_json.is_potentially_hazardous_asteroid,
-                    approach_date: approach_data.close_approach_date.clone(),
-                    velocity: approach_data
-                        .relative_velocity
-                        .miles_per_hour
-                        .parse()
-                        .unwrap(),
-                    miss_distance: approach_data.miss_distance.miles.parse().unwrap(),
-                    orbiting_body: approach_data.orbiting_body.clone(),
-                };
-
-                self.add_neo(
-                    neo.api_id.clone(),
-                    neo.designation.clone(),
-                    neo.diameter_min.clone(),
-                    neo.diameter_max.clone(),
-                    neo.hazardous_asteroid.clone(),
-                    neo.approach_date.clone(),
-                    neo.velocity.clone(),
-                    neo.miss_distance.clone(),
-                    neo.orbiting_body.clone(),
-                )
-                .await?;
+        Ok(rows.into_iter().map(|row| NeoId(row.api_id)).collect())
+    }
 
-                neos.push(neo);
-            }
+    /// Pulls fresh data from NeoWs for every stale watched asteroid
+    pub async fn refresh_stale_watched(&mut self, max_age: Duration) -> Result<usize, AppError> {
+        let stale = self.get_stale_watched(max_age).await?;
+        let mut refreshed = 0;
 
-            Ok(neos)
+        for id in stale {
+            // One asteroid failing shouldn't hold back the rest
+            let result = match Self::fetch_neo_from_api(id).await {
+                Ok(neos) => self.ingest_neos(neos).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(_) => refreshed += 1,
+                Err(err) => error!("Failed to refresh watched asteroid {}: {:?}", id, err),
+            }
         }
+
+        Ok(refreshed)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use super::*;
+    use crate::mailer::{QueuedMailer, RecordingMailer};
+    use crate::notify::EmailNotifier;
+    use crate::throttle;
+
+    // The `sqlx::test`s below each get a fresh database with every migration applied,
+    // created through the server `DATABASE_URL` points at
+
+    pub(crate) fn test_store(pool: PgPool, notifiers: Vec<Arc<dyn Notifier>>) -> Store {
+        Store {
+            conn_pool: pool,
+            notifiers: Arc::new(notifiers),
+            mailer: Arc::new(RecordingMailer::default()),
+            oidc: None,
+            events: broadcast::channel(16).0,
+            role_permissions: Arc::default(),
+        }
+    }
+
+    pub(crate) async fn add_user(store: &Store, email: &str) -> i32 {
+        sqlx::query_scalar(
+            "INSERT INTO users(email, password, email_verified) VALUES ($1, 'unused', TRUE) RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&store.conn_pool)
+        .await
+        .unwrap()
+    }
+
+    /// Who [`audit`] says made the change
+    pub(crate) fn admin() -> Claims {
+        Claims {
+            id: 0,
+            email: "admin@example.com".to_string(),
+            role: Role::Admin,
+            banned: false,
+            token_version: 0,
+            exp: u64::MAX,
+            jti: "test".to_string(),
+        }
+    }
+
+    pub(crate) fn audit() -> Audit {
+        Audit {
+            actor: "admin@example.com".to_string(),
+            reason: Some("testing".to_string()),
+            request_id: "test".to_string(),
+        }
+    }
+
+    async fn audit_log(store: &Store) -> Vec<(String, String)> {
+        sqlx::query_as("SELECT action, target FROM audit_log ORDER BY id")
+            .fetch_all(&store.conn_pool)
+            .await
+            .unwrap()
+    }
+
+    fn approach(api_id: i32, hazardous_asteroid: bool) -> CreateNeo {
+        CreateNeo {
+            api_id,
+            designation: format!("Test {}", api_id),
+            diameter_min: 0.1,
+            diameter_max: 0.2,
+            hazardous_asteroid,
+            approach_date: "2024-03-20".to_string(),
+            velocity: 40000.0,
+            miss_distance: 4_000_000.0,
+            orbiting_body: "Earth".to_string(),
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn rows_repeated_in_an_upload_count_as_created(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        store
+            .import_neos(vec![(1, approach(1, false))], false, audit())
+            .await
+            .unwrap();
+
+        let changes = store
+            .import_neos(
+                vec![
+                    (1, approach(1, true)),
+                    (2, approach(2, false)),
+                    (3, approach(2, true)),
+                ],
+                true,
+                audit(),
+            )
+            .await
+            .unwrap();
+
+        let kinds: Vec<_> = changes
+            .iter()
+            .map(|change| (change.row, change.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, NeoEventKind::Updated),
+                (2, NeoEventKind::Created),
+                (3, NeoEventKind::Created),
+            ]
+        );
+        assert_eq!(import::tally(&changes), (2, 1));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn alerts_are_mailed_once_whichever_way_approaches_are_stored(pool: PgPool) {
+        let sink = Arc::new(RecordingMailer::default());
+        let email = EmailNotifier::new(Arc::new(QueuedMailer::spawn(sink.clone())));
+        let mut store = test_store(pool, vec![Arc::new(email)]);
+        let user_id = add_user(&store, "watcher@example.com").await;
+        store
+            .add_alert_rule(
+                user_id,
+                CreateAlertRule {
+                    name: "Hazardous".to_string(),
+                    hazardous_only: true,
+                    max_miss_distance_au: None,
+                    within_days: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        store
+            .import_neos(
+                vec![(1, approach(1, true)), (2, approach(2, false))],
+                false,
+                audit(),
+            )
+            .await
+            .unwrap();
+        // dry runs store nothing, so nobody is alerted
+        store
+            .import_neos(vec![(1, approach(3, true))], true, audit())
+            .await
+            .unwrap();
+        let created = approach(4, true);
+        store
+            .add_neo(
+                created.api_id,
+                created.designation,
+                created.diameter_min,
+                created.diameter_max,
+                created.hazardous_asteroid,
+                created.approach_date,
+                created.velocity,
+                created.miss_distance,
+                created.orbiting_body,
+                audit(),
+            )
+            .await
+            .unwrap();
+        store
+            .ingest_neos(vec![Neo::from(approach(5, true))])
+            .await
+            .unwrap();
+        // the same approach again is an update, which was already alerted on
+        store
+            .import_neos(vec![(1, approach(1, true))], false, audit())
+            .await
+            .unwrap();
+
+        let mut sent = sink.wait_for(3).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(sink.sent.lock().unwrap().len(), 3);
+        sent.sort_by(|a, b| a.subject.cmp(&b.subject));
+        assert!(sent.iter().all(|email| email.to == "watcher@example.com"));
+        assert!(sent[0].subject.starts_with("[Hazardous] Test 1 approaches"));
+        assert!(sent[1].subject.starts_with("[Hazardous] Test 4 approaches"));
+        assert!(sent[2].subject.starts_with("[Hazardous] Test 5 approaches"));
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn every_write_path_publishes_events(pool: PgPool) {
+        let mut store = test_store(pool, vec![]);
+        let mut events = store.events.subscribe();
+
+        let created = approach(1, false);
+        let neo = store
+            .add_neo(
+                created.api_id,
+                created.designation,
+                created.diameter_min,
+                created.diameter_max,
+                created.hazardous_asteroid,
+                created.approach_date,
+                created.velocity,
+                created.miss_distance,
+                created.orbiting_body,
+                audit(),
+            )
+            .await
+            .unwrap();
+        store
+            .update_neo(
+                neo.id,
+                UpdateNeo {
+                    hazardous_asteroid: Some(true),
+                    ..Default::default()
+                },
+                audit(),
+            )
+            .await
+            .unwrap();
+        store
+            .import_neos(
+                vec![(1, approach(1, true)), (2, approach(2, false))],
+                false,
+                audit(),
+            )
+            .await
+            .unwrap();
+        store
+            .import_neos(vec![(1, approach(3, false))], true, audit())
+            .await
+            .unwrap();
+        store
+            .ingest_neos(vec![Neo::from(approach(4, false))])
+            .await
+            .unwrap();
+        store.delete_neo(neo.id, audit()).await.unwrap();
+
+        let mut published = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            published.push((event.kind, event.neo.api_id, event.neo.hazardous_asteroid));
+        }
+        assert_eq!(
+            published,
+            vec![
+                (NeoEventKind::Created, 1, false),
+                (NeoEventKind::Updated, 1, true),
+                (NeoEventKind::Updated, 1, true),
+                (NeoEventKind::Created, 2, false),
+                (NeoEventKind::Created, 4, false),
+                (NeoEventKind::Deleted, 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_feed_pages_are_errors() {
+        let feed = serde_json::json!({
+            "near_earth_objects": {
+                "2024-03-20": [{
+                    "id": "3542519",
+                    "name": "(2010 PK9)",
+                    "estimated_diameter": {
+                        "miles": {"estimated_diameter_min": 0.06, "estimated_diameter_max": 0.15}
+                    },
+                    "is_potentially_hazardous_asteroid": true,
+                    "close_approach_data": [{
+                        "close_approach_date": "2024-03-20",
+                        "epoch_date_close_approach": 1710936000000_i64,
+                        "relative_velocity": {"miles_per_hour": "69201.9"},
+                        "miss_distance": {"miles": "4140648.4"},
+                        "orbiting_body": "Earth"
+                    }]
+                }]
+            }
+        });
+        let neos = neos_from_feed(&feed).unwrap();
+        assert_eq!(neos.len(), 1);
+        assert_eq!(neos[0].api_id, 3542519);
+        assert_eq!(neos[0].designation, "(2010 PK9)");
+        assert!(neos[0].approach_time.is_some());
+
+        let broken = |pointer: &str, value: Value| {
+            let mut feed = feed.clone();
+            *feed.pointer_mut(pointer).unwrap() = value;
+            neos_from_feed(&feed)
+        };
+        let day = "/near_earth_objects/2024-03-20";
+        for result in [
+            neos_from_feed(&serde_json::json!({"error_message": "too long"})),
+            broken(day, Value::Null),
+            broken(&format!("{}/0/id", day), "not-an-id".into()),
+            broken(&format!("{}/0/close_approach_data", day), Value::Null),
+            broken(
+                &format!("{}/0/close_approach_data/0/miss_distance/miles", day),
+                "far".into(),
+            ),
+        ] {
+            assert!(matches!(result, Err(AppError::InvalidApiData(_))));
+        }
+    }
+
+    #[test]
+    fn long_ranges_are_fetched_a_week_at_a_time() {
+        let date = |day| NaiveDate::from_ymd_opt(2024, 3, day).unwrap();
+        let windows = feed_windows(DateRange {
+            begin: date(1),
+            end: date(31),
+        })
+        .unwrap();
+        assert_eq!(
+            windows,
+            vec![
+                (date(1), date(7)),
+                (date(8), date(14)),
+                (date(15), date(21)),
+                (date(22), date(28)),
+                (date(29), date(31)),
+            ]
+        );
+
+        assert!(matches!(
+            feed_windows(DateRange {
+                begin: date(1),
+                end: date(1) + Duration::days(MAX_FEED_DAYS),
+            }),
+            Err(AppError::InvalidDateRange(_))
+        ));
+    }
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn bad_numbers_from_neows_are_errors() {
+        assert_eq!(
+            parse_api_number::<f32>(" 4140648.5 ", "miles").unwrap(),
+            4140648.5
+        );
+        assert_eq!(parse_api_number::<i32>("2000433", "id").unwrap(), 2000433);
+        assert!(matches!(
+            parse_api_number::<f32>("far", "miles"),
+            Err(AppError::InvalidApiData(_))
+        ));
+        assert!(parse_api_number::<i32>("", "id").is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn audit_rows_are_written_with_the_change_or_not_at_all(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let user_id = add_user(&store, "user@example.com").await;
+
+        store
+            .import_neos(vec![(1, approach(1, true))], false, audit())
+            .await
+            .unwrap();
+        store
+            .import_neos(vec![(1, approach(2, true))], true, audit())
+            .await
+            .unwrap();
+        store
+            .set_role(user_id, Role::Analyst, audit())
+            .await
+            .unwrap();
+        store
+            .ban_user(&admin(), user_id, None, audit())
+            .await
+            .unwrap();
+        // nothing changes on these, so nothing is logged
+        assert!(store.delete_neo(NeoId(999), audit()).await.is_err());
+        assert!(store
+            .set_role(user_id + 1, Role::Admin, audit())
+            .await
+            .is_err());
+        assert!(!store
+            .unlock_account("user@example.com", audit())
+            .await
+            .unwrap());
+
+        assert_eq!(
+            audit_log(&store).await,
+            vec![
+                (
+                    "import_neos".to_string(),
+                    "1 rows, 1 new and 0 updated".to_string()
+                ),
+                ("set_role".to_string(), "user@example.com".to_string()),
+                ("ban_user".to_string(), "user@example.com".to_string()),
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn bans_and_role_changes_reach_the_next_token(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let user_id = add_user(&store, "user@example.com").await;
+        let issued = store.get_session_user("user@example.com").await.unwrap();
+        assert!(!issued.banned);
+
+        store
+            .set_role(user_id, Role::Analyst, audit())
+            .await
+            .unwrap();
+        let promoted = store.get_session_user("user@example.com").await.unwrap();
+        assert_eq!(promoted.role, "analyst");
+        assert!(promoted.token_version > issued.token_version);
+        assert!(store
+            .role_allows(Role::Analyst, "write_neos")
+            .await
+            .unwrap());
+        assert!(!store.role_allows(Role::Viewer, "write_neos").await.unwrap());
+        assert!(!store
+            .role_allows(Role::Moderator, "manage_users")
+            .await
+            .unwrap());
+
+        store
+            .ban_user(&admin(), user_id, None, audit())
+            .await
+            .unwrap();
+        let banned = store.get_session_user("user@example.com").await.unwrap();
+        assert!(banned.banned);
+        assert!(banned.token_version > promoted.token_version);
+
+        assert_eq!(
+            store.unban_user(&admin(), user_id, audit()).await.unwrap(),
+            1
+        );
+        let unbanned = store.get_session_user("user@example.com").await.unwrap();
+        assert!(!unbanned.banned);
+        assert!(unbanned.token_version > banned.token_version);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn parallel_login_attempts_are_counted_before_any_is_checked(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let keys = vec![Subject::Account("user@example.com").key()];
+
+        let attempts = (0..10).map(|_| {
+            let store = store.clone();
+            let keys = keys.clone();
+            tokio::spawn(async move {
+                store
+                    .reserve_login_attempt(&keys, throttle::FAILURE_WINDOW)
+                    .await
+            })
+        });
+        let results = futures::future::join_all(attempts).await;
+
+        // the free attempts get through, the rest have to wait for the delay the third one started
+        let (allowed, refused): (Vec<_>, Vec<_>) = results
+            .into_iter()
+            .map(Result::unwrap)
+            .partition(Result::is_ok);
+        assert_eq!(allowed.len(), 3);
+        assert!(refused
+            .iter()
+            .all(|result| matches!(result, Err(AppError::LoginThrottled(_)))));
+
+        store.release_login_attempt(&keys).await.unwrap();
+        let failures: i32 = sqlx::query_scalar("SELECT failures FROM login_throttles")
+            .fetch_one(&store.conn_pool)
+            .await
+            .unwrap();
+        assert_eq!(failures, 2);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn only_lower_roles_can_be_banned(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let moderator_id = add_user(&store, "moderator@example.com").await;
+        let other_moderator_id = add_user(&store, "other@example.com").await;
+        let admin_id = add_user(&store, "admin@example.com").await;
+        let viewer_id = add_user(&store, "viewer@example.com").await;
+        for (user_id, role) in [
+            (moderator_id, Role::Moderator),
+            (other_moderator_id, Role::Moderator),
+            (admin_id, Role::Admin),
+        ] {
+            store.set_role(user_id, role, audit()).await.unwrap();
+        }
+        let moderator = Claims {
+            id: moderator_id,
+            email: "moderator@example.com".to_string(),
+            role: Role::Moderator,
+            ..admin()
+        };
+
+        for target in [admin_id, other_moderator_id] {
+            assert!(matches!(
+                store.ban_user(&moderator, target, None, audit()).await,
+                Err(AppError::Forbidden)
+            ));
+        }
+        assert!(matches!(
+            store
+                .ban_user(&moderator, moderator_id, None, audit())
+                .await,
+            Err(AppError::InvalidBan(_))
+        ));
+        assert!(matches!(
+            store
+                .ban_user(
+                    &Claims {
+                        id: admin_id,
+                        ..admin()
+                    },
+                    admin_id,
+                    None,
+                    audit()
+                )
+                .await,
+            Err(AppError::InvalidBan(_))
+        ));
+        assert!(store.get_active_ban(admin_id).await.unwrap().is_none());
+
+        store
+            .ban_user(&moderator, viewer_id, None, audit())
+            .await
+            .unwrap();
+        assert!(store.get_active_ban(viewer_id).await.unwrap().is_some());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn only_lower_roles_can_be_unbanned(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let moderator_id = add_user(&store, "moderator@example.com").await;
+        let other_moderator_id = add_user(&store, "other@example.com").await;
+        let viewer_id = add_user(&store, "viewer@example.com").await;
+        for user_id in [moderator_id, other_moderator_id] {
+            store
+                .set_role(user_id, Role::Moderator, audit())
+                .await
+                .unwrap();
+        }
+        for user_id in [moderator_id, other_moderator_id, viewer_id] {
+            store
+                .ban_user(&admin(), user_id, None, audit())
+                .await
+                .unwrap();
+        }
+        let moderator = Claims {
+            id: moderator_id,
+            email: "moderator@example.com".to_string(),
+            role: Role::Moderator,
+            ..admin()
+        };
+
+        for target in [other_moderator_id, moderator_id] {
+            assert!(matches!(
+                store.unban_user(&moderator, target, audit()).await,
+                Err(AppError::Forbidden)
+            ));
+            assert!(store.get_active_ban(target).await.unwrap().is_some());
+        }
+
+        assert_eq!(
+            store
+                .unban_user(&moderator, viewer_id, audit())
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(store.get_active_ban(viewer_id).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn deleting_an_account_blanks_comments_and_keeps_bans(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let user_id = add_user(&store, "leaving@example.com").await;
+        let other_id = add_user(&store, "staying@example.com").await;
+
+        store.add_to_watchlist(user_id, 2000433).await.unwrap();
+        store
+            .add_saved_query(
+                user_id,
+                CreateSavedQuery {
+                    name: "close ones".to_string(),
+                    begin_date: "2024-01-01".to_string(),
+                    end_date: None,
+                    hazardous: Some(true),
+                    orbiting_body: None,
+                    max_miss_distance: None,
+                },
+            )
+            .await
+            .unwrap();
+        store
+            .add_alert_rule(
+                user_id,
+                CreateAlertRule {
+                    name: "hazardous".to_string(),
+                    hazardous_only: true,
+                    max_miss_distance_au: None,
+                    within_days: Some(7),
+                },
+            )
+            .await
+            .unwrap();
+        let key = ValidApiKey {
+            name: "script".to_string(),
+            scopes: vec!["read:neos".to_string()],
+            expires_at: None,
+        };
+        store
+            .add_api_key(user_id, key, &crate::models::api_key::generate_key())
+            .await
+            .unwrap();
+        store
+            .create_refresh_token(user_id, "hash", "family", Utc::now() + Duration::days(1))
+            .await
+            .unwrap();
+        let comment = store
+            .add_comment(user_id, 2000433, None, "first".to_string())
+            .await
+            .unwrap();
+        let reply = store
+            .add_comment(other_id, 2000433, Some(comment.id), "second".to_string())
+            .await
+            .unwrap();
+        store
+            .ban_user(&admin(), user_id, None, audit())
+            .await
+            .unwrap();
+
+        store.delete_account(user_id).await.unwrap();
+
+        let (body, author, deleted): (String, Option<i32>, bool) = sqlx::query_as(
+            "SELECT body, user_id, deleted_at IS NOT NULL FROM comments WHERE id = $1",
+        )
+        .bind(comment.id)
+        .fetch_one(&store.conn_pool)
+        .await
+        .unwrap();
+        assert_eq!((body.as_str(), author, deleted), ("", None, true));
+        let reply = store.get_comment(reply.id).await.unwrap();
+        assert_eq!(reply.body, "second");
+        assert!(reply.deleted_at.is_none());
+
+        for table in [
+            "watchlist",
+            "saved_queries",
+            "alert_rules",
+            "api_keys",
+            "refresh_tokens",
+        ] {
+            let left: i64 =
+                sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table} WHERE user_id = $1"))
+                    .bind(user_id)
+                    .fetch_one(&store.conn_pool)
+                    .await
+                    .unwrap();
+            assert_eq!(left, 0, "{table} kept rows of the deleted account");
+        }
+
+        let filter = BanFilter {
+            email: Some("Leaving@example.com".to_string()),
+        };
+        let bans = store.get_bans(&filter, 10).await.unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].email, "(deleted account)");
+    }
 }
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use jsonwebtoken::{decode, encode, Header, Validation};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::models::user::KEYS;
+use crate::{get_timestamp_after, hash_secret_token};
+
+/// What a token sent by email may be used for. Each purpose is its own JWT audience,
+/// so a verification link can't reset a password and neither is accepted as a login token
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl Purpose {
+    fn audience(&self) -> &'static str {
+        match self {
+            Purpose::VerifyEmail => "verify_email",
+            Purpose::ResetPassword => "reset_password",
+        }
+    }
+
+    /// Reset links are as good as the password, so they don't last long
+    pub fn ttl(&self) -> Duration {
+        match self {
+            Purpose::VerifyEmail => Duration::from_secs(24 * 60 * 60),
+            Purpose::ResetPassword => Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailClaims {
+    /// The user's id
+    pub sub: i32,
+    pub email: String,
+    aud: String,
+    exp: u64,
+    /// Fingerprint of the password hash the token was issued against. Once the password changes the
+    /// token stops working, which makes reset links single use
+    pwd: String,
+}
+
+/// A short, one-way fingerprint of a stored password hash
+fn fingerprint(password_hash: &str) -> String {
+    hash_secret_token(password_hash)[..16].to_string()
+}
+
+pub fn sign(
+    purpose: Purpose,
+    user_id: i32,
+    email: &str,
+    password_hash: &str,
+) -> Result<String, AppError> {
+    let claims = EmailClaims {
+        sub: user_id,
+        email: email.to_string(),
+        aud: purpose.audience().to_string(),
+        exp: get_timestamp_after(purpose.ttl()),
+        pwd: fingerprint(password_hash),
+    };
+
+    encode(&Header::default(), &claims, &KEYS.encoding).map_err(|_| AppError::InternalServerError)
+}
+
+/// Checks the signature, expiry and purpose of a token. `password_hash` has to be looked up from
+/// [`EmailClaims::sub`] first, see [`check_password`]
+pub fn verify(purpose: Purpose, token: &str) -> Result<EmailClaims, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[purpose.audience()]);
+
+    decode::<EmailClaims>(token, &KEYS.decoding, &validation)
+        .map(|data| data.claims)
+        .map_err(|_| AppError::InvalidToken)
+}
+
+/// Whether the password is still the one the token was issued against
+pub fn check_password(claims: &EmailClaims, password_hash: &str) -> Result<(), AppError> {
+    if claims.pwd == fingerprint(password_hash) {
+        Ok(())
+    } else {
+        Err(AppError::InvalidToken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session;
+
+    #[test]
+    fn tokens_only_work_for_their_purpose() {
+        std::env::set_var("JWT_SECRET", "test secret");
+
+        let token = sign(Purpose::VerifyEmail, 7, "user@example.com", "$argon2id$old").unwrap();
+        let claims = verify(Purpose::VerifyEmail, &token).unwrap();
+        assert_eq!(claims.sub, 7);
+        assert_eq!(claims.email, "user@example.com");
+
+        assert!(verify(Purpose::ResetPassword, &token).is_err());
+        assert!(session::decode_access_token(&token).is_err());
+        assert!(verify(Purpose::VerifyEmail, &format!("{}x", token)).is_err());
+    }
+
+    #[test]
+    fn reset_tokens_stop_working_once_the_password_changes() {
+        std::env::set_var("JWT_SECRET", "test secret");
+
+        let token = sign(
+            Purpose::ResetPassword,
+            7,
+            "user@example.com",
+            "$argon2id$old",
+        )
+        .unwrap();
+        let claims = verify(Purpose::ResetPassword, &token).unwrap();
+
+        assert!(check_password(&claims, "$argon2id$old").is_ok());
+        assert!(check_password(&claims, "$argon2id$new").is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        std::env::set_var("JWT_SECRET", "test secret");
+
+        let claims = EmailClaims {
+            sub: 7,
+            email: "user@example.com".to_string(),
+            aud: Purpose::ResetPassword.audience().to_string(),
+            // Well past the default 60 seconds of leeway
+            exp: get_timestamp_after(Duration::ZERO) - 120,
+            pwd: fingerprint("$argon2id$old"),
+        };
+        let token = encode(&Header::default(), &claims, &KEYS.encoding).unwrap();
+
+        assert!(verify(Purpose::ResetPassword, &token).is_err());
+    }
+}
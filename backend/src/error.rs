@@ -1,22 +1,44 @@
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use chrono::ParseError;
+use http::header::RETRY_AFTER;
 use http::StatusCode;
 use reqwest::Error as ReqwestError;
 use serde_json::{json, Error as SerdeError};
 use sqlx::Error;
 
+use crate::models::ban::Ban;
+
 #[derive(Debug)]
 pub enum AppError {
     Database(sqlx::Error),
     MissingCredentials,
-    InvalidPassword,
+    InvalidCredentials,
+    LoginThrottled(std::time::Duration),
+    EmailNotVerified,
+    TwoFactorRequired,
+    SingleSignOn(String),
     UserDoesNotExist,
     UserAlreadyExists,
     InvalidDate(chrono::ParseError),
+    InvalidDateRange(String),
     RequestAPI(ReqwestError),
+    /// NeoWs answered with something we couldn't make sense of
+    InvalidApiData(String),
     SerdeFailedParse(SerdeError),
     InvalidToken,
+    CsrfTokenMismatch,
+    Forbidden,
+    Banned(Box<Ban>),
+    NotFound,
+    InvalidNeo(String),
+    InvalidImport(String),
+    InvalidQuery(String),
+    InvalidComment(String),
+    InvalidRole(String),
+    InvalidBan(String),
+    InvalidApiKey(String),
+    UnsupportedMediaType,
     InternalServerError,
     #[allow(dead_code)]
     Any(anyhow::Error),
@@ -43,13 +65,71 @@ impl IntoResponse for AppError {
                 "There is already an account with that email address in the system".to_string(),
             ),
             AppError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid Token".to_string()),
-            AppError::InvalidPassword => (StatusCode::UNAUTHORIZED, "Invalid Password".to_string()),
+            AppError::CsrfTokenMismatch => (
+                StatusCode::FORBIDDEN,
+                "The form's security token was missing or out of date, reload the page and try again"
+                    .to_string(),
+            ),
+            AppError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "You are not allowed to do that".to_string(),
+            ),
+            AppError::Banned(ban) => (StatusCode::FORBIDDEN, ban.notice()),
+            AppError::NotFound => (
+                StatusCode::NOT_FOUND,
+                "The requested record could not be found".to_string(),
+            ),
+            AppError::InvalidNeo(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidImport(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidQuery(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidComment(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidRole(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidBan(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidApiKey(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::UnsupportedMediaType => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected application/json, application/x-ndjson or text/csv".to_string(),
+            ),
+            AppError::InvalidCredentials => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid email or password".to_string(),
+            ),
+            AppError::LoginThrottled(retry_after) => {
+                let seconds = retry_after.as_secs().max(1);
+                let body = Json(json!({
+                    "error": format!("Too many failed logins, try again in {} seconds", seconds)
+                }));
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, seconds.to_string())],
+                    body,
+                )
+                    .into_response();
+            }
+            AppError::EmailNotVerified => (
+                StatusCode::FORBIDDEN,
+                "Verify your email address before logging in".to_string(),
+            ),
+            AppError::TwoFactorRequired => (
+                StatusCode::FORBIDDEN,
+                "Your role requires two-factor authentication, log in again to set it up"
+                    .to_string(),
+            ),
+            AppError::SingleSignOn(message) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Single sign-on failed, {}", message),
+            ),
             AppError::InternalServerError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Something terrible happened".to_string(),
             ),
-            AppError::InvalidDate(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+            AppError::InvalidDate(err) => (StatusCode::BAD_REQUEST, err.to_string()),
+            AppError::InvalidDateRange(message) => (StatusCode::BAD_REQUEST, message),
             AppError::RequestAPI(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+            AppError::InvalidApiData(message) => (
+                StatusCode::BAD_GATEWAY,
+                format!("NeoWs sent data we couldn't read, {}", message),
+            ),
             AppError::SerdeFailedParse(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
         };
 
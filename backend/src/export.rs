@@ -0,0 +1,154 @@
+use axum::body::StreamBody;
+use axum::response::{IntoResponse, Response};
+use axum::BoxError;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use http::header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::neo::Neo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<ExportFormat>,
+}
+
+impl ExportFormat {
+    /// An explicit `format=` parameter wins, otherwise fall back to the Accept header
+    pub fn negotiate(requested: Option<ExportFormat>, headers: &HeaderMap) -> Self {
+        if let Some(format) = requested {
+            return format;
+        }
+
+        let accept = headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        if accept.contains("text/csv") {
+            ExportFormat::Csv
+        } else if accept.contains("application/x-ndjson") {
+            ExportFormat::Ndjson
+        } else {
+            ExportFormat::Json
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Turns a row stream from the database into a streamed CSV or NDJSON body,
+/// so the full result set is never held in memory
+pub fn stream_neos(
+    format: ExportFormat,
+    neos: BoxStream<'static, Result<Neo, sqlx::Error>>,
+    file_name: &str,
+) -> Response {
+    let body = neos
+        .enumerate()
+        .map(move |(index, neo)| -> Result<String, BoxError> {
+            let neo = neo?;
+            match format {
+                ExportFormat::Csv => csv_row(&neo, index == 0),
+                _ => Ok(format!("{}\n", serde_json::to_string(&neo)?)),
+            }
+        });
+
+    let mut response = StreamBody::new(body).into_response();
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}.{}\"",
+        file_name,
+        format.extension()
+    )) {
+        response
+            .headers_mut()
+            .insert(CONTENT_DISPOSITION, disposition);
+    }
+
+    response
+}
+
+fn csv_row(neo: &Neo, with_headers: bool) -> Result<String, BoxError> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(with_headers)
+        .from_writer(vec![]);
+    writer.serialize(neo)?;
+    let bytes = writer.into_inner().map_err(|err| err.into_error())?;
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::neo::NeoId;
+
+    #[test]
+    fn format_parameter_overrides_accept_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/csv"));
+
+        assert_eq!(
+            ExportFormat::negotiate(Some(ExportFormat::Ndjson), &headers),
+            ExportFormat::Ndjson
+        );
+        assert_eq!(ExportFormat::negotiate(None, &headers), ExportFormat::Csv);
+        assert_eq!(
+            ExportFormat::negotiate(None, &HeaderMap::new()),
+            ExportFormat::Json
+        );
+    }
+
+    #[test]
+    fn csv_header_only_on_first_row() {
+        let neo = Neo::new(
+            NeoId(1),
+            3542519,
+            "2010 PK9".to_string(),
+            0.5,
+            1.5,
+            true,
+            "1900-06-01".to_string(),
+            69201.0,
+            4140648.0,
+            "Merc".to_string(),
+            None,
+        );
+
+        let first = csv_row(&neo, true).unwrap();
+        let second = csv_row(&neo, false).unwrap();
+
+        assert!(first.starts_with("id,api_id,designation"));
+        assert_eq!(
+            second,
+            "1,3542519,2010 PK9,0.5,1.5,true,1900-06-01,69201.0,4140648.0,Merc,\n"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use html_escape::{encode_double_quoted_attribute, encode_text};
+
+use crate::models::neo::Neo;
+
+/// How many entries the feed carries, readers only care about the latest ones
+pub const FEED_LENGTH: i64 = 50;
+
+/// Renders recently ingested approaches as an RFC 4287 Atom feed.
+///
+/// `entries` pairs each approach with when it was ingested and should already be newest first.
+/// Links are made absolute with `base_url` so feed readers can follow them.
+pub fn render(
+    entries: &[(Neo, DateTime<Utc>)],
+    base_url: &str,
+    self_path: &str,
+    now: DateTime<Utc>,
+) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    // An empty feed has nothing newer than the moment it was generated
+    let updated = entries
+        .iter()
+        .map(|(_, ingested_at)| *ingested_at)
+        .max()
+        .unwrap_or(now);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str("  <id>tag:earths-close-calls,2023:feeds/hazardous</id>\n");
+    xml.push_str("  <title>Newly ingested potentially hazardous asteroids</title>\n");
+    xml.push_str(&format!("  <updated>{}</updated>\n", timestamp(updated)));
+    xml.push_str("  <author><name>Earth's Close Calls</name></author>\n");
+    xml.push_str(&format!(
+        "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>\n",
+        encode_double_quoted_attribute(&format!("{}{}", base_url, self_path))
+    ));
+    xml.push_str(&format!(
+        "  <link rel=\"alternate\" type=\"text/html\" href=\"{}/\"/>\n",
+        encode_double_quoted_attribute(base_url)
+    ));
+
+    for (neo, ingested_at) in entries {
+        xml.push_str(&entry(neo, *ingested_at, base_url));
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn entry(neo: &Neo, ingested_at: DateTime<Utc>, base_url: &str) -> String {
+    let summary = format!(
+        "{} passes {} on {} at {} miles, travelling {} mph. Diameter {} to {} miles.",
+        neo.designation,
+        neo.orbiting_body,
+        neo.approach_date,
+        neo.miss_distance,
+        neo.velocity,
+        neo.diameter_min,
+        neo.diameter_max,
+    );
+
+    format!(
+        "  <entry>\n    \
+             <id>tag:earths-close-calls,2023:neo/{}</id>\n    \
+             <title>{} close approach on {}</title>\n    \
+             <updated>{}</updated>\n    \
+             <link rel=\"alternate\" type=\"text/html\" href=\"{}/neo/id?neo_id={}\"/>\n    \
+             <summary type=\"text\">{}</summary>\n  \
+         </entry>\n",
+        neo.id,
+        encode_text(&neo.designation),
+        encode_text(&neo.approach_date),
+        timestamp(ingested_at),
+        encode_double_quoted_attribute(base_url),
+        neo.api_id,
+        encode_text(&summary),
+    )
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::neo::NeoId;
+    use chrono::TimeZone;
+    use roxmltree::{Document, Node};
+
+    const ATOM: &str = "http://www.w3.org/2005/Atom";
+
+    fn neo(id: i32, designation: &str) -> Neo {
+        Neo::new(
+            NeoId(id),
+            3542500 + id,
+            designation.to_string(),
+            0.1,
+            0.3,
+            true,
+            "2024-03-20".to_string(),
+            45000.0,
+            3000000.0,
+            "Earth".to_string(),
+            None,
+        )
+    }
+
+    fn children<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Vec<Node<'a, 'input>> {
+        node.children()
+            .filter(|child| child.has_tag_name((ATOM, name)))
+            .collect()
+    }
+
+    fn assert_date_construct(node: Node, name: &str) {
+        let updated = children(node, name);
+        assert_eq!(updated.len(), 1, "exactly one atom:{}", name);
+        let text = updated[0].text().unwrap_or_default();
+        assert!(
+            DateTime::parse_from_rfc3339(text).is_ok(),
+            "atom:{} must be an RFC 3339 date-time, got {:?}",
+            name,
+            text
+        );
+    }
+
+    fn assert_iri(node: Node) {
+        let id = children(node, "id");
+        assert_eq!(id.len(), 1, "exactly one atom:id");
+        let text = id[0].text().unwrap_or_default();
+        assert!(
+            text.split_once(':')
+                .is_some_and(|(scheme, rest)| { !scheme.is_empty() && !rest.is_empty() }),
+            "atom:id must be an absolute IRI, got {:?}",
+            text
+        );
+    }
+
+    /// Spot-checks the elements RFC 4287 requires of a feed and its entries. This isn't schema
+    /// validation: it covers ids, titles, updated dates, authors and links, nothing else
+    fn assert_required_atom_elements(xml: &str) {
+        let document = Document::parse(xml).expect("feed must be well formed XML");
+        let feed = document.root_element();
+        assert!(feed.has_tag_name((ATOM, "feed")), "root must be atom:feed");
+
+        assert_iri(feed);
+        assert_eq!(children(feed, "title").len(), 1, "exactly one atom:title");
+        assert_date_construct(feed, "updated");
+
+        for link in children(feed, "link") {
+            assert!(link.attribute("href").is_some(), "atom:link needs an href");
+        }
+        assert!(
+            children(feed, "link")
+                .iter()
+                .any(|link| link.attribute("rel") == Some("self")),
+            "feeds should link to themselves"
+        );
+
+        let feed_has_author = !children(feed, "author").is_empty();
+        for author in children(feed, "author") {
+            assert_eq!(
+                children(author, "name").len(),
+                1,
+                "atom:author needs a name"
+            );
+        }
+
+        for entry in children(feed, "entry") {
+            assert_iri(entry);
+            assert_eq!(children(entry, "title").len(), 1, "exactly one atom:title");
+            assert_date_construct(entry, "updated");
+            assert!(
+                feed_has_author || !children(entry, "author").is_empty(),
+                "every entry needs an author, directly or through the feed"
+            );
+            // Without atom:content an entry must have an alternate link
+            if children(entry, "content").is_empty() {
+                let alternates: Vec<_> = children(entry, "link")
+                    .into_iter()
+                    .filter(|link| link.attribute("rel").unwrap_or("alternate") == "alternate")
+                    .collect();
+                assert!(!alternates.is_empty(), "entry needs an alternate link");
+                assert!(
+                    alternates.iter().all(|link| link
+                        .attribute("href")
+                        .is_some_and(|href| href.starts_with("http"))),
+                    "alternate links must be absolute"
+                );
+            }
+            assert!(children(entry, "summary").len() <= 1);
+        }
+    }
+
+    #[test]
+    fn feed_has_the_required_atom_elements_newest_first() {
+        let older = Utc.with_ymd_and_hms(2024, 3, 1, 8, 0, 0).unwrap();
+        let newer = Utc.with_ymd_and_hms(2024, 3, 2, 8, 0, 0).unwrap();
+        let entries = vec![(neo(2, "2024 <B> & co"), newer), (neo(1, "2024 A"), older)];
+
+        let xml = render(
+            &entries,
+            "http://localhost:3000/",
+            "/feeds/hazardous.atom?orbiting_body=Earth&max_miss_distance=5000000",
+            Utc::now(),
+        );
+
+        assert_required_atom_elements(&xml);
+
+        let document = Document::parse(&xml).unwrap();
+        let feed = document.root_element();
+        assert_eq!(
+            children(feed, "updated")[0].text(),
+            Some("2024-03-02T08:00:00Z")
+        );
+        let titles: Vec<_> = children(feed, "entry")
+            .into_iter()
+            .map(|entry| children(entry, "title")[0].text().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                "2024 <B> & co close approach on 2024-03-20",
+                "2024 A close approach on 2024-03-20"
+            ]
+        );
+        assert!(xml.contains("href=\"http://localhost:3000/neo/id?neo_id=3542502\""));
+    }
+
+    #[test]
+    fn empty_feed_still_has_the_required_atom_elements() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 2, 8, 0, 0).unwrap();
+        let xml = render(&[], "http://localhost:3000", "/feeds/hazardous.atom", now);
+
+        assert_required_atom_elements(&xml);
+        assert!(xml.contains("<updated>2024-03-02T08:00:00Z</updated>"));
+    }
+}
@@ -1,27 +1,67 @@
-use argon2::Config;
-use axum::extract::{Path, Query, State};
-use axum::response::{Html, Response};
+use axum::body::Bytes;
+use axum::extract::{OriginalUri, Path, Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::{Form, Json};
+use futures::Stream;
 use http::header::{LOCATION, SET_COOKIE};
-use http::{HeaderValue, StatusCode};
+use http::{HeaderMap, HeaderValue, StatusCode};
 use hyper::Body;
-use jsonwebtoken::Header;
 use serde_json::Value;
+use std::collections::HashSet;
 use tera::Context;
-use tracing::error;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
 
+use crate::calendar::{self, CalendarQuery, CalendarToken};
 use crate::db::Store;
+use crate::email_token::{self, Purpose};
 use crate::error::AppError;
-use crate::get_timestamp_after_8_hours;
-use crate::models::neo::{CreateDateRange, CreateNeo, GetNeoById, Neo, NeoId};
-use crate::models::user::{Claims, GetUserByEmail, OptionalClaims, User, UserSignup, KEYS};
+use crate::export::{self, ExportFormat, ExportQuery};
+use crate::feed;
+use crate::generate_secret_token;
+use crate::import::{self, ImportOptions, ImportReport, RowError};
+use crate::layers::{ClientIp, RequestId};
+use crate::mailer::Email;
+use crate::models::alert::{AlertRule, CreateAlertRule, InboxMessage};
+use crate::models::api_key::{
+    self, ApiKey, ApiKeyForm, ApiKeyId, ApiScope, CreateApiKey, CreatedApiKey,
+};
+use crate::models::audit::{self, Audit, AuditAction, AuditFilter, AuditReason};
+use crate::models::ban::{self, BanFilter, BanForm, UnbanForm};
+use crate::models::comment::{self, Comment, CommentForm, CreateComment, UpdateComment};
+use crate::models::date_range::DateRange;
+use crate::models::event::EventFilter;
+use crate::models::neo::{CreateNeo, GetNeoById, Neo, NeoFilter, NeoId, UpdateNeo};
+use crate::models::role::{
+    BanUsers, Comment as CommentPermission, ManageUsers, ModerateComments, ReadNeos, Role, SetRole,
+    ViewAuditLog, WriteNeos,
+};
+use crate::models::saved_query::{CreateSavedQuery, SavedQuery, SavedQueryId, ShareLink};
+use crate::models::settings::{self, ChangePassword, DeleteAccount};
+use crate::models::user::{
+    Account, ApiClaims, Claims, CodeForm, EmailForm, OptionalClaims, RequirePermission,
+    ResetPassword, TokenQuery, TwoFactorUser, UnlockForm, User, UserSignup,
+};
+use crate::models::watchlist::{AddWatch, WatchlistEntry};
+use crate::oidc;
+use crate::password;
+use crate::session;
+use crate::throttle::{self, LockedAccount, LoginThrottle, Subject};
+use crate::two_factor;
 
 use crate::template::TEMPLATES;
+use crate::PUBLIC_URL;
+
+/// How many entries the audit page shows, the CSV export has everything
+const AUDIT_PAGE_LENGTH: i64 = 500;
+const BAN_PAGE_LENGTH: i64 = 500;
 
 #[allow(dead_code)]
 pub async fn root(
     State(am_database): State<Store>,
     OptionalClaims(claims): OptionalClaims,
+    moderator: Option<RequirePermission<BanUsers>>,
 ) -> Result<Html<String>, AppError> {
     let mut context = Context::new();
 
@@ -29,16 +69,23 @@ pub async fn root(
         error!("Setting claims and is_logged_in is TRUE now");
         context.insert("claims", &claims_data);
         context.insert("is_logged_in", &true);
-        let admin = am_database.check_admin(claims_data.email.clone()).await?;
-        let banned = am_database.check_banned(claims_data.email.clone()).await?;
-        if banned {
+        let admin = moderator.is_some();
+        let ban = claims_data.active_ban(&am_database).await?;
+        if let Some(ban) = ban {
             error!("is_banned is TRUE now");
             error!("is_logged_in is FALSE now");
             context.insert("is_banned", &true);
+            context.insert("ban", &ban);
             context.insert("is_logged_in", &false);
 
             "index.html"
         } else {
+            let user_id = claims_data.id;
+            let watchlist = am_database.get_watchlist(user_id).await?;
+            context.insert("watchlist", &watchlist);
+            let saved_queries = am_database.get_saved_queries(user_id).await?;
+            context.insert("saved_queries", &saved_queries);
+
             if admin {
                 error!("admin_logged_in is TRUE now");
                 context.insert("admin_logged_in", &true);
@@ -59,6 +106,7 @@ pub async fn root(
         // Handle the case where the user isn't logged in
         error!("is_logged_in is FALSE now");
         context.insert("is_logged_in", &false);
+        context.insert("sso_enabled", &am_database.oidc.is_some());
         "index.html" // Use the original template when not logged in
     };
 
@@ -90,18 +138,22 @@ pub async fn register_page() -> Result<Html<String>, AppError> {
 pub async fn admin_page(
     State(am_database): State<Store>,
     OptionalClaims(claims): OptionalClaims,
+    moderator: Option<RequirePermission<BanUsers>>,
+    user_manager: Option<RequirePermission<ManageUsers>>,
+    auditor: Option<RequirePermission<ViewAuditLog>>,
 ) -> Result<Html<String>, AppError> {
     let mut context = Context::new();
     let template_name = if let Some(claims_data) = claims {
         error!("Setting claims and is_logged_in is TRUE now");
         context.insert("claims", &claims_data);
         context.insert("is_logged_in", &true);
-        let admin = am_database.check_admin(claims_data.email.clone()).await?;
-        let banned = am_database.check_banned(claims_data.email.clone()).await?;
-        if banned {
+        let admin = moderator.is_some();
+        let ban = claims_data.active_ban(&am_database).await?;
+        if let Some(ban) = ban {
             error!("is_banned is TRUE now");
             error!("is_logged_in is FALSE now");
             context.insert("is_banned", &true);
+            context.insert("ban", &ban);
             context.insert("is_logged_in", &false);
 
             "index.html"
@@ -113,6 +165,21 @@ pub async fn admin_page(
                 // Get all the page data
                 let page_packages = am_database.get_all_users().await?;
                 context.insert("page_packages", &page_packages);
+                let manage_users = user_manager.is_some();
+                context.insert("can_manage_users", &manage_users);
+                if manage_users {
+                    let locked_accounts: Vec<LockedAccount> = am_database
+                        .get_locked_accounts()
+                        .await?
+                        .into_iter()
+                        .filter_map(LockedAccount::from_throttle)
+                        .collect();
+                    context.insert("locked_accounts", &locked_accounts);
+                }
+                let view_audit_log = auditor.is_some();
+                context.insert("can_view_audit_log", &view_audit_log);
+                let roles: Vec<&str> = Role::ALL.iter().map(Role::as_str).collect();
+                context.insert("roles", &roles);
 
                 "admin.html"
             } else {
@@ -139,90 +206,66 @@ pub async fn admin_page(
     Ok(Html(rendered))
 }
 
+/// What a page shows visitors `RequirePermission` turned away: the login form to anyone not logged
+/// in, and their ban to banned users. Other rejections stay errors
+fn turned_away_page(am_database: &Store, rejection: AppError) -> Result<Html<String>, AppError> {
+    let mut context = Context::new();
+    context.insert("is_logged_in", &false);
+    match rejection {
+        AppError::Banned(ban) => {
+            context.insert("is_banned", &true);
+            context.insert("ban", &ban);
+        }
+        AppError::InvalidToken => context.insert("sso_enabled", &am_database.oidc.is_some()),
+        rejection => return Err(rejection),
+    }
+
+    Ok(render_page("index.html", &context))
+}
+
 #[allow(dead_code)]
 pub async fn neo_date_page(
     State(mut am_database): State<Store>,
-    OptionalClaims(claims): OptionalClaims,
-    dates: Query<CreateDateRange>,
+    reader: Result<RequirePermission<ReadNeos>, AppError>,
+    dates: DateRange,
 ) -> Result<Html<String>, AppError> {
-    let mut context = Context::new();
-    let template_name = if let Some(claims_data) = claims {
-        error!("Setting claims and is_logged_in is TRUE now");
-        context.insert("claims", &claims_data);
-        context.insert("is_logged_in", &true);
-        let banned = am_database.check_banned(claims_data.email.clone()).await?;
-        if banned {
-            error!("is_banned is TRUE now");
-            error!("is_logged_in is FALSE now");
-            context.insert("is_banned", &true);
-            context.insert("is_logged_in", &false);
-
-            "index.html"
-        } else {
-            let results = am_database
-                .get_neo_by_date(dates.0.begin_date, dates.0.end_date)
-                .await?;
-            context.insert("results", &results);
-            context.insert("is_banned", &false);
-            "neo_date.html"
-        }
-    } else {
-        // Handle the case where the user isn't admin
-        error!("Setting claims and is_logged_in is FALSE now");
-        error!("admin_logged_in is FALSE now");
-        context.insert("is_logged_in", &false);
-        "index.html" // Use the original template when not logged in
+    let claims = match reader {
+        Ok(RequirePermission(claims, _)) => claims,
+        Err(rejection) => return turned_away_page(&am_database, rejection),
     };
 
-    let rendered = TEMPLATES
-        .render(template_name, &context)
-        .unwrap_or_else(|err| {
-            error!("Template rendering error: {}", err);
-            panic!()
-        });
-    Ok(Html(rendered))
+    let results = am_database.get_neo_by_date(dates).await?;
+    let mut context = Context::new();
+    context.insert("claims", &claims);
+    context.insert("is_logged_in", &true);
+    context.insert("is_banned", &false);
+    context.insert("results", &results);
+    context.insert("begin_date", &dates.begin.to_string());
+    context.insert("end_date", &dates.end.to_string());
+    Ok(render_page("neo_date.html", &context))
 }
 
 #[allow(dead_code)]
 pub async fn neo_id_page(
     State(mut am_database): State<Store>,
-    OptionalClaims(claims): OptionalClaims,
+    reader: Result<RequirePermission<ReadNeos>, AppError>,
     neo_id: Query<GetNeoById>,
 ) -> Result<Html<String>, AppError> {
-    let mut context = Context::new();
-    let template_name = if let Some(claims_data) = claims {
-        error!("Setting claims and is_logged_in is TRUE now");
-        context.insert("claims", &claims_data);
-        context.insert("is_logged_in", &true);
-        let banned = am_database.check_banned(claims_data.email.clone()).await?;
-        if banned {
-            error!("is_banned is TRUE now");
-            error!("is_logged_in is FALSE now");
-            context.insert("is_banned", &true);
-            context.insert("is_logged_in", &false);
-
-            "index.html"
-        } else {
-            let results = am_database.get_neo_by_id(NeoId(neo_id.0.neo_id)).await?;
-            context.insert("results", &results);
-            context.insert("is_banned", &false);
-            "neo.html"
-        }
-    } else {
-        // Handle the case where the user isn't admin
-        error!("Setting claims and is_logged_in is FALSE now");
-        error!("admin_logged_in is FALSE now");
-        context.insert("is_logged_in", &false);
-        "index.html" // Use the original template when not logged in
+    let claims = match reader {
+        Ok(RequirePermission(claims, _)) => claims,
+        Err(rejection) => return turned_away_page(&am_database, rejection),
     };
 
-    let rendered = TEMPLATES
-        .render(template_name, &context)
-        .unwrap_or_else(|err| {
-            error!("Template rendering error: {}", err);
-            panic!()
-        });
-    Ok(Html(rendered))
+    let results = am_database.get_neo_by_id(NeoId(neo_id.0.neo_id)).await?;
+    let comments = am_database.get_comments(neo_id.0.neo_id).await?;
+    let mut context = Context::new();
+    context.insert("claims", &claims);
+    context.insert("is_logged_in", &true);
+    context.insert("is_banned", &false);
+    context.insert("results", &results);
+    context.insert("comments", &comment::thread(comments, claims.id));
+    context.insert("api_id", &neo_id.0.neo_id);
+    Ok(render_page("neo.html", &context))
 }
 
 pub async fn register(
@@ -242,34 +285,26 @@ pub async fn register(
     // Check to see if there is already a user in the database with the given email address
     let existing_user = database.get_user(&credentials.email).await;
 
-    if let Ok(_) = existing_user {
+    if existing_user.is_ok() {
         return Err(AppError::UserAlreadyExists);
     }
 
     // Here we're assured that our credentials are valid and the user doesn't already exist
-    // hash their password
-    let hash_config = Config::default();
-    let salt = std::env::var("SALT").expect("Missing SALT");
-    let hashed_password = match argon2::hash_encoded(
-        credentials.password.as_bytes(),
-        // If you'd like unique salts per-user, simply pass &[] and argon will generate them for you
-        salt.as_bytes(),
-        &hash_config,
-    ) {
-        Ok(result) => result,
-        Err(_) => {
-            return Err(AppError::Any(anyhow::anyhow!("Password hashing failed")));
-        }
-    };
-
-    credentials.password = hashed_password;
+    // hash their password with a salt of its own
+    credentials.password = password::hash(&credentials.password)?;
 
+    let email = credentials.email.clone();
     let new_user = database.create_user(credentials).await?;
+    send_verification_email(&database, &email, &PUBLIC_URL).await?;
+
     Ok(new_user)
 }
 
+/// Admins create other admins, the first one is seeded from `ADMIN_EMAIL` at startup
 pub async fn register_admin(
     State(database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ManageUsers>,
+    request_id: RequestId,
     Form(mut credentials): Form<UserSignup>,
 ) -> Result<Json<Value>, AppError> {
     // We should also check to validate other things at some point like email address being in right format
@@ -285,67 +320,271 @@ pub async fn register_admin(
     // Check to see if there is already a user in the database with the given email address
     let existing_user = database.get_user(&credentials.email).await;
 
-    if let Ok(_) = existing_user {
+    if existing_user.is_ok() {
         return Err(AppError::UserAlreadyExists);
     }
 
     // Here we're assured that our credentials are valid and the user doesn't already exist
-    // hash their password
-    let hash_config = Config::default();
-    let salt = std::env::var("SALT").expect("Missing SALT");
-    let hashed_password = match argon2::hash_encoded(
-        credentials.password.as_bytes(),
-        // If you'd like unique salts per-user, simply pass &[] and argon will generate them for you
-        salt.as_bytes(),
-        &hash_config,
-    ) {
-        Ok(result) => result,
-        Err(_) => {
-            return Err(AppError::Any(anyhow::anyhow!("Password hashing failed")));
-        }
-    };
+    // hash their password with a salt of its own
+    credentials.password = password::hash(&credentials.password)?;
 
-    credentials.password = hashed_password;
-
-    let new_user = database.create_admin(credentials).await?;
+    let target = credentials.email.clone();
+    let audit = Audit {
+        actor: claims.email,
+        reason: None,
+        request_id: request_id.0,
+    };
+    let new_user = database.create_admin(credentials, audit).await?;
+    send_verification_email(&database, &target, &PUBLIC_URL).await?;
     Ok(new_user)
 }
 
 pub async fn login(
     State(database): State<Store>,
+    ClientIp(ip): ClientIp,
     Form(creds): Form<User>,
 ) -> Result<Response<Body>, AppError> {
     if creds.email.is_empty() || creds.password.is_empty() {
         return Err(AppError::MissingCredentials);
     }
 
-    let existing_user = database.get_user(&creds.email).await?;
+    let subjects = [Subject::Account(&creds.email), Subject::Ip(ip)];
+    let reserved = reserve_login_attempt(&database, &subjects).await?;
 
-    let is_password_correct =
-        match argon2::verify_encoded(&*existing_user.password, creds.password.as_bytes()) {
-            Ok(result) => result,
-            Err(_) => {
-                return Err(AppError::InternalServerError);
-            }
+    // Unknown emails and wrong passwords get the same answer, in about the same time
+    let existing_user = match database.get_account(&creds.email).await? {
+        Some(account) if password::verify(&account.password, &creds.password)? => account,
+        Some(_) => {
+            record_login_failure(&database, &subjects, &reserved).await?;
+            return Err(AppError::InvalidCredentials);
+        }
+        None => {
+            password::verify_nothing(&creds.password);
+            record_login_failure(&database, &subjects, &reserved).await?;
+            return Err(AppError::InvalidCredentials);
+        }
+    };
+    release_login_attempt(&database, &subjects).await?;
+
+    // The password is known good here, so this is the one chance to move it to the current parameters
+    if password::needs_rehash(&existing_user.password) {
+        let rehashed = password::hash(&creds.password)?;
+        database.update_password(&creds.email, &rehashed).await?;
+    }
+
+    if !existing_user.email_verified {
+        return Err(AppError::EmailNotVerified);
+    }
+
+    finish_login(&database, existing_user.id, &existing_user.email).await
+}
+
+/// Counts the attempt against each of `subjects` before it's checked, refusing it while any of them
+/// has to wait after failed logins, or is locked out
+async fn reserve_login_attempt(
+    database: &Store,
+    subjects: &[Subject<'_>],
+) -> Result<Vec<LoginThrottle>, AppError> {
+    let keys: Vec<String> = subjects.iter().map(Subject::key).collect();
+    database
+        .reserve_login_attempt(&keys, throttle::FAILURE_WINDOW)
+        .await
+}
+
+/// The attempt was good after all, so it doesn't count as a failure
+async fn release_login_attempt(database: &Store, subjects: &[Subject<'_>]) -> Result<(), AppError> {
+    let keys: Vec<String> = subjects.iter().map(Subject::key).collect();
+    database.release_login_attempt(&keys).await
+}
+
+/// The reserved attempt failed. Locks out the subjects that reached their threshold with it
+async fn record_login_failure(
+    database: &Store,
+    subjects: &[Subject<'_>],
+    reserved: &[LoginThrottle],
+) -> Result<(), AppError> {
+    for subject in subjects {
+        let key = subject.key();
+        let Some(throttle) = reserved.iter().find(|throttle| throttle.key == key) else {
+            continue;
         };
+        if throttle.failures >= subject.lockout_threshold() {
+            database
+                .lock_login(&throttle.key, throttle::LOCKOUT)
+                .await?;
+            warn!(
+                "Locked out {} for {} minutes after {} failed logins",
+                throttle.key,
+                throttle::LOCKOUT.as_secs() / 60,
+                throttle.failures
+            );
+        }
+    }
+    Ok(())
+}
 
-    if !is_password_correct {
-        return Err(AppError::InvalidPassword);
+/// Starts a session once the user's identity is confirmed, by password or single sign-on
+async fn finish_login(
+    database: &Store,
+    user_id: i32,
+    email: &str,
+) -> Result<Response<Body>, AppError> {
+    // with two-factor authentication on, or required for their role, that was only the first step.
+    // The session starts in `login_two_factor_form` once the code checks out too
+    let two_factor_user = database.get_two_factor_user(user_id).await?;
+    let role = two_factor_user
+        .role
+        .parse()
+        .map_err(AppError::InvalidRole)?;
+    if two_factor_user.totp_enabled || two_factor::required_for(&role) {
+        let token = two_factor::sign_pending_login(user_id)?;
+        return Ok(redirect_with_cookies(
+            "/login/2fa",
+            [two_factor::login_cookie(&token)],
+        ));
     }
 
     // at this point we've authenticated the user's identity
-    // create JWT to return
-    let claims = Claims {
-        id: 0,
-        email: creds.email.to_owned(),
-        exp: get_timestamp_after_8_hours(),
+    // start a session, a short lived access token plus a refresh token to renew it with
+    database
+        .clear_login_throttle(&Subject::Account(email).key())
+        .await?;
+    let session = session::start(database, email).await?;
+
+    Ok(redirect_with_cookies("/", session.cookies()))
+}
+
+/// Sends the user to the identity provider to sign in, see [`oidc`]. With `?reauth=true` a logged in
+/// user signs in there again to confirm changes on the settings page
+pub async fn oidc_login(
+    State(database): State<Store>,
+    OptionalClaims(claims): OptionalClaims,
+    Query(query): Query<oidc::LoginQuery>,
+) -> Result<Response, AppError> {
+    let config = database.oidc.as_ref().ok_or(AppError::NotFound)?;
+    let reauth_user = match (query.reauth, claims) {
+        (false, _) => None,
+        (true, Some(claims)) => Some(claims.id),
+        (true, None) => return Err(AppError::InvalidToken),
     };
+    let provider = config.provider().await?;
+    let (url, cookie) = oidc::start_login(
+        config,
+        &provider.discovery,
+        &config.redirect_url(&PUBLIC_URL),
+        reauth_user,
+    )?;
+
+    let mut response = Redirect::to(&url).into_response();
+    response.headers_mut().append(SET_COOKIE, cookie);
+    Ok(response)
+}
+
+/// Where the identity provider sends the user back to. Their account is found by the provider's subject,
+/// linked by email address the first time, or created with `OIDC_DEFAULT_ROLE`
+pub async fn oidc_callback(
+    State(database): State<Store>,
+    headers: HeaderMap,
+    Query(query): Query<oidc::CallbackQuery>,
+) -> Result<Response<Body>, AppError> {
+    let config = database.oidc.as_ref().ok_or(AppError::NotFound)?;
+    if let Some(error) = query.error {
+        return Err(AppError::SingleSignOn(format!(
+            "the provider answered {} {}",
+            error,
+            query.error_description.unwrap_or_default()
+        )));
+    }
+    let login = oidc::login_state(&headers)?;
+    if query.state.as_deref() != Some(login.state.as_str()) {
+        return Err(AppError::SingleSignOn(
+            "the login was started elsewhere".to_string(),
+        ));
+    }
+    let code = query
+        .code
+        .ok_or_else(|| AppError::SingleSignOn("the provider sent no code".to_string()))?;
 
-    let token = jsonwebtoken::encode(&Header::default(), &claims, &KEYS.encoding)
-        .map_err(|_| AppError::MissingCredentials)?;
+    let provider = config.provider().await?;
+    let discovery = &provider.discovery;
+    let redirect_url = config.redirect_url(&PUBLIC_URL);
+    let claims = oidc::exchange_code(config, &provider, &login, &code, &redirect_url).await?;
+    if let Some(user_id) = login.reauth_user {
+        let account = database
+            .get_oidc_account(&discovery.issuer, &claims.sub)
+            .await?;
+        if account.map(|account| account.id) != Some(user_id) {
+            return Err(AppError::SingleSignOn(
+                "you signed in as someone else".to_string(),
+            ));
+        }
+        return Ok(redirect_with_cookies(
+            "/account/settings",
+            [oidc::reauth_cookie(user_id)?, oidc::cleared_login_cookie()],
+        ));
+    }
+    let email = claims.email.ok_or_else(|| {
+        AppError::SingleSignOn("the provider didn't share an email address".to_string())
+    })?;
+
+    let account = match database
+        .get_oidc_account(&discovery.issuer, &claims.sub)
+        .await?
+    {
+        Some(account) => account,
+        None => match database.get_account(&email).await? {
+            // Only an address the provider verified may take over a local account
+            Some(account) if claims.email_verified == Some(true) => {
+                if !database
+                    .link_oidc_subject(account.id, &discovery.issuer, &claims.sub)
+                    .await?
+                {
+                    return Err(AppError::SingleSignOn(
+                        "that account is linked to someone else".to_string(),
+                    ));
+                }
+                account
+            }
+            Some(_) => {
+                return Err(AppError::SingleSignOn(
+                    "an account with that email address already exists".to_string(),
+                ))
+            }
+            // Nor may an unverified one take the address from whoever owns it
+            None if claims.email_verified != Some(true) => {
+                return Err(AppError::SingleSignOn(
+                    "the provider hasn't verified your email address".to_string(),
+                ))
+            }
+            None => {
+                let unusable_password = password::hash(&generate_secret_token())?;
+                let account = database
+                    .create_oidc_user(
+                        &email,
+                        &unusable_password,
+                        config.default_role,
+                        &discovery.issuer,
+                        &claims.sub,
+                    )
+                    .await?;
+                info!("Created {} on their first single sign-on", email);
+                account
+            }
+        },
+    };
 
-    let cookie = cookie::Cookie::build("jwt", token).http_only(true).finish();
+    let mut response = finish_login(&database, account.id, &account.email).await?;
+    response
+        .headers_mut()
+        .append(SET_COOKIE, oidc::cleared_login_cookie());
+    Ok(response)
+}
 
+/// A `302 Found` to `location` that sets `cookies`
+fn redirect_with_cookies(
+    location: &'static str,
+    cookies: impl IntoIterator<Item = HeaderValue>,
+) -> Response<Body> {
     let mut response = Response::builder()
         .status(StatusCode::FOUND)
         .body(Body::empty())
@@ -353,40 +592,192 @@ pub async fn login(
 
     response
         .headers_mut()
-        .insert(LOCATION, HeaderValue::from_static("/"));
-    response.headers_mut().insert(
-        SET_COOKIE,
-        HeaderValue::from_str(&cookie.to_string()).unwrap(),
-    );
+        .insert(LOCATION, HeaderValue::from_static(location));
+    for cookie in cookies {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+
+    response
+}
+
+/// Trades the refresh token cookie for a new access and refresh token pair. When another request
+/// just did that, there's nothing to hand out and the cookies it set stay
+pub async fn refresh(
+    State(database): State<Store>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, AppError> {
+    let refresh_token =
+        session::cookie_value(&headers, session::REFRESH_COOKIE).ok_or(AppError::InvalidToken)?;
+    let renewed = session::refresh(&database, &refresh_token).await?;
+
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+    for cookie in renewed.iter().flat_map(session::Session::cookies) {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+/// Revokes the current access token and refresh token family and clears both cookies
+pub async fn logout(
+    State(database): State<Store>,
+    OptionalClaims(claims): OptionalClaims,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let refresh_token = session::cookie_value(&headers, session::REFRESH_COOKIE);
+    session::end(&database, claims.as_ref(), refresh_token.as_deref()).await?;
 
+    let mut response = Redirect::to("/").into_response();
+    for cookie in session::cleared_cookies() {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
     Ok(response)
 }
 
+/// Admin page form banning a user, for good or for `duration`
 pub async fn ban_user(
     State(am_database): State<Store>,
-    Form(user): Form<GetUserByEmail>,
-) -> Result<(), AppError> {
-    am_database.ban_user(user.email).await?;
-    Ok(())
+    RequirePermission(claims, _): RequirePermission<BanUsers>,
+    request_id: RequestId,
+    Form(form): Form<BanForm>,
+) -> Result<Redirect, AppError> {
+    let expires_at =
+        ban::parse_duration(&form.duration)?.map(|duration| chrono::Utc::now() + duration);
+    let reason = AuditReason {
+        reason: Some(form.reason),
+    }
+    .into_reason();
+
+    let audit = Audit {
+        actor: claims.email.clone(),
+        reason,
+        request_id: request_id.0,
+    };
+    let ban = am_database
+        .ban_user(&claims, form.user_id, expires_at, audit)
+        .await?;
+    info!("{} banned {}", claims.email, ban.email);
+    Ok(Redirect::to("/admin"))
+}
+
+/// Admin page form lifting a user's bans before they run out
+pub async fn unban_user(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<BanUsers>,
+    request_id: RequestId,
+    Form(form): Form<UnbanForm>,
+) -> Result<Redirect, AppError> {
+    let reason = AuditReason {
+        reason: Some(form.reason),
+    }
+    .into_reason();
+
+    let audit = Audit {
+        actor: claims.email.clone(),
+        reason,
+        request_id: request_id.0,
+    };
+    if am_database.unban_user(&claims, form.user_id, audit).await? > 0 {
+        info!("{} unbanned user {}", claims.email, form.user_id);
+    }
+    Ok(Redirect::to("/admin"))
+}
+
+/// Every ban with who lifted it and why, optionally for one user
+pub async fn bans_page(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<BanUsers>,
+    Query(filter): Query<BanFilter>,
+) -> Result<Html<String>, AppError> {
+    let bans = am_database.get_bans(&filter, BAN_PAGE_LENGTH).await?;
+
+    let mut context = Context::new();
+    context.insert("claims", &claims);
+    context.insert("is_logged_in", &true);
+    context.insert("bans", &bans);
+    context.insert("filter", &filter);
+
+    Ok(render_page("bans.html", &context))
+}
+
+/// Admin page form for moving a user to another role
+pub async fn set_role_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ManageUsers>,
+    request_id: RequestId,
+    Form(form): Form<SetRole>,
+) -> Result<Redirect, AppError> {
+    let role: Role = form.role.parse().map_err(AppError::InvalidRole)?;
+    let audit = Audit {
+        actor: claims.email,
+        reason: None,
+        request_id: request_id.0,
+    };
+    am_database.set_role(form.user_id, role, audit).await?;
+    Ok(Redirect::to("/admin"))
+}
+
+/// Lifts a lockout after failed logins, and forgets the failures that led to it
+pub async fn unlock_account_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ManageUsers>,
+    request_id: RequestId,
+    Form(form): Form<UnlockForm>,
+) -> Result<Redirect, AppError> {
+    let audit = Audit {
+        actor: claims.email.clone(),
+        reason: AuditReason {
+            reason: Some(form.reason),
+        }
+        .into_reason(),
+        request_id: request_id.0,
+    };
+    if am_database.unlock_account(&form.email, audit).await? {
+        info!("{} unlocked {}", claims.email, form.email);
+    }
+    Ok(Redirect::to("/admin"))
 }
 
-pub async fn protected(claims: Claims) -> Result<String, AppError> {
+pub async fn protected(
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<String, AppError> {
     Ok(format!(
         "Welcome to the PROTECTED area :) \n Your claim data is: {}",
         claims
     ))
 }
 
-pub async fn get_neos(State(am_database): State<Store>) -> Result<Json<Vec<Neo>>, AppError> {
-    let all_neos = am_database.get_all_neos().await?;
-
-    Ok(Json(all_neos))
+pub async fn get_neos(
+    State(am_database): State<Store>,
+    _reader: RequirePermission<ReadNeos>,
+    headers: HeaderMap,
+    Query(export): Query<ExportQuery>,
+    Query(filter): Query<NeoFilter>,
+) -> Result<Response, AppError> {
+    match ExportFormat::negotiate(export.format, &headers) {
+        ExportFormat::Json => {
+            let all_neos = am_database.get_all_neos(&filter).await?;
+            Ok(Json(all_neos).into_response())
+        }
+        format => Ok(export::stream_neos(
+            format,
+            am_database.stream_all_neos(filter),
+            "neos",
+        )),
+    }
 }
 
 pub async fn create_neo(
     State(mut am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<WriteNeos>,
+    request_id: RequestId,
+    Query(reason): Query<AuditReason>,
     Json(neo): Json<CreateNeo>,
 ) -> Result<Json<Neo>, AppError> {
+    neo.validate().map_err(AppError::InvalidNeo)?;
+
     let neo = am_database
         .add_neo(
             neo.api_id,
@@ -398,14 +789,105 @@ pub async fn create_neo(
             neo.velocity,
             neo.miss_distance,
             neo.orbiting_body,
+            Audit {
+                actor: claims.email,
+                reason: reason.into_reason(),
+                request_id: request_id.0,
+            },
         )
         .await?;
 
     Ok(Json(neo))
 }
 
+pub async fn get_neo_record(
+    State(am_database): State<Store>,
+    _reader: RequirePermission<ReadNeos>,
+    Path(id): Path<i32>, // localhost:3000/api/v1/neos/5
+) -> Result<Json<Neo>, AppError> {
+    let neo = am_database.get_neo_record(NeoId(id)).await?;
+    Ok(Json(neo))
+}
+
+pub async fn update_neo(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<WriteNeos>,
+    request_id: RequestId,
+    Path(id): Path<i32>, // localhost:3000/neo/record/5
+    Query(reason): Query<AuditReason>,
+    Json(update): Json<UpdateNeo>,
+) -> Result<Json<Neo>, AppError> {
+    let audit = Audit {
+        actor: claims.email,
+        reason: reason.into_reason(),
+        request_id: request_id.0,
+    };
+    let neo = am_database.update_neo(NeoId(id), update, audit).await?;
+    Ok(Json(neo))
+}
+
+pub async fn delete_neo(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<WriteNeos>,
+    request_id: RequestId,
+    Path(id): Path<i32>,
+    Query(reason): Query<AuditReason>,
+) -> Result<StatusCode, AppError> {
+    let audit = Audit {
+        actor: claims.email,
+        reason: reason.into_reason(),
+        request_id: request_id.0,
+    };
+    am_database.delete_neo(NeoId(id), audit).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn import_neos(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<WriteNeos>,
+    request_id: RequestId,
+    Query(options): Query<ImportOptions>,
+    Query(reason): Query<AuditReason>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<ImportReport>, AppError> {
+    let rows = import::parse_rows(&headers, &body)?;
+    let received = rows.len();
+
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    for (row, result) in rows {
+        match result {
+            Ok(neo) => valid.push((row, neo)),
+            Err(error) => errors.push(RowError { row, error }),
+        }
+    }
+
+    let valid_rows = valid.len();
+    let audit = Audit {
+        actor: claims.email,
+        reason: reason.into_reason(),
+        request_id: request_id.0,
+    };
+    let changes = am_database
+        .import_neos(valid, options.dry_run, audit)
+        .await?;
+    let (inserted, updated) = import::tally(&changes);
+
+    Ok(Json(ImportReport {
+        dry_run: options.dry_run,
+        received,
+        valid: valid_rows,
+        inserted,
+        updated,
+        changes,
+        errors,
+    }))
+}
+
 pub async fn get_neo_by_id(
     State(mut am_database): State<Store>,
+    _reader: RequirePermission<ReadNeos>,
     Path(query): Path<i32>, // localhost:3000/neo/5
 ) -> Result<Json<Vec<Neo>>, AppError> {
     let neo = am_database.get_neo_by_id(NeoId(query)).await?;
@@ -414,10 +896,1283 @@ pub async fn get_neo_by_id(
 
 pub async fn get_neo_by_date(
     State(mut am_database): State<Store>,
-    Json(dates): Json<CreateDateRange>,
-) -> Result<Json<Vec<Neo>>, AppError> {
-    let neos = am_database
-        .get_neo_by_date(dates.begin_date, dates.end_date)
+    _reader: RequirePermission<ReadNeos>,
+    headers: HeaderMap,
+    Query(export): Query<ExportQuery>,
+    dates: DateRange,
+) -> Result<Response, AppError> {
+    match ExportFormat::negotiate(export.format, &headers) {
+        ExportFormat::Json => {
+            let neos = am_database.get_neo_by_date(dates).await?;
+            Ok(Json(neos).into_response())
+        }
+        format => {
+            let file_name = format!("neos_{}_{}", dates.begin, dates.end);
+            let neos = am_database.stream_neo_by_date(dates).await?;
+            Ok(export::stream_neos(format, neos, &file_name))
+        }
+    }
+}
+
+pub async fn get_watchlist(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<Json<Vec<WatchlistEntry>>, AppError> {
+    let user_id = claims.id;
+    let watchlist = am_database.get_watchlist(user_id).await?;
+    Ok(Json(watchlist))
+}
+
+pub async fn add_to_watchlist(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Json(watch): Json<AddWatch>,
+) -> Result<StatusCode, AppError> {
+    let user_id = claims.id;
+    am_database.add_to_watchlist(user_id, watch.api_id).await?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn remove_from_watchlist(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(api_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = claims.id;
+    am_database.remove_from_watchlist(user_id, api_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Form version of `add_to_watchlist` for the dashboard, sends the user back home
+pub async fn watch_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Form(watch): Form<AddWatch>,
+) -> Result<Redirect, AppError> {
+    let user_id = claims.id;
+    am_database.add_to_watchlist(user_id, watch.api_id).await?;
+    Ok(Redirect::to("/"))
+}
+
+pub async fn unwatch_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Form(watch): Form<AddWatch>,
+) -> Result<Redirect, AppError> {
+    let user_id = claims.id;
+    am_database
+        .remove_from_watchlist(user_id, watch.api_id)
         .await?;
-    Ok(Json(neos))
+    Ok(Redirect::to("/"))
+}
+
+pub async fn get_alert_rules(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<Json<Vec<AlertRule>>, AppError> {
+    let user_id = claims.id;
+    let rules = am_database.get_alert_rules(user_id).await?;
+    Ok(Json(rules))
+}
+
+pub async fn create_alert_rule(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Json(rule): Json<CreateAlertRule>,
+) -> Result<(StatusCode, Json<AlertRule>), AppError> {
+    let user_id = claims.id;
+    let rule = am_database.add_alert_rule(user_id, rule).await?;
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+pub async fn delete_alert_rule(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(rule_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = claims.id;
+    am_database.delete_alert_rule(user_id, rule_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_inbox(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<Json<Vec<InboxMessage>>, AppError> {
+    let user_id = claims.id;
+    let messages = am_database.get_inbox(user_id).await?;
+    Ok(Json(messages))
+}
+
+pub async fn mark_inbox_read(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(message_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = claims.id;
+    am_database.mark_inbox_read(user_id, message_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Server-Sent Events of approaches as ingestion stores them.
+/// `?hazardous=true` and `?watchlist=true` narrow down what gets pushed.
+pub async fn events(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Query(filter): Query<EventFilter>,
+) -> Result<Sse<impl Stream<Item = Result<Event, serde_json::Error>>>, AppError> {
+    let watched: HashSet<i32> = if filter.watchlist {
+        let user_id = claims.id;
+        am_database
+            .get_watched_ids(user_id)
+            .await?
+            .into_iter()
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let mut receiver = am_database.events.subscribe();
+
+    let stream = async_stream::stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if filter.accepts(&event, &watched) {
+                        yield Event::default()
+                            .event(event.kind.as_str())
+                            .json_data(&event.neo);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("SSE subscriber fell behind, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// iCalendar feed of upcoming approaches. `?hazardous=true` narrows it down and
+/// `?watchlist=true&token=...` limits it to the token owner's watchlist.
+/// Without `watchlist` it is public on purpose, calendar apps subscribe without logging in.
+/// Only the watchlist needs [`ReadNeos`], through the token or the login cookie
+pub async fn calendar_feed(
+    State(am_database): State<Store>,
+    reader: Result<RequirePermission<ReadNeos>, AppError>,
+    Query(query): Query<CalendarQuery>,
+) -> Result<Response, AppError> {
+    let watchlist_user = if query.watchlist {
+        match (&query.token, reader) {
+            (Some(token), _) => {
+                let user_id = am_database.get_user_id_by_calendar_token(token).await?;
+                // the token stands in for the login cookie, so it stops working during a ban too
+                if let Some(ban) = am_database.get_active_ban(user_id).await? {
+                    return Err(AppError::Banned(Box::new(ban)));
+                }
+                Some(user_id)
+            }
+            (None, Ok(RequirePermission(claims, _))) => Some(claims.id),
+            (None, Err(rejection)) => return Err(rejection),
+        }
+    } else {
+        None
+    };
+
+    let neos = am_database
+        .get_upcoming_neos(query.hazardous, watchlist_user)
+        .await?;
+    let body = calendar::render(&neos, chrono::Utc::now());
+
+    Ok((
+        [(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/calendar; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response())
+}
+
+pub async fn get_calendar_token(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<Json<CalendarToken>, AppError> {
+    let user_id = claims.id;
+    let token = am_database.get_calendar_token(user_id).await?;
+    Ok(Json(CalendarToken {
+        url: format!("/calendar.ics?watchlist=true&token={}", token),
+        token,
+    }))
+}
+
+pub async fn rotate_calendar_token(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<Json<CalendarToken>, AppError> {
+    let user_id = claims.id;
+    let token = am_database.rotate_calendar_token(user_id).await?;
+    Ok(Json(CalendarToken {
+        url: format!("/calendar.ics?watchlist=true&token={}", token),
+        token,
+    }))
+}
+
+/// Atom feed of the latest hazardous approaches we ingested. Takes the same filters as `GET /neos`,
+/// though `hazardous` is always forced on.
+/// Public on purpose, unlike `GET /neos`: feed readers can't log in, and it only lists approaches
+/// NeoWs publishes anyway
+pub async fn hazardous_feed(
+    State(am_database): State<Store>,
+    OriginalUri(uri): OriginalUri,
+    Query(filter): Query<NeoFilter>,
+) -> Result<Response, AppError> {
+    let filter = NeoFilter {
+        hazardous: Some(true),
+        ..filter
+    };
+    let entries = am_database
+        .get_recently_ingested(&filter, feed::FEED_LENGTH)
+        .await?;
+
+    let body = feed::render(&entries, &PUBLIC_URL, &uri.to_string(), chrono::Utc::now());
+
+    Ok((
+        [(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/atom+xml; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response())
+}
+
+/// Runs a saved query with its date expressions resolved against today
+async fn run_saved_query(
+    am_database: &mut Store,
+    query: &SavedQuery,
+) -> Result<(DateRange, Vec<Neo>), AppError> {
+    let dates = query.date_range(chrono::Utc::now().date_naive())?;
+    let filter = query.filter();
+    let neos = am_database
+        .get_neo_by_date(dates)
+        .await?
+        .into_iter()
+        .filter(|neo| filter.matches(neo))
+        .collect();
+
+    Ok((dates, neos))
+}
+
+fn render_saved_query(
+    mut context: Context,
+    query: &SavedQuery,
+    dates: DateRange,
+    results: Vec<Neo>,
+) -> Html<String> {
+    context.insert("saved_query", &query.name);
+    context.insert("results", &results);
+    context.insert("begin_date", &dates.begin.to_string());
+    context.insert("end_date", &dates.end.to_string());
+
+    render_page("neo_date.html", &context)
+}
+
+pub async fn get_saved_queries(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+) -> Result<Json<Vec<SavedQuery>>, AppError> {
+    let user_id = claims.id;
+    let queries = am_database.get_saved_queries(user_id).await?;
+    Ok(Json(queries))
+}
+
+pub async fn create_saved_query(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Json(query): Json<CreateSavedQuery>,
+) -> Result<(StatusCode, Json<SavedQuery>), AppError> {
+    let query = query.validate(chrono::Utc::now().date_naive())?;
+    let user_id = claims.id;
+    let query = am_database.add_saved_query(user_id, query).await?;
+    Ok((StatusCode::CREATED, Json(query)))
+}
+
+pub async fn delete_saved_query(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(query_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = claims.id;
+    am_database.delete_saved_query(user_id, query_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_saved_query_results(
+    State(mut am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(query_id): Path<i32>,
+) -> Result<Json<Vec<Neo>>, AppError> {
+    let user_id = claims.id;
+    let query = am_database.get_saved_query(user_id, query_id).await?;
+    let (_, neos) = run_saved_query(&mut am_database, &query).await?;
+    Ok(Json(neos))
+}
+
+pub async fn share_saved_query(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(query_id): Path<i32>,
+) -> Result<Json<ShareLink>, AppError> {
+    let user_id = claims.id;
+    let token = am_database.share_saved_query(user_id, query_id).await?;
+    Ok(Json(ShareLink::new(token)))
+}
+
+pub async fn revoke_saved_query_share(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(query_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user_id = claims.id;
+    am_database
+        .revoke_saved_query_share(user_id, query_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Re-runs one of the user's own saved queries as a results page
+pub async fn saved_query_page(
+    State(mut am_database): State<Store>,
+    reader: Result<RequirePermission<ReadNeos>, AppError>,
+    Path(query_id): Path<i32>,
+) -> Result<Html<String>, AppError> {
+    let claims = match reader {
+        Ok(RequirePermission(claims, _)) => claims,
+        Err(rejection) => return turned_away_page(&am_database, rejection),
+    };
+
+    let query = am_database.get_saved_query(claims.id, query_id).await?;
+    let (dates, results) = run_saved_query(&mut am_database, &query).await?;
+
+    let mut context = Context::new();
+    context.insert("claims", &claims);
+    context.insert("is_logged_in", &true);
+    context.insert("is_banned", &false);
+    Ok(render_saved_query(context, &query, dates, results))
+}
+
+/// Permalink for a shared query, anyone holding the token can see the results without logging in
+pub async fn shared_query_page(
+    State(mut am_database): State<Store>,
+    Path(token): Path<String>,
+) -> Result<Html<String>, AppError> {
+    let query = am_database.get_shared_query(&token).await?;
+    let (dates, results) = run_saved_query(&mut am_database, &query).await?;
+
+    let mut context = Context::new();
+    context.insert("is_logged_in", &false);
+    context.insert("shared", &true);
+    Ok(render_saved_query(context, &query, dates, results))
+}
+
+/// Form versions of the saved query endpoints for the dashboard, they all send the user back home
+pub async fn save_query_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Form(query): Form<CreateSavedQuery>,
+) -> Result<Redirect, AppError> {
+    let query = query.validate(chrono::Utc::now().date_naive())?;
+    let user_id = claims.id;
+    am_database.add_saved_query(user_id, query).await?;
+    Ok(Redirect::to("/"))
+}
+
+pub async fn delete_query_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Form(query): Form<SavedQueryId>,
+) -> Result<Redirect, AppError> {
+    let user_id = claims.id;
+    am_database.delete_saved_query(user_id, query.id).await?;
+    Ok(Redirect::to("/"))
+}
+
+pub async fn share_query_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Form(query): Form<SavedQueryId>,
+) -> Result<Redirect, AppError> {
+    let user_id = claims.id;
+    am_database.share_saved_query(user_id, query.id).await?;
+    Ok(Redirect::to("/"))
+}
+
+pub async fn unshare_query_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Form(query): Form<SavedQueryId>,
+) -> Result<Redirect, AppError> {
+    let user_id = claims.id;
+    am_database
+        .revoke_saved_query_share(user_id, query.id)
+        .await?;
+    Ok(Redirect::to("/"))
+}
+
+/// Authors can delete their own comments, moderators anyone's
+async fn remove_comment(
+    am_database: &Store,
+    claims: &Claims,
+    moderator: Option<RequirePermission<ModerateComments>>,
+    comment_id: i32,
+) -> Result<(), AppError> {
+    if moderator.is_some() {
+        return am_database.moderate_comment(comment_id).await;
+    }
+    let user_id = claims.id;
+    am_database.delete_comment(user_id, comment_id).await
+}
+
+pub async fn get_comments(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ReadNeos>,
+    Path(api_id): Path<i32>,
+) -> Result<Json<Vec<Comment>>, AppError> {
+    let comments = am_database.get_comments(api_id).await?;
+    Ok(Json(comment::thread(comments, claims.id)))
+}
+
+pub async fn create_comment(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<CommentPermission>,
+    Path(api_id): Path<i32>,
+    Json(new_comment): Json<CreateComment>,
+) -> Result<(StatusCode, Json<Comment>), AppError> {
+    let body = comment::validate_body(&new_comment.body).map_err(AppError::InvalidComment)?;
+    let user_id = claims.id;
+    let row = am_database
+        .add_comment(user_id, api_id, new_comment.parent_id, body)
+        .await?;
+    Ok((StatusCode::CREATED, Json(Comment::new(row, user_id))))
+}
+
+pub async fn update_comment(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<CommentPermission>,
+    Path(comment_id): Path<i32>,
+    Json(update): Json<UpdateComment>,
+) -> Result<Json<Comment>, AppError> {
+    let body = comment::validate_body(&update.body).map_err(AppError::InvalidComment)?;
+    let user_id = claims.id;
+    let row = am_database
+        .update_comment(user_id, comment_id, body)
+        .await?;
+    Ok(Json(Comment::new(row, user_id)))
+}
+
+pub async fn delete_comment(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<CommentPermission>,
+    moderator: Option<RequirePermission<ModerateComments>>,
+    Path(comment_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    remove_comment(&am_database, &claims, moderator, comment_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Form versions of the comment endpoints for `neo.html`, they send the user back to the asteroid
+pub async fn comment_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<CommentPermission>,
+    Form(form): Form<CommentForm>,
+) -> Result<Redirect, AppError> {
+    let body = comment::validate_body(&form.body).map_err(AppError::InvalidComment)?;
+    let user_id = claims.id;
+    am_database
+        .add_comment(user_id, form.api_id, form.parent_id, body)
+        .await?;
+    Ok(Redirect::to(&format!("/neo/id?neo_id={}", form.api_id)))
+}
+
+pub async fn edit_comment_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<CommentPermission>,
+    Form(form): Form<CommentForm>,
+) -> Result<Redirect, AppError> {
+    let comment_id = form.id.ok_or(AppError::NotFound)?;
+    let body = comment::validate_body(&form.body).map_err(AppError::InvalidComment)?;
+    let user_id = claims.id;
+    am_database
+        .update_comment(user_id, comment_id, body)
+        .await?;
+    Ok(Redirect::to(&format!("/neo/id?neo_id={}", form.api_id)))
+}
+
+pub async fn delete_comment_form(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<CommentPermission>,
+    moderator: Option<RequirePermission<ModerateComments>>,
+    Form(form): Form<CommentForm>,
+) -> Result<Redirect, AppError> {
+    let comment_id = form.id.ok_or(AppError::NotFound)?;
+    remove_comment(&am_database, &claims, moderator, comment_id).await?;
+    Ok(Redirect::to(&format!("/neo/id?neo_id={}", form.api_id)))
+}
+
+/// Admin page listing the audit log, newest first
+pub async fn audit_page(
+    State(am_database): State<Store>,
+    RequirePermission(claims, _): RequirePermission<ViewAuditLog>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Html<String>, AppError> {
+    let entries = am_database
+        .get_audit_log(&filter, Some(AUDIT_PAGE_LENGTH))
+        .await?;
+    let actions: Vec<&str> = AuditAction::ALL.iter().map(AuditAction::as_str).collect();
+
+    let mut context = Context::new();
+    context.insert("claims", &claims);
+    context.insert("is_logged_in", &true);
+    context.insert("entries", &entries);
+    context.insert("filter", &filter);
+    context.insert("actions", &actions);
+
+    Ok(render_page("audit.html", &context))
+}
+
+/// Every audit entry matching the filters as a CSV download
+pub async fn audit_export(
+    State(am_database): State<Store>,
+    _auditor: RequirePermission<ViewAuditLog>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Response, AppError> {
+    let entries = am_database.get_audit_log(&filter, None).await?;
+    let body = audit::to_csv(&entries)?;
+
+    Ok((
+        [
+            (
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("text/csv; charset=utf-8"),
+            ),
+            (
+                http::header::CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"audit_log.csv\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Listing works with a key too, so scripts can keep an eye on expiry. Creating and revoking keys
+/// takes the login cookie
+pub async fn get_api_keys(
+    State(am_database): State<Store>,
+    ApiClaims(claims, _): ApiClaims,
+) -> Result<Json<Vec<ApiKey>>, AppError> {
+    let keys = am_database.get_api_keys(claims.id).await?;
+    Ok(Json(keys))
+}
+
+pub async fn create_api_key(
+    State(am_database): State<Store>,
+    claims: Claims,
+    Json(key): Json<CreateApiKey>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), AppError> {
+    let created = add_api_key(&am_database, &claims, key).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub async fn delete_api_key(
+    State(am_database): State<Store>,
+    claims: Claims,
+    Path(key_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    am_database.delete_api_key(claims.id, key_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Keys are managed with the login cookie only, a leaked key can't be used to mint more
+async fn add_api_key(
+    am_database: &Store,
+    claims: &Claims,
+    key: CreateApiKey,
+) -> Result<CreatedApiKey, AppError> {
+    let key = key.validate(chrono::Utc::now().date_naive())?;
+    let generated = api_key::generate_key();
+    let api_key = am_database.add_api_key(claims.id, key, &generated).await?;
+
+    Ok(CreatedApiKey {
+        key: generated.key,
+        api_key,
+    })
+}
+
+/// Lists the user's API keys. Right after creating one, `created` holds the only copy of the key
+async fn render_api_keys(
+    am_database: &Store,
+    claims: &Claims,
+    created: Option<&CreatedApiKey>,
+) -> Result<Html<String>, AppError> {
+    let keys = am_database.get_api_keys(claims.id).await?;
+    let scopes: Vec<&str> = ApiScope::ALL.iter().map(ApiScope::as_str).collect();
+
+    let mut context = Context::new();
+    context.insert("claims", claims);
+    context.insert("is_logged_in", &true);
+    context.insert("keys", &keys);
+    context.insert("scopes", &scopes);
+    context.insert("created", &created);
+
+    Ok(render_page("api_keys.html", &context))
+}
+
+pub async fn api_keys_page(
+    State(am_database): State<Store>,
+    claims: Claims,
+) -> Result<Html<String>, AppError> {
+    render_api_keys(&am_database, &claims, None).await
+}
+
+pub async fn create_api_key_form(
+    State(am_database): State<Store>,
+    claims: Claims,
+    Form(form): Form<ApiKeyForm>,
+) -> Result<Html<String>, AppError> {
+    let created = add_api_key(&am_database, &claims, form.into()).await?;
+    render_api_keys(&am_database, &claims, Some(&created)).await
+}
+
+pub async fn revoke_api_key_form(
+    State(am_database): State<Store>,
+    claims: Claims,
+    Form(key): Form<ApiKeyId>,
+) -> Result<Redirect, AppError> {
+    am_database.delete_api_key(claims.id, key.id).await?;
+    Ok(Redirect::to("/keys"))
+}
+
+fn render_page(template_name: &str, context: &Context) -> Html<String> {
+    let rendered = TEMPLATES
+        .render(template_name, context)
+        .unwrap_or_else(|err| {
+            error!("Template rendering error: {}", err);
+            panic!()
+        });
+    Html(rendered)
+}
+
+/// Renders the email for `purpose` from `templates/emails` and sends it to the account.
+/// Delivery problems are only logged, the user can always ask for another email
+async fn send_account_email(
+    database: &Store,
+    account: &Account,
+    purpose: Purpose,
+    base_url: &str,
+) -> Result<(), AppError> {
+    let (template_name, subject, path) = match purpose {
+        Purpose::VerifyEmail => (
+            "emails/verify_email.txt",
+            "Verify your email address",
+            "/verify-email",
+        ),
+        Purpose::ResetPassword => (
+            "emails/reset_password.txt",
+            "Reset your password",
+            "/password/reset",
+        ),
+    };
+    let token = email_token::sign(purpose, account.id, &account.email, &account.password)?;
+
+    let mut context = Context::new();
+    context.insert("email", &account.email);
+    context.insert(
+        "link",
+        &format!("{}{}?token={}", base_url.trim_end_matches('/'), path, token),
+    );
+    context.insert("valid_hours", &(purpose.ttl().as_secs() / 3600));
+    let body = TEMPLATES
+        .render(template_name, &context)
+        .map_err(|err| AppError::Any(err.into()))?;
+
+    let email = Email {
+        to: account.email.clone(),
+        subject: subject.to_string(),
+        body,
+    };
+    if let Err(err) = database.mailer.send(&email).await {
+        error!(
+            "Could not send {:?} email to {}: {:?}",
+            purpose, account.email, err
+        );
+    }
+    Ok(())
+}
+
+async fn send_verification_email(
+    database: &Store,
+    email: &str,
+    base_url: &str,
+) -> Result<(), AppError> {
+    match database.get_account(email).await? {
+        Some(account) if !account.email_verified => {
+            send_account_email(database, &account, Purpose::VerifyEmail, base_url).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Target of the link in verification emails. Without a token it just offers to send another email
+pub async fn verify_email_page(
+    State(database): State<Store>,
+    Query(query): Query<TokenQuery>,
+) -> Result<(StatusCode, Html<String>), AppError> {
+    let mut context = Context::new();
+    if query.token.is_empty() {
+        return Ok((StatusCode::OK, render_page("verify_email.html", &context)));
+    }
+
+    let verified = match email_token::verify(Purpose::VerifyEmail, &query.token) {
+        Ok(claims) => match database.get_account_by_id(claims.sub).await {
+            Ok(account) if account.email == claims.email => {
+                database.verify_email(account.id).await?;
+                true
+            }
+            _ => false,
+        },
+        Err(_) => false,
+    };
+
+    let status = if verified {
+        context.insert("verified", &true);
+        StatusCode::OK
+    } else {
+        context.insert("invalid", &true);
+        StatusCode::BAD_REQUEST
+    };
+    Ok((status, render_page("verify_email.html", &context)))
+}
+
+/// Sends another verification email. The answer is the same whether or not the account exists
+pub async fn resend_verification_form(
+    State(database): State<Store>,
+    Form(form): Form<EmailForm>,
+) -> Result<Html<String>, AppError> {
+    send_verification_email(&database, form.email.trim(), &PUBLIC_URL).await?;
+
+    let mut context = Context::new();
+    context.insert("sent", &true);
+    Ok(render_page("verify_email.html", &context))
+}
+
+pub async fn forgot_password_page() -> Html<String> {
+    render_page("password_forgot.html", &Context::new())
+}
+
+/// Emails a reset link. The answer is the same whether or not the account exists
+pub async fn forgot_password_form(
+    State(database): State<Store>,
+    Form(form): Form<EmailForm>,
+) -> Result<Html<String>, AppError> {
+    if let Some(account) = database.get_account(form.email.trim()).await? {
+        send_account_email(&database, &account, Purpose::ResetPassword, &PUBLIC_URL).await?;
+    }
+
+    let mut context = Context::new();
+    context.insert("sent", &true);
+    Ok(render_page("password_forgot.html", &context))
+}
+
+/// Target of the link in reset emails, asks for the new password
+pub async fn reset_password_page(Query(query): Query<TokenQuery>) -> Html<String> {
+    let mut context = Context::new();
+    if email_token::verify(Purpose::ResetPassword, &query.token).is_ok() {
+        context.insert("token", &query.token);
+    } else {
+        context.insert("invalid", &true);
+    }
+    render_page("password_reset.html", &context)
+}
+
+pub async fn reset_password_form(
+    State(database): State<Store>,
+    Form(form): Form<ResetPassword>,
+) -> Result<(StatusCode, Html<String>), AppError> {
+    let mut context = Context::new();
+
+    let claims = match email_token::verify(Purpose::ResetPassword, &form.token) {
+        Ok(claims) => claims,
+        Err(_) => {
+            context.insert("invalid", &true);
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                render_page("password_reset.html", &context),
+            ));
+        }
+    };
+    let account = database.get_account_by_id(claims.sub).await?;
+    // A link that was already used, or issued before the password last changed
+    if account.email != claims.email
+        || email_token::check_password(&claims, &account.password).is_err()
+    {
+        context.insert("invalid", &true);
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("password_reset.html", &context),
+        ));
+    }
+
+    if form.password.is_empty() || form.password != form.confirm_password {
+        context.insert("token", &form.token);
+        context.insert("mismatch", &true);
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("password_reset.html", &context),
+        ));
+    }
+
+    let hash = password::hash(&form.password)?;
+    database.reset_password(account.id, &hash).await?;
+
+    context.insert("done", &true);
+    Ok((StatusCode::OK, render_page("password_reset.html", &context)))
+}
+
+/// The user half way through logging in, from the cookie `login` sets once the password is right
+fn pending_login(headers: &HeaderMap) -> Option<i32> {
+    session::cookie_value(headers, two_factor::LOGIN_COOKIE)
+        .and_then(|token| two_factor::verify_pending_login(&token).ok())
+}
+
+/// Checks a code from the user's authenticator app, or failing that one of their recovery codes.
+/// Either works only once
+async fn check_second_factor(
+    database: &Store,
+    user: &TwoFactorUser,
+    code: &str,
+) -> Result<bool, AppError> {
+    let secret = match (&user.totp_secret, user.totp_enabled) {
+        (Some(secret), true) => secret,
+        _ => return Ok(false),
+    };
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    if let Some(step) = two_factor::verify_code(secret, code, now, user.totp_last_step)? {
+        return database.use_totp_step(user.id, step as i64).await;
+    }
+    database
+        .use_recovery_code(user.id, &two_factor::hash_recovery_code(code))
+        .await
+}
+
+/// Finishes setup when `code` matches the pending secret, returning the new recovery codes
+async fn enable_two_factor(
+    database: &Store,
+    user: &TwoFactorUser,
+    code: &str,
+) -> Result<Option<Vec<String>>, AppError> {
+    let secret = match &user.totp_secret {
+        Some(secret) if !user.totp_enabled => secret,
+        _ => return Ok(None),
+    };
+    let now = chrono::Utc::now().timestamp() as u64;
+    let step = match two_factor::verify_code(secret, code, now, None)? {
+        Some(step) => step,
+        None => return Ok(None),
+    };
+
+    let codes = two_factor::generate_recovery_codes();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| two_factor::hash_recovery_code(code))
+        .collect();
+    database.enable_totp(user.id, step as i64, &hashes).await?;
+    Ok(Some(codes))
+}
+
+/// What the setup form needs to enrol `secret` in an authenticator app
+fn insert_enrolment(
+    context: &mut Context,
+    user: &TwoFactorUser,
+    secret: &str,
+) -> Result<(), AppError> {
+    let uri = two_factor::provisioning_uri(secret, &user.email);
+    context.insert("qr_code", &two_factor::qr_svg(&uri)?);
+    context.insert("provisioning_uri", &uri);
+    context.insert("secret", secret);
+    Ok(())
+}
+
+/// The second login step: a code, or setting up two-factor authentication when the role requires it
+pub async fn login_two_factor_page(
+    State(database): State<Store>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let user_id = match pending_login(&headers) {
+        Some(user_id) => user_id,
+        None => return Ok(Redirect::to("/").into_response()),
+    };
+    let user = database.get_two_factor_user(user_id).await?;
+
+    let mut context = Context::new();
+    if !user.totp_enabled {
+        let secret = match &user.totp_secret {
+            Some(secret) => secret.clone(),
+            None => {
+                let secret = two_factor::generate_secret();
+                database.set_pending_totp_secret(user.id, &secret).await?;
+                secret
+            }
+        };
+        insert_enrolment(&mut context, &user, &secret)?;
+    }
+    Ok(render_page("two_factor_login.html", &context).into_response())
+}
+
+pub async fn login_two_factor_form(
+    State(database): State<Store>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(form): Form<CodeForm>,
+) -> Result<Response, AppError> {
+    let user_id = match pending_login(&headers) {
+        Some(user_id) => user_id,
+        None => return Ok(Redirect::to("/").into_response()),
+    };
+    let user = database.get_two_factor_user(user_id).await?;
+
+    // codes can be guessed too, so they count towards the same lockout as passwords
+    let subjects = [Subject::Account(&user.email), Subject::Ip(ip)];
+    let reserved = reserve_login_attempt(&database, &subjects).await?;
+
+    let mut context = Context::new();
+    let recovery_codes = if user.totp_enabled {
+        if !check_second_factor(&database, &user, &form.code).await? {
+            record_login_failure(&database, &subjects, &reserved).await?;
+            context.insert("error", "That code didn't work");
+            return Ok((
+                StatusCode::UNAUTHORIZED,
+                render_page("two_factor_login.html", &context),
+            )
+                .into_response());
+        }
+        None
+    } else {
+        match enable_two_factor(&database, &user, &form.code).await? {
+            Some(codes) => Some(codes),
+            None => {
+                record_login_failure(&database, &subjects, &reserved).await?;
+                if let Some(secret) = &user.totp_secret {
+                    insert_enrolment(&mut context, &user, secret)?;
+                }
+                context.insert("error", "That code didn't work");
+                return Ok((
+                    StatusCode::UNAUTHORIZED,
+                    render_page("two_factor_login.html", &context),
+                )
+                    .into_response());
+            }
+        }
+    };
+
+    release_login_attempt(&database, &subjects).await?;
+    database
+        .clear_login_throttle(&Subject::Account(&user.email).key())
+        .await?;
+    let session = session::start(&database, &user.email).await?;
+    let mut response = match recovery_codes {
+        // first login with a new authenticator, the recovery codes are only ever shown now
+        Some(codes) => {
+            context.insert("recovery_codes", &codes);
+            render_page("two_factor_login.html", &context).into_response()
+        }
+        None => Redirect::to("/").into_response(),
+    };
+    for cookie in session.cookies() {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    response
+        .headers_mut()
+        .append(SET_COOKIE, two_factor::cleared_login_cookie());
+    Ok(response)
+}
+
+fn two_factor_context(user: &TwoFactorUser) -> Result<Context, AppError> {
+    let role = user.role.parse().map_err(AppError::InvalidRole)?;
+    let mut context = Context::new();
+    context.insert("enabled", &user.totp_enabled);
+    context.insert("recovery_codes_left", &user.recovery_codes_left);
+    context.insert("required", &two_factor::required_for(&role));
+    Ok(context)
+}
+
+pub async fn two_factor_page(
+    State(database): State<Store>,
+    claims: Claims,
+) -> Result<Html<String>, AppError> {
+    let user = database.get_two_factor_user(claims.id).await?;
+    let context = two_factor_context(&user)?;
+    Ok(render_page("two_factor.html", &context))
+}
+
+/// Starts setup with a new secret, shown as a QR code until a code from it is confirmed
+pub async fn two_factor_setup_form(
+    State(database): State<Store>,
+    claims: Claims,
+) -> Result<Html<String>, AppError> {
+    let user = database.get_two_factor_user(claims.id).await?;
+    let mut context = two_factor_context(&user)?;
+    if !user.totp_enabled {
+        let secret = two_factor::generate_secret();
+        database.set_pending_totp_secret(user.id, &secret).await?;
+        insert_enrolment(&mut context, &user, &secret)?;
+    }
+    Ok(render_page("two_factor.html", &context))
+}
+
+pub async fn two_factor_enable_form(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(form): Form<CodeForm>,
+) -> Result<(StatusCode, Html<String>), AppError> {
+    let user = database.get_two_factor_user(claims.id).await?;
+
+    match enable_two_factor(&database, &user, &form.code).await? {
+        Some(codes) => {
+            let user = database.get_two_factor_user(claims.id).await?;
+            let mut context = two_factor_context(&user)?;
+            context.insert("recovery_codes", &codes);
+            Ok((StatusCode::OK, render_page("two_factor.html", &context)))
+        }
+        None => {
+            let mut context = two_factor_context(&user)?;
+            if let (Some(secret), false) = (&user.totp_secret, user.totp_enabled) {
+                insert_enrolment(&mut context, &user, secret)?;
+            }
+            context.insert("error", "That code didn't work");
+            Ok((
+                StatusCode::BAD_REQUEST,
+                render_page("two_factor.html", &context),
+            ))
+        }
+    }
+}
+
+/// Replaces the recovery codes, the old ones stop working
+pub async fn two_factor_recovery_codes_form(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(form): Form<CodeForm>,
+) -> Result<(StatusCode, Html<String>), AppError> {
+    let user = database.get_two_factor_user(claims.id).await?;
+    if !check_second_factor(&database, &user, &form.code).await? {
+        let mut context = two_factor_context(&user)?;
+        context.insert("error", "That code didn't work");
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("two_factor.html", &context),
+        ));
+    }
+
+    let codes = two_factor::generate_recovery_codes();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| two_factor::hash_recovery_code(code))
+        .collect();
+    database.replace_recovery_codes(user.id, &hashes).await?;
+
+    let user = database.get_two_factor_user(claims.id).await?;
+    let mut context = two_factor_context(&user)?;
+    context.insert("recovery_codes", &codes);
+    Ok((StatusCode::OK, render_page("two_factor.html", &context)))
+}
+
+pub async fn two_factor_disable_form(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(form): Form<CodeForm>,
+) -> Result<(StatusCode, Html<String>), AppError> {
+    let user = database.get_two_factor_user(claims.id).await?;
+    let mut context = two_factor_context(&user)?;
+
+    if two_factor::required_for(&claims.role) {
+        context.insert("error", "Your role requires two-factor authentication");
+        return Ok((
+            StatusCode::FORBIDDEN,
+            render_page("two_factor.html", &context),
+        ));
+    }
+    if !check_second_factor(&database, &user, &form.code).await? {
+        context.insert("error", "That code didn't work");
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("two_factor.html", &context),
+        ));
+    }
+
+    database.disable_totp(user.id).await?;
+    let user = database.get_two_factor_user(claims.id).await?;
+    let context = two_factor_context(&user)?;
+    Ok((StatusCode::OK, render_page("two_factor.html", &context)))
+}
+
+async fn settings_context(
+    database: &Store,
+    claims: &Claims,
+    headers: &HeaderMap,
+) -> Result<Context, AppError> {
+    let mut context = Context::new();
+    context.insert("profile", &database.get_profile(claims.id).await?);
+    context.insert("last_admin", &database.is_last_admin(claims.id).await?);
+    context.insert("sso_enabled", &database.oidc.is_some());
+    context.insert(
+        "reauthenticated",
+        &oidc::reauthenticated(headers, claims.id),
+    );
+    Ok(context)
+}
+
+pub async fn settings_page(
+    State(database): State<Store>,
+    claims: Claims,
+    headers: HeaderMap,
+) -> Result<Html<String>, AppError> {
+    let context = settings_context(&database, &claims, &headers).await?;
+    Ok(render_page("settings.html", &context))
+}
+
+/// Checks a password typed in again on the settings page, counting wrong ones like failed logins.
+/// Signing in again through single sign-on in the last few minutes counts as well
+async fn confirm_identity(
+    database: &Store,
+    claims: &Claims,
+    headers: &HeaderMap,
+    ip: std::net::IpAddr,
+    password: &str,
+) -> Result<bool, AppError> {
+    if password.is_empty() && oidc::reauthenticated(headers, claims.id) {
+        return Ok(true);
+    }
+    let subjects = [Subject::Account(&claims.email), Subject::Ip(ip)];
+    let reserved = reserve_login_attempt(database, &subjects).await?;
+
+    let account = database.get_account_by_id(claims.id).await?;
+    if password::verify(&account.password, password)? {
+        release_login_attempt(database, &subjects).await?;
+        return Ok(true);
+    }
+    record_login_failure(database, &subjects, &reserved).await?;
+    Ok(false)
+}
+
+/// Sets a new password after the current one is confirmed. Every other session is logged out,
+/// this one carries on with new cookies
+pub async fn change_password_form(
+    State(database): State<Store>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(form): Form<ChangePassword>,
+) -> Result<Response, AppError> {
+    let mut context = settings_context(&database, &claims, &headers).await?;
+
+    if !confirm_identity(&database, &claims, &headers, ip, &form.current_password).await? {
+        context.insert("password_error", "Your current password was wrong");
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("settings.html", &context),
+        )
+            .into_response());
+    }
+    if form.password.is_empty() || form.password != form.confirm_password {
+        context.insert(
+            "password_error",
+            "The new passwords were empty or didn't match",
+        );
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("settings.html", &context),
+        )
+            .into_response());
+    }
+
+    let hash = password::hash(&form.password)?;
+    database.change_password(claims.id, &hash).await?;
+    let session = session::start(&database, &claims.email).await?;
+    info!("User {} changed their password", claims.id);
+
+    context.insert("password_changed", &true);
+    context.insert("reauthenticated", &false);
+    let mut response = render_page("settings.html", &context).into_response();
+    for cookie in session.cookies() {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    response
+        .headers_mut()
+        .append(SET_COOKIE, oidc::cleared_reauth_cookie());
+    Ok(response)
+}
+
+/// Everything stored about the user as a JSON download
+pub async fn export_account_data(
+    State(database): State<Store>,
+    claims: Claims,
+) -> Result<Response, AppError> {
+    let export = database.export_account(claims.id).await?;
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        settings::export_filename(export.exported_at.date_naive())
+    );
+
+    Ok((
+        [(
+            http::header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&disposition).map_err(|_| AppError::InternalServerError)?,
+        )],
+        Json(export),
+    )
+        .into_response())
+}
+
+/// Deletes the account once the password is confirmed, and logs out.
+/// The only admin can't leave, someone else has to be able to manage the users
+pub async fn delete_account_form(
+    State(database): State<Store>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(form): Form<DeleteAccount>,
+) -> Result<Response, AppError> {
+    let mut context = settings_context(&database, &claims, &headers).await?;
+
+    if !confirm_identity(&database, &claims, &headers, ip, &form.password).await? {
+        context.insert("delete_error", "Your password was wrong");
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_page("settings.html", &context),
+        )
+            .into_response());
+    }
+    if database.is_last_admin(claims.id).await? {
+        context.insert(
+            "delete_error",
+            "You are the only admin, make someone else an admin first",
+        );
+        return Ok((StatusCode::CONFLICT, render_page("settings.html", &context)).into_response());
+    }
+
+    database.delete_account(claims.id).await?;
+    database
+        .clear_login_throttle(&Subject::Account(&claims.email).key())
+        .await?;
+    info!("User {} deleted their account", claims.id);
+
+    let cookies = session::cleared_cookies()
+        .into_iter()
+        .chain([oidc::cleared_reauth_cookie()]);
+    Ok(redirect_with_cookies("/", cookies).into_response())
 }
@@ -0,0 +1,216 @@
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::AppError;
+use crate::models::event::NeoEventKind;
+use crate::models::neo::CreateNeo;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportOptions {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: usize,
+    pub error: String,
+}
+
+/// What storing a valid row did, or would do in a dry run
+#[derive(Debug, Serialize)]
+pub struct RowChange {
+    pub row: usize,
+    pub kind: NeoEventKind,
+}
+
+/// How many of `changes` created an approach and how many updated one
+pub fn tally(changes: &[RowChange]) -> (usize, usize) {
+    let created = changes
+        .iter()
+        .filter(|change| change.kind == NeoEventKind::Created)
+        .count();
+    (created, changes.len() - created)
+}
+
+/// With `dry_run` set, `inserted`, `updated` and `changes` say what the import would have done
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub received: usize,
+    pub valid: usize,
+    pub inserted: usize,
+    pub updated: usize,
+    pub changes: Vec<RowChange>,
+    pub errors: Vec<RowError>,
+}
+
+/// Counts `\n`, `\r\n` and bare `\r` line endings, the csv reader accepts all three
+fn line_breaks(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .enumerate()
+        .filter(|&(index, &byte)| {
+            byte == b'\n' || (byte == b'\r' && bytes.get(index + 1) != Some(&b'\n'))
+        })
+        .count()
+}
+
+/// A row number paired with either the parsed row or why it was rejected
+pub type ParsedRow = (usize, Result<CreateNeo, String>);
+
+/// Splits an upload into rows, keeping the (1-based) row number next to each parse result
+pub fn parse_rows(headers: &HeaderMap, body: &[u8]) -> Result<Vec<ParsedRow>, AppError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let rows: Vec<(usize, Result<CreateNeo, String>)> = if content_type
+        .starts_with("application/json")
+    {
+        let values: Vec<Value> = serde_json::from_slice(body)
+            .map_err(|err| AppError::InvalidImport(format!("Expected a JSON array: {}", err)))?;
+        values
+            .into_iter()
+            .map(|value| serde_json::from_value(value).map_err(|err| err.to_string()))
+            .enumerate()
+            .collect()
+    } else if content_type.starts_with("application/x-ndjson") {
+        let text =
+            std::str::from_utf8(body).map_err(|err| AppError::InvalidImport(err.to_string()))?;
+        // numbered before blank lines are skipped, so the numbers match the lines of the upload
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                let row = serde_json::from_str(line).map_err(|err| err.to_string());
+                (index, row)
+            })
+            .collect()
+    } else if content_type.starts_with("text/csv") {
+        let mut reader = csv::Reader::from_reader(body);
+        let header = reader
+            .headers()
+            .map_err(|err| AppError::InvalidImport(err.to_string()))?
+            .clone();
+        reader
+            .records()
+            .enumerate()
+            .map(|(index, record)| {
+                // the csv reader doesn't count the blank lines it skips, and a record's offset is
+                // where they start. Counting the lines before its first byte, minus the header,
+                // gives the row's index
+                let index = match record.as_ref().ok().and_then(|record| record.position()) {
+                    Some(position) => {
+                        let offset = (position.byte() as usize).min(body.len());
+                        let start = offset
+                            + body[offset..]
+                                .iter()
+                                .take_while(|&&byte| byte == b'\n' || byte == b'\r')
+                                .count();
+                        line_breaks(&body[..start]).saturating_sub(1)
+                    }
+                    None => index,
+                };
+                let row = record
+                    .and_then(|record| record.deserialize(Some(&header)))
+                    .map_err(|err| err.to_string());
+                (index, row)
+            })
+            .collect()
+    } else {
+        return Err(AppError::UnsupportedMediaType);
+    };
+
+    Ok(rows
+        .into_iter()
+        .map(|(index, row)| (index + 1, row.and_then(|neo| neo.validate().map(|_| neo))))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(content_type: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers
+    }
+
+    #[test]
+    fn csv_rows_are_validated_individually() {
+        let body = "api_id,designation,diameter_min,diameter_max,hazardous_asteroid,approach_date,velocity,miss_distance,orbiting_body\n\
+                    3542519,2010 PK9,0.06,0.15,true,1900-06-01,69201.9,4140648.4,Merc\n\
+                    3542520,2010 PK10,0.2,0.1,false,1900-06-01,69201.9,4140648.4,Earth\n\
+                    3542521,2010 PK11,0.06,0.15,false,not-a-date,69201.9,4140648.4,Earth\n";
+
+        let rows = parse_rows(&headers("text/csv"), body.as_bytes()).unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].1.is_ok());
+        assert!(rows[1].1.is_err());
+        assert!(rows[2].1.is_err());
+        assert_eq!(rows[2].0, 3);
+    }
+
+    #[test]
+    fn ndjson_reports_bad_lines() {
+        let body = r#"{"api_id":1,"designation":"A","diameter_min":0.1,"diameter_max":0.2,"hazardous_asteroid":false,"approach_date":"2020-01-01","velocity":1.0,"miss_distance":1.0,"orbiting_body":"Earth"}
+{"api_id":"oops"}"#;
+
+        let rows = parse_rows(&headers("application/x-ndjson"), body.as_bytes()).unwrap();
+
+        assert!(rows[0].1.is_ok());
+        assert!(rows[1].1.is_err());
+    }
+
+    #[test]
+    fn row_numbers_count_blank_lines() {
+        let line = r#"{"api_id":1,"designation":"A","diameter_min":0.1,"diameter_max":0.2,"hazardous_asteroid":false,"approach_date":"2020-01-01","velocity":1.0,"miss_distance":1.0,"orbiting_body":"Earth"}"#;
+        let body = format!("{}\n\n   \n{{\"api_id\":\"oops\"}}\n", line);
+
+        let rows = parse_rows(&headers("application/x-ndjson"), body.as_bytes()).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].0, 1);
+        assert_eq!(rows[1].0, 4);
+        assert!(rows[1].1.is_err());
+
+        let body = "api_id,designation,diameter_min,diameter_max,hazardous_asteroid,approach_date,velocity,miss_distance,orbiting_body\n\n\
+                    3542519,2010 PK9,0.06,0.15,true,1900-06-01,69201.9,4140648.4,Earth\n";
+        let rows = parse_rows(&headers("text/csv"), body.as_bytes()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, 2);
+        assert!(rows[0].1.is_ok());
+    }
+
+    #[test]
+    fn csv_rows_are_numbered_whatever_the_line_endings() {
+        let header = "api_id,designation,diameter_min,diameter_max,hazardous_asteroid,approach_date,velocity,miss_distance,orbiting_body";
+        let row = "3542519,2010 PK9,0.06,0.15,true,1900-06-01,69201.9,4140648.4,Earth";
+        let bad_row = "3542520,2010 PK10,0.06,0.15,true,not-a-date,69201.9,4140648.4,Earth";
+        for ending in ["\n", "\r\n", "\r"] {
+            let body = [header, "", row, bad_row].join(ending);
+
+            let rows = parse_rows(&headers("text/csv"), body.as_bytes()).unwrap();
+
+            let numbers: Vec<_> = rows.iter().map(|(row, _)| *row).collect();
+            assert_eq!(numbers, vec![2, 3], "line ending {:?}", ending);
+            assert!(rows[0].1.is_ok());
+            assert!(rows[1].1.is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_content_type_is_rejected() {
+        assert!(matches!(
+            parse_rows(&headers("text/plain"), b""),
+            Err(AppError::UnsupportedMediaType)
+        ));
+    }
+}
@@ -1,7 +1,28 @@
-use http::Method;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use axum::async_trait;
+use axum::body::{Body, HttpBody};
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header::{COOKIE, SET_COOKIE};
+use http::request::Parts;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request};
 use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::{csrf, session};
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Most of a form body read looking for the CSRF token, axum's default limit for extractors
+const MAX_FORM_BYTES: usize = 2 * 1024 * 1024;
 
 pub fn get_layers() -> (
     CorsLayer,
@@ -9,11 +30,12 @@ pub fn get_layers() -> (
 ) {
     let cors_layer = CorsLayer::new()
         .allow_origin(Any)
-        .allow_headers([http::header::CONTENT_TYPE])
+        .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
         .allow_methods([
             Method::GET,
             Method::POST,
             Method::PUT,
+            Method::PATCH,
             Method::DELETE,
             Method::OPTIONS,
         ]);
@@ -22,3 +44,312 @@ pub fn get_layers() -> (
 
     (cors_layer, trace_layer)
 }
+
+/// Marks responses from the pre-/api/v1 JSON paths as deprecated
+pub async fn deprecated<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert("Deprecation", HeaderValue::from_static("true"));
+    headers.insert(
+        http::header::LINK,
+        HeaderValue::from_static("</api/v1>; rel=\"successor-version\""),
+    );
+
+    response
+}
+
+/// Id of the current request, taken from `X-Request-Id` when the client or a proxy sent one
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Tags every request with a [`RequestId`] and echoes it back in the response headers
+pub async fn request_id<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 64)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let mut response = next.run(request).await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+
+    response
+}
+
+/// Renews the session from the refresh token cookie when the access token is missing, expired or outdated,
+/// so pages keep working past the access token's short lifetime and pick up role changes
+pub async fn refresh_session<B>(
+    State(store): State<Store>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let claims = match session::cookie_value(request.headers(), session::ACCESS_COOKIE)
+        .map(|token| session::decode_access_token(&token))
+    {
+        Some(Ok(claims)) => Some(claims),
+        _ => None,
+    };
+    if let Some(claims) = claims {
+        if store.is_token_current(&claims).await.unwrap_or(false) {
+            // Checked once here so the extractors don't have to go back to the database
+            request.extensions_mut().insert(claims);
+            return next.run(request).await;
+        }
+    }
+
+    let Some(refresh_token) = session::cookie_value(request.headers(), session::REFRESH_COOKIE)
+    else {
+        return next.run(request).await;
+    };
+
+    let cookies = match session::refresh(&store, &refresh_token).await {
+        Ok(Some(session)) => {
+            // Let this request's extractors see the new access token too
+            let cookie = session::replace_session_cookies(request.headers(), &session);
+            request.headers_mut().insert(COOKIE, cookie);
+            session.cookies().to_vec()
+        }
+        // Another request refreshed the session a moment ago, the cookies it set are the ones to keep
+        Ok(None) => vec![],
+        // The refresh token is no good, stop the browser from sending it again
+        Err(_) => session::cleared_cookies().to_vec(),
+    };
+
+    let mut response = next.run(request).await;
+    // Login, logout and /refresh set their own cookies, those win
+    if !response.headers().contains_key(SET_COOKIE) {
+        for cookie in cookies {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+/// Turns away writes another site could have made with the user's cookies unless they echo the
+/// `csrf_token` cookie, in the form or the `X-CSRF-Token` header. Also hands the token to the templates
+pub async fn csrf(request: Request<Body>, next: Next<Body>) -> Response {
+    let (token, new) = csrf::request_token(request.headers());
+
+    let request = if csrf::needs_check(request.method(), request.headers()) {
+        let (parts, body) = request.into_parts();
+        let mut submitted = parts
+            .headers
+            .get(csrf::HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let is_form = parts
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+        let body = if submitted.is_none() && is_form {
+            let Some(bytes) = read_form(body).await else {
+                return AppError::CsrfTokenMismatch.into_response();
+            };
+            submitted = csrf::form_token(&bytes);
+            Body::from(bytes)
+        } else {
+            body
+        };
+
+        // a token made up for this request can't have been in any form
+        let valid =
+            !new && submitted.is_some_and(|submitted| csrf::tokens_match(&token, &submitted));
+        if !valid {
+            warn!(
+                "Rejected {} {} without a valid CSRF token",
+                parts.method,
+                parts.uri.path()
+            );
+            let mut response = AppError::CsrfTokenMismatch.into_response();
+            if new {
+                response
+                    .headers_mut()
+                    .append(SET_COOKIE, csrf::cookie(&token));
+            }
+            return response;
+        }
+        Request::from_parts(parts, body)
+    } else {
+        request
+    };
+
+    let mut response = csrf::scope(token.clone(), next.run(request)).await;
+    if new {
+        response
+            .headers_mut()
+            .append(SET_COOKIE, csrf::cookie(&token));
+    }
+    response
+}
+
+/// The whole of a form body, `None` when it's too big or broken off
+async fn read_form(mut body: Body) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.ok()?;
+        if bytes.len() + chunk.len() > MAX_FORM_BYTES {
+            return None;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Some(bytes)
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestId
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string())))
+    }
+}
+
+/// Address the request came from. With `TRUST_PROXY=true` that's the last `X-Forwarded-For` entry,
+/// the one the reverse proxy in front of the server added. Entries before it came from the client
+/// and could say anything
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+/// The address the nearest proxy saw the request come from
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|last| last.trim().parse().ok())
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let trust_proxy = std::env::var("TRUST_PROXY")
+            .map(|value| value == "true")
+            .unwrap_or(false);
+        let forwarded = Some(&parts.headers)
+            .filter(|_| trust_proxy)
+            .and_then(forwarded_for);
+
+        let ip = forwarded
+            .or_else(|| {
+                parts
+                    .extensions
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|info| info.0.ip())
+            })
+            // Requests that never went through the server, like in tests, all share one address
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        Ok(ClientIp(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{add_user, test_store};
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    fn session_cookies(response: &Response) -> Vec<String> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .filter(|cookie| {
+                cookie.starts_with(session::ACCESS_COOKIE)
+                    || cookie.starts_with(session::REFRESH_COOKIE)
+            })
+            .collect()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn a_refresh_losing_a_race_leaves_the_cookies_alone(pool: PgPool) {
+        std::env::set_var("JWT_SECRET", "test secret");
+        let store = test_store(pool.clone(), vec![]);
+        add_user(&store, "user@example.com").await;
+        let started = session::start(&store, "user@example.com").await.unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(store, refresh_session));
+        let with_old_token = || {
+            Request::get("/")
+                .header(
+                    COOKIE,
+                    format!("{}={}", session::REFRESH_COOKIE, started.refresh_token),
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let winner = app.clone().oneshot(with_old_token()).await.unwrap();
+        let renewed = session_cookies(&winner);
+        assert_eq!(renewed.len(), 2);
+        assert!(renewed.iter().all(|cookie| !cookie.contains("Max-Age=0")));
+
+        let loser = app.clone().oneshot(with_old_token()).await.unwrap();
+        assert!(session_cookies(&loser).is_empty());
+
+        // Past the grace window the old token is a stolen copy, which ends the session for everyone
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = revoked_at - INTERVAL '1 minute' WHERE revoked_at IS NOT NULL",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let reused = app.oneshot(with_old_token()).await.unwrap();
+        let cleared = session_cookies(&reused);
+        assert_eq!(cleared.len(), 2);
+        assert!(cleared.iter().all(|cookie| cookie.contains("Max-Age=0")));
+        let live: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM refresh_tokens WHERE revoked_at IS NULL")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(live, 0);
+    }
+
+    #[test]
+    fn the_proxys_forwarded_entry_is_the_client() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_for(&headers), None);
+
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("6.6.6.6, 10.0.0.2"),
+        );
+        assert_eq!(forwarded_for(&headers), "10.0.0.2".parse().ok());
+        headers.append("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(forwarded_for(&headers), "203.0.113.7".parse().ok());
+        headers.append(
+            "x-forwarded-for",
+            HeaderValue::from_static("not an address"),
+        );
+        assert_eq!(forwarded_for(&headers), None);
+    }
+}
@@ -2,39 +2,96 @@ use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::db::new_pool;
+use crate::db::{new_pool, Store};
 use crate::error::AppError;
+use crate::models::audit::Audit;
 use crate::routes::main_routes;
 use dotenvy::dotenv;
+use once_cell::sync::Lazy;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
 
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod calendar;
+mod csrf;
 pub mod db;
+mod email_token;
 pub mod error;
+pub mod export;
+mod feed;
 pub mod handlers;
+pub mod import;
 pub mod layers;
+pub mod mailer;
+mod markdown;
 mod models;
+pub mod notify;
+mod oidc;
+mod password;
 mod routes;
+mod session;
+mod tasks;
 mod template;
+mod throttle;
+mod two_factor;
 
 pub async fn run_backend() {
     dotenv().ok();
     init_logging();
 
     let addr = get_host_from_env();
+    Lazy::force(&session::COOKIE_SETTINGS);
+    Lazy::force(&PUBLIC_URL);
 
-    let app = main_routes::app(new_pool().await).await;
+    // Shared so the background refresh publishes on the same event channel the routes listen to
+    let store = Store::with_pool(new_pool().await);
+    seed_admin_from_env(&store).await;
+    tasks::spawn_watchlist_refresh(store.clone());
+
+    let app = main_routes::app(store).await;
 
     info!("Listening...");
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
+/// Who the audit log names for the admin created from `ADMIN_EMAIL` at startup
+const STARTUP_ACTOR: &str = "(startup)";
+
+/// Creates the first admin from `ADMIN_EMAIL` and `ADMIN_PASSWORD` while there is none. Admins can't
+/// be created any other way until one exists, so a failure here stops the server
+async fn seed_admin_from_env(store: &Store) {
+    let (Ok(email), Ok(password)) = (
+        std::env::var("ADMIN_EMAIL"),
+        std::env::var("ADMIN_PASSWORD"),
+    ) else {
+        return;
+    };
+    let email = email.trim();
+    if email.is_empty() || password.is_empty() {
+        panic!("ADMIN_EMAIL and ADMIN_PASSWORD must not be blank");
+    }
+
+    let hash = password::hash(&password).expect("Could not hash ADMIN_PASSWORD");
+    let audit = Audit {
+        actor: STARTUP_ACTOR.to_string(),
+        reason: None,
+        request_id: "startup".to_string(),
+    };
+    match store.seed_admin(email, &hash, audit).await {
+        Ok(true) => info!("Created the first admin, {}", email),
+        Ok(false) => warn!("An admin already exists, ignoring ADMIN_EMAIL and ADMIN_PASSWORD"),
+        Err(err) => panic!("Could not create the first admin {}: {:?}", email, err),
+    }
+}
+
 fn get_host_from_env() -> SocketAddr {
     let host = std::env::var("API_HOST").unwrap();
     let api_host = IpAddr::from_str(&host).unwrap();
@@ -60,14 +117,44 @@ fn init_logging() {
         .init();
 }
 
-pub fn get_timestamp_after_8_hours() -> u64 {
+/// Where links we hand out point to, taken from `PUBLIC_URL` and never from request headers
+pub static PUBLIC_URL: Lazy<String> = Lazy::new(|| {
+    public_url_from(std::env::var("PUBLIC_URL").ok())
+        .unwrap_or_else(|err| panic!("Invalid PUBLIC_URL: {}", err))
+});
+
+fn public_url_from(value: Option<String>) -> Result<String, String> {
+    let value = value.unwrap_or_default();
+    let url = value.trim().trim_end_matches('/');
+    if url.is_empty() {
+        return Err("it must be set to the address users reach the site at".to_string());
+    }
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("{} doesn't start with http:// or https://", url));
+    }
+    Ok(url.to_string())
+}
+
+pub fn get_timestamp_after(duration: Duration) -> u64 {
     let now = SystemTime::now();
     let since_epoch = now
         .duration_since(UNIX_EPOCH)
         .expect("Time somehow went backwards");
-    // 8 hours later
-    let eight_hours_from_now = since_epoch + Duration::from_secs(60 * 60 * 8);
-    eight_hours_from_now.as_secs()
+    (since_epoch + duration).as_secs()
+}
+
+/// Random secret for links and tokens that have to be unguessable
+pub fn generate_secret_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex SHA-256 of a secret token, which is all the database keeps of it
+pub fn hash_secret_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
 pub type AppResult<T> = Result<T, AppError>;
@@ -124,3 +211,19 @@ macro_rules! make_db_id {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_url_is_required_and_absolute() {
+        assert!(public_url_from(None).is_err());
+        assert!(public_url_from(Some(" ".to_string())).is_err());
+        assert!(public_url_from(Some("example.com".to_string())).is_err());
+        assert_eq!(
+            public_url_from(Some("https://example.com/ ".to_string())).unwrap(),
+            "https://example.com"
+        );
+    }
+}
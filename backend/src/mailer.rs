@@ -0,0 +1,346 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::async_trait;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+/// Where mail is dropped in dev mode when neither `MAIL_DROP_DIR` nor `SMTP_HOST` is set
+const DEFAULT_DROP_DIR: &str = "mail";
+
+/// Emails a [`QueuedMailer`] holds on to before it refuses more
+const QUEUE_LENGTH: usize = 1000;
+
+/// A plain text email
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Somewhere account emails can be sent
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), AppError>;
+}
+
+/// How account emails are delivered
+#[derive(Debug, PartialEq, Eq)]
+pub enum MailSettings {
+    Smtp,
+    /// Written as files into a directory, which is only allowed in dev mode since the emails
+    /// carry login and reset links
+    Files(PathBuf),
+}
+
+impl MailSettings {
+    /// Reads the settings through `var`, which looks up an environment variable.
+    /// `MAIL_DROP_DIR` and the `./mail` fallback need `DEV_MODE=true`
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let set = |name| var(name).filter(|value| !value.trim().is_empty());
+        let dev_mode = match set("DEV_MODE").as_deref().map(str::trim) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => return Err(format!("DEV_MODE must be true or false, not {}", other)),
+        };
+
+        match (set("MAIL_DROP_DIR"), set("SMTP_HOST")) {
+            (Some(_), _) if !dev_mode => {
+                Err("MAIL_DROP_DIR is only allowed with DEV_MODE=true".to_string())
+            }
+            (Some(dir), _) => Ok(MailSettings::Files(PathBuf::from(dir))),
+            (None, Some(_)) => Ok(MailSettings::Smtp),
+            (None, None) if dev_mode => Ok(MailSettings::Files(PathBuf::from(DEFAULT_DROP_DIR))),
+            (None, None) => Err(
+                "set SMTP_HOST, or DEV_MODE=true to write emails into ./mail instead".to_string(),
+            ),
+        }
+    }
+}
+
+/// The mailer [`MailSettings`] asks for. Panics on settings that don't work, so the server
+/// refuses to start rather than losing or leaking account emails
+pub fn mailer_from_env() -> Arc<dyn Mailer> {
+    let settings = MailSettings::from_vars(|name| std::env::var(name).ok())
+        .unwrap_or_else(|err| panic!("Invalid mail settings: {}", err));
+
+    match settings {
+        MailSettings::Files(dir) => {
+            warn!("Dev mode: dropping account emails into {}", dir.display());
+            Arc::new(FileMailer::new(dir))
+        }
+        MailSettings::Smtp => match SmtpMailer::from_env() {
+            Ok(Some(smtp)) => Arc::new(smtp),
+            Ok(None) => unreachable!("SMTP_HOST is set"),
+            Err(err) => panic!("Could not configure SMTP: {:?}", err),
+        },
+    }
+}
+
+/// Sends mail over SMTP. Set `SMTP_TLS=false` to talk plain SMTP to a local sink when testing
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn from_env() -> Result<Option<Self>, AppError> {
+        let host = match std::env::var("SMTP_HOST") {
+            Ok(host) => host,
+            Err(_) => return Ok(None),
+        };
+        let port: u16 = std::env::var("SMTP_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(587);
+        let tls = std::env::var("SMTP_TLS")
+            .map(|tls| tls != "false")
+            .unwrap_or(true);
+        let from = std::env::var("SMTP_FROM")
+            .unwrap_or_else(|_| "alerts@localhost".to_string())
+            .parse::<Mailbox>()
+            .map_err(|err| AppError::Any(anyhow::anyhow!("Invalid SMTP_FROM: {}", err)))?;
+
+        let mut builder = if tls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+                .map_err(|err| AppError::Any(err.into()))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host)
+        }
+        .port(port);
+
+        if let (Ok(username), Ok(password)) = (
+            std::env::var("SMTP_USERNAME"),
+            std::env::var("SMTP_PASSWORD"),
+        ) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Some(SmtpMailer {
+            transport: builder.build(),
+            from,
+        }))
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), AppError> {
+        let to = email
+            .to
+            .parse::<Mailbox>()
+            .map_err(|err| AppError::Any(err.into()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .body(email.body.clone())
+            .map_err(|err| AppError::Any(err.into()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| AppError::Any(err.into()))?;
+
+        Ok(())
+    }
+}
+
+/// Hands emails to a background task that sends them through another mailer, so the caller never
+/// waits on a slow mail server. Delivery failures are only logged
+pub struct QueuedMailer {
+    queue: mpsc::Sender<Email>,
+}
+
+impl QueuedMailer {
+    /// Starts the task sending through `mailer`, it runs until the queue is dropped
+    pub fn spawn(mailer: Arc<dyn Mailer>) -> Self {
+        let (queue, mut emails) = mpsc::channel::<Email>(QUEUE_LENGTH);
+        tokio::spawn(async move {
+            while let Some(email) = emails.recv().await {
+                if let Err(err) = mailer.send(&email).await {
+                    error!("Failed to send email to {}: {:?}", email.to, err);
+                }
+            }
+        });
+
+        QueuedMailer { queue }
+    }
+}
+
+#[async_trait]
+impl Mailer for QueuedMailer {
+    /// Only queues the email. Fails when the queue is full, rather than waiting for room
+    async fn send(&self, email: &Email) -> Result<(), AppError> {
+        self.queue
+            .try_send(email.clone())
+            .map_err(|err| AppError::Any(anyhow::anyhow!("Could not queue email: {}", err)))
+    }
+}
+
+/// Keeps every email it's given, the mail sink for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingMailer {
+    pub sent: std::sync::Mutex<Vec<Email>>,
+}
+
+#[cfg(test)]
+impl RecordingMailer {
+    /// Waits up to a few seconds for `count` emails to arrive, for mail sent from a background task
+    pub async fn wait_for(&self, count: usize) -> Vec<Email> {
+        for _ in 0..100 {
+            if self.sent.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        }
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, email: &Email) -> Result<(), AppError> {
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+/// Writes every email to its own `.eml` file in `dir`, named so they sort by when they were sent
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileMailer { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), AppError> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|err| AppError::Any(err.into()))?;
+
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            Uuid::new_v4()
+        ));
+        let contents = format!(
+            "To: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            email.to, email.subject, email.body
+        );
+        tokio::fs::write(&path, contents)
+            .await
+            .map_err(|err| AppError::Any(err.into()))?;
+
+        info!("Dropped email to {} in {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(vars: &[(&str, &str)]) -> Result<MailSettings, String> {
+        MailSettings::from_vars(|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn mail_only_goes_to_files_in_dev_mode() {
+        assert_eq!(settings(&[("SMTP_HOST", "smtp")]), Ok(MailSettings::Smtp));
+        assert!(settings(&[]).is_err());
+        assert!(settings(&[("MAIL_DROP_DIR", "/tmp/mail")]).is_err());
+        assert!(settings(&[("MAIL_DROP_DIR", "/tmp/mail"), ("SMTP_HOST", "smtp")]).is_err());
+        assert!(settings(&[("DEV_MODE", "yes")]).is_err());
+
+        assert_eq!(
+            settings(&[("DEV_MODE", "true")]),
+            Ok(MailSettings::Files(PathBuf::from("mail")))
+        );
+        assert_eq!(
+            settings(&[("DEV_MODE", "true"), ("MAIL_DROP_DIR", "/tmp/mail")]),
+            Ok(MailSettings::Files(PathBuf::from("/tmp/mail")))
+        );
+        assert_eq!(
+            settings(&[("DEV_MODE", "true"), ("SMTP_HOST", "smtp")]),
+            Ok(MailSettings::Smtp)
+        );
+    }
+
+    #[tokio::test]
+    async fn file_mailer_writes_readable_emails() {
+        let dir = std::env::temp_dir().join(format!("mailer-test-{}", Uuid::new_v4()));
+        let mailer = FileMailer::new(&dir);
+        let email = Email {
+            to: "user@example.com".to_string(),
+            subject: "Verify your email".to_string(),
+            body: "Open http://localhost:3000/verify-email?token=abc\n".to_string(),
+        };
+
+        mailer.send(&email).await.unwrap();
+        mailer.send(&email).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 2);
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(contents.starts_with("To: user@example.com\r\nSubject: Verify your email\r\n"));
+        assert!(contents.ends_with("\r\n\r\nOpen http://localhost:3000/verify-email?token=abc\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Stands in for a mail server that takes its time
+    struct SlowMailer(Arc<RecordingMailer>);
+
+    #[async_trait]
+    impl Mailer for SlowMailer {
+        async fn send(&self, email: &Email) -> Result<(), AppError> {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            self.0.send(email).await
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_mail_is_sent_in_the_background() {
+        let sink = Arc::new(RecordingMailer::default());
+        let mailer = QueuedMailer::spawn(Arc::new(SlowMailer(sink.clone())));
+        let email = |to: &str| Email {
+            to: to.to_string(),
+            subject: "Alert".to_string(),
+            body: "Something is coming".to_string(),
+        };
+
+        let started = std::time::Instant::now();
+        mailer.send(&email("first@example.com")).await.unwrap();
+        mailer.send(&email("second@example.com")).await.unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+
+        let sent = sink.wait_for(2).await;
+        assert_eq!(
+            sent,
+            vec![email("first@example.com"), email("second@example.com")]
+        );
+    }
+}
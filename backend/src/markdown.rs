@@ -0,0 +1,225 @@
+use html_escape::{encode_double_quoted_attribute, encode_text};
+
+/// Renders the small markdown subset comments support: paragraphs, `- ` lists, fenced code blocks,
+/// `**bold**`, `*italic*`, `` `code` `` and `[links](https://...)`.
+///
+/// Every piece of user text goes through `html_escape` on the way out, so the only tags in the result
+/// are the ones written here. Links are only made for http(s) URLs.
+pub fn render(input: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut list: Vec<&str> = vec![];
+    let mut code: Option<Vec<&str>> = None;
+
+    for line in input.lines() {
+        if let Some(block) = code.as_mut() {
+            if line.trim_start().starts_with("```") {
+                html.push_str(&format!(
+                    "<pre><code>{}</code></pre>",
+                    encode_text(&block.join("\n"))
+                ));
+                code = None;
+            } else {
+                block.push(line);
+            }
+            continue;
+        }
+
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            flush_paragraph(&mut html, &mut paragraph);
+            flush_list(&mut html, &mut list);
+            code = Some(vec![]);
+        } else if let Some(item) = trimmed
+            .strip_prefix("- ")
+            .or_else(|| trimmed.strip_prefix("* "))
+        {
+            flush_paragraph(&mut html, &mut paragraph);
+            list.push(item);
+        } else if trimmed.is_empty() {
+            flush_paragraph(&mut html, &mut paragraph);
+            flush_list(&mut html, &mut list);
+        } else {
+            flush_list(&mut html, &mut list);
+            paragraph.push(trimmed);
+        }
+    }
+
+    // An unclosed fence still shows its contents
+    if let Some(block) = code {
+        html.push_str(&format!(
+            "<pre><code>{}</code></pre>",
+            encode_text(&block.join("\n"))
+        ));
+    }
+    flush_paragraph(&mut html, &mut paragraph);
+    flush_list(&mut html, &mut list);
+
+    html
+}
+
+fn flush_paragraph(html: &mut String, lines: &mut Vec<&str>) {
+    if lines.is_empty() {
+        return;
+    }
+    let rendered: Vec<String> = lines.iter().map(|line| inline(line)).collect();
+    html.push_str(&format!("<p>{}</p>", rendered.join("<br>")));
+    lines.clear();
+}
+
+fn flush_list(html: &mut String, items: &mut Vec<&str>) {
+    if items.is_empty() {
+        return;
+    }
+    html.push_str("<ul>");
+    for item in items.iter() {
+        html.push_str(&format!("<li>{}</li>", inline(item)));
+    }
+    html.push_str("</ul>");
+    items.clear();
+}
+
+/// Renders emphasis, code and links in one pass over `text`. Marker lookups go through [`NextMarker`],
+/// so text full of unclosed markers doesn't get rescanned from every position
+fn inline(text: &str) -> String {
+    let mut html = String::new();
+    let mut markers = NextMarker::new(text);
+    let mut at = 0;
+
+    while let Some(next) = text[at..].chars().next() {
+        if let Some(close) = delimited(&mut markers, at, "`") {
+            html.push_str(&format!(
+                "<code>{}</code>",
+                encode_text(&text[at + 1..close])
+            ));
+            at = close + 1;
+        } else if let Some(close) = delimited(&mut markers, at, "**") {
+            html.push_str(&format!(
+                "<strong>{}</strong>",
+                inline(&text[at + 2..close])
+            ));
+            at = close + 2;
+        } else if let Some(close) = delimited(&mut markers, at, "*") {
+            html.push_str(&format!("<em>{}</em>", inline(&text[at + 1..close])));
+            at = close + 1;
+        } else if let Some((label_end, url_end)) = link(&mut markers, at) {
+            html.push_str(&format!(
+                "<a href=\"{}\" rel=\"nofollow noopener\">{}</a>",
+                encode_double_quoted_attribute(&text[label_end + 2..url_end]),
+                inline(&text[at + 1..label_end])
+            ));
+            at = url_end + 1;
+        } else {
+            html.push_str(&encode_text(&text[at..at + next.len_utf8()]));
+            at += next.len_utf8();
+        }
+    }
+
+    html
+}
+
+/// Finds the next occurrence of a marker in `text`, remembering the last answer for each marker.
+/// `inline` only ever asks about positions further along, so an answer stays good until it's passed
+struct NextMarker<'a> {
+    text: &'a str,
+    found: Vec<(&'static str, Option<usize>)>,
+}
+
+impl<'a> NextMarker<'a> {
+    fn new(text: &'a str) -> Self {
+        NextMarker {
+            text,
+            found: vec![],
+        }
+    }
+
+    /// Byte offset of the first `marker` at or after `from`
+    fn find(&mut self, marker: &'static str, from: usize) -> Option<usize> {
+        let cached = self.found.iter_mut().find(|(known, _)| *known == marker);
+        match cached {
+            // Nothing was left after an earlier position, so there's nothing after this one either
+            Some((_, None)) => return None,
+            Some((_, Some(at))) if *at >= from => return Some(*at),
+            _ => {}
+        }
+
+        let at = self.text[from..].find(marker).map(|offset| from + offset);
+        match self.found.iter_mut().find(|(known, _)| *known == marker) {
+            Some(cached) => cached.1 = at,
+            None => self.found.push((marker, at)),
+        }
+        at
+    }
+}
+
+/// Where the `marker` closing one that opens at `at` starts, if it does open there with something inside
+fn delimited(markers: &mut NextMarker, at: usize, marker: &'static str) -> Option<usize> {
+    if !markers.text[at..].starts_with(marker) {
+        return None;
+    }
+    let close = markers.find(marker, at + marker.len())?;
+    (close > at + marker.len()).then_some(close)
+}
+
+/// Where the label of a link starting at `at` ends and where its http(s) URL ends
+fn link(markers: &mut NextMarker, at: usize) -> Option<(usize, usize)> {
+    if !markers.text[at..].starts_with('[') {
+        return None;
+    }
+    let label_end = markers.find("](", at + 1)?;
+    let url_end = markers.find(")", label_end + 2)?;
+    let url = &markers.text[label_end + 2..url_end];
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return None;
+    }
+    Some((label_end, url_end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_supported_subset() {
+        assert_eq!(
+            render("Observe **tonight**, use *filter B*\nthen `reduce.py`"),
+            "<p>Observe <strong>tonight</strong>, use <em>filter B</em><br>then <code>reduce.py</code></p>"
+        );
+        assert_eq!(
+            render("Plan:\n\n- first\n- second"),
+            "<p>Plan:</p><ul><li>first</li><li>second</li></ul>"
+        );
+        assert_eq!(
+            render("```\nif a < b {}\n```"),
+            "<pre><code>if a &lt; b {}</code></pre>"
+        );
+        assert_eq!(
+            render("[orbit](https://ssd.jpl.nasa.gov/?a=1&b=\"2\")"),
+            "<p><a href=\"https://ssd.jpl.nasa.gov/?a=1&amp;b=&quot;2&quot;\" rel=\"nofollow noopener\">orbit</a></p>"
+        );
+    }
+
+    #[test]
+    fn user_html_never_gets_through() {
+        assert_eq!(
+            render("<script>alert(1)</script>"),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>"
+        );
+        assert_eq!(
+            render("**<img src=x onerror=alert(1)>**"),
+            "<p><strong>&lt;img src=x onerror=alert(1)&gt;</strong></p>"
+        );
+        assert_eq!(
+            render("[click](javascript:alert(1))"),
+            "<p>[click](javascript:alert(1))</p>"
+        );
+    }
+
+    #[test]
+    fn unclosed_markers_render_in_linear_time() {
+        // Rescanning for the closing marker from every position would take minutes here
+        let opens = "[".repeat(200_000);
+        let input = format!("{}](javascript:alert(1))", opens);
+        assert_eq!(render(&input), format!("<p>{}</p>", encode_text(&input)));
+    }
+}
@@ -0,0 +1,119 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::neo::Neo;
+
+/// NeoWs distances are stored in miles, rules are written in astronomical units
+pub const MILES_PER_AU: f64 = 92_955_807.273;
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AlertRule {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub hazardous_only: bool,
+    pub max_miss_distance_au: Option<f64>,
+    pub within_days: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateAlertRule {
+    pub name: String,
+    #[serde(default)]
+    pub hazardous_only: bool,
+    pub max_miss_distance_au: Option<f64>,
+    pub within_days: Option<i32>,
+}
+
+/// A rule together with the address its alerts go to
+#[derive(Debug, sqlx::FromRow)]
+pub struct AlertRuleOwner {
+    #[sqlx(flatten)]
+    pub rule: AlertRule,
+    pub email: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct InboxMessage {
+    pub id: i32,
+    pub user_id: i32,
+    pub alert_id: Option<i32>,
+    pub subject: String,
+    pub body: String,
+    pub read: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AlertRule {
+    /// Whether an approach satisfies every condition set on the rule
+    pub fn matches(&self, neo: &Neo, today: NaiveDate) -> bool {
+        if self.hazardous_only && !neo.hazardous_asteroid {
+            return false;
+        }
+
+        if let Some(max_au) = self.max_miss_distance_au {
+            if neo.miss_distance as f64 / MILES_PER_AU >= max_au {
+                return false;
+            }
+        }
+
+        if let Some(days) = self.within_days {
+            let approach = match NaiveDate::parse_from_str(&neo.approach_date, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => return false,
+            };
+            if approach < today || approach > today + Duration::days(days as i64) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::neo::NeoId;
+
+    fn neo(hazardous: bool, miss_distance: f32, approach_date: &str) -> Neo {
+        Neo::new(
+            NeoId(1),
+            3542519,
+            "2010 PK9".to_string(),
+            0.06,
+            0.15,
+            hazardous,
+            approach_date.to_string(),
+            69201.99,
+            miss_distance,
+            "Earth".to_string(),
+            None,
+        )
+    }
+
+    #[test]
+    fn rule_checks_every_condition() {
+        let rule = AlertRule {
+            id: 1,
+            user_id: 1,
+            name: "close and dangerous".to_string(),
+            hazardous_only: true,
+            max_miss_distance_au: Some(0.05),
+            within_days: Some(14),
+            created_at: Utc::now(),
+        };
+        let today = NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+
+        // 0.03 AU, hazardous, in 5 days
+        assert!(rule.matches(&neo(true, 2_788_674.0, "2024-03-25"), today));
+        // not hazardous
+        assert!(!rule.matches(&neo(false, 2_788_674.0, "2024-03-25"), today));
+        // 0.1 AU
+        assert!(!rule.matches(&neo(true, 9_295_580.0, "2024-03-25"), today));
+        // too far out, and already passed
+        assert!(!rule.matches(&neo(true, 2_788_674.0, "2024-04-20"), today));
+        assert!(!rule.matches(&neo(true, 2_788_674.0, "2024-03-19"), today));
+    }
+}
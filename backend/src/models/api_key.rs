@@ -0,0 +1,408 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Utc};
+use http::header::AUTHORIZATION;
+use http::request::Parts;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::models::role::{Comment, Permission, ReadNeos, Role, WriteNeos};
+use crate::models::user::Claims;
+use crate::{generate_secret_token, hash_secret_token};
+
+/// Every key starts with this, so leaked keys are easy to spot
+const KEY_PREFIX: &str = "ecc_";
+
+/// How much of a key is kept in the clear to tell keys apart on the keys page
+const DISPLAY_PREFIX_LENGTH: usize = KEY_PREFIX.len() + 6;
+
+/// What a key may be used for. A key can never do more than its owner's role allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiScope {
+    /// Read close approaches and use the personal features built on them
+    #[serde(rename = "read:neos")]
+    ReadNeos,
+    /// Create, edit, delete and import close approaches, and comment
+    #[serde(rename = "write:neos")]
+    WriteNeos,
+    /// Everything the owner's role allows
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 3] = [ApiScope::ReadNeos, ApiScope::WriteNeos, ApiScope::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::ReadNeos => "read:neos",
+            ApiScope::WriteNeos => "write:neos",
+            ApiScope::Admin => "admin",
+        }
+    }
+
+    /// Whether this scope lets a key use endpoints that need `permission`
+    pub fn covers(&self, permission: &str) -> bool {
+        match self {
+            ApiScope::ReadNeos => permission == ReadNeos::NAME,
+            ApiScope::WriteNeos => permission == WriteNeos::NAME || permission == Comment::NAME,
+            ApiScope::Admin => true,
+        }
+    }
+}
+
+/// A key as listed to its owner. The secret itself is never stored, only its hash
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    /// The first few characters of the key, to recognise it by
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Last day the key works, `YYYY-MM-DD`. Keys without one never expire
+    pub expires_on: Option<String>,
+}
+
+/// The keys page form, one checkbox per scope
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyForm {
+    pub name: String,
+    pub read_neos: Option<String>,
+    pub write_neos: Option<String>,
+    pub admin: Option<String>,
+    pub expires_on: Option<String>,
+}
+
+impl From<ApiKeyForm> for CreateApiKey {
+    fn from(form: ApiKeyForm) -> Self {
+        let scopes = [
+            (form.read_neos, ApiScope::ReadNeos),
+            (form.write_neos, ApiScope::WriteNeos),
+            (form.admin, ApiScope::Admin),
+        ]
+        .into_iter()
+        .filter_map(|(checked, scope)| checked.map(|_| scope))
+        .collect();
+
+        CreateApiKey {
+            name: form.name,
+            scopes,
+            expires_on: form.expires_on,
+        }
+    }
+}
+
+/// A key that passed validation, ready to be stored
+#[derive(Debug)]
+pub struct ValidApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CreateApiKey {
+    /// Trims the name, drops duplicate scopes and turns the last valid day into an expiry timestamp
+    pub fn validate(self, today: NaiveDate) -> Result<ValidApiKey, AppError> {
+        let name = self.name.trim().to_string();
+        if name.is_empty() || name.len() > 100 {
+            return Err(AppError::InvalidApiKey(
+                "name must be between 1 and 100 characters".to_string(),
+            ));
+        }
+
+        let scopes: Vec<String> = ApiScope::ALL
+            .iter()
+            .filter(|scope| self.scopes.contains(scope))
+            .map(|scope| scope.as_str().to_string())
+            .collect();
+        if scopes.is_empty() {
+            return Err(AppError::InvalidApiKey(
+                "pick at least one scope".to_string(),
+            ));
+        }
+
+        let expires_at = match self.expires_on.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(day) => {
+                let day = NaiveDate::parse_from_str(day, "%Y-%m-%d")?;
+                if day < today {
+                    return Err(AppError::InvalidApiKey(
+                        "expiry date is in the past".to_string(),
+                    ));
+                }
+                // Valid through the whole of the last day
+                let end = day.checked_add_days(Days::new(1)).ok_or_else(|| {
+                    AppError::InvalidApiKey("expiry date is too far away".to_string())
+                })?;
+                Some(Utc.from_utc_datetime(&end.and_hms_opt(0, 0, 0).unwrap()))
+            }
+        };
+
+        Ok(ValidApiKey {
+            name,
+            scopes,
+            expires_at,
+        })
+    }
+}
+
+/// A new secret key and the hash and prefix stored for it
+pub struct GeneratedKey {
+    pub key: String,
+    pub hash: String,
+    pub prefix: String,
+}
+
+pub fn generate_key() -> GeneratedKey {
+    let key = format!("{}{}", KEY_PREFIX, generate_secret_token());
+
+    GeneratedKey {
+        hash: hash_secret_token(&key),
+        prefix: key[..DISPLAY_PREFIX_LENGTH].to_string(),
+        key,
+    }
+}
+
+/// Returned once, right after creation. The key can't be shown again
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+/// Identifies a key in the keys page forms
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyId {
+    pub id: i32,
+}
+
+/// The owner of a key that was just used, looked up by its hash
+#[derive(Debug, sqlx::FromRow)]
+pub struct ApiKeyUser {
+    pub key_id: i32,
+    pub user_id: i32,
+    pub email: String,
+    pub role: String,
+    pub token_version: i32,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub banned: bool,
+}
+
+impl ApiKeyUser {
+    /// Whether any of the key's scopes covers `permission`
+    pub fn allows(&self, permission: &str) -> bool {
+        ApiScope::ALL
+            .iter()
+            .filter(|scope| self.scopes.iter().any(|name| name == scope.as_str()))
+            .any(|scope| scope.covers(permission))
+    }
+
+    /// The key's owner as handlers know them from a login token
+    pub fn claims(&self) -> Result<Claims, AppError> {
+        Ok(Claims {
+            id: self.user_id,
+            email: self.email.clone(),
+            role: self.role.parse::<Role>().map_err(AppError::InvalidRole)?,
+            banned: self.banned,
+            token_version: self.token_version,
+            exp: self
+                .expires_at
+                .map(|expires_at| expires_at.timestamp() as u64)
+                .unwrap_or(u64::MAX),
+            jti: format!("api-key-{}", self.key_id),
+        })
+    }
+}
+
+/// A valid, unexpired key from `Authorization: Bearer` whose owner isn't banned.
+/// Every use is recorded as the key's last use
+pub struct ApiKeyAuth(pub ApiKeyUser);
+
+#[async_trait]
+impl FromRequestParts<Store> for ApiKeyAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Store) -> Result<Self, Self::Rejection> {
+        let key = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(AppError::InvalidToken)?;
+
+        let user = state.use_api_key(&hash_secret_token(key)).await?;
+        if user.banned {
+            if let Some(ban) = state.get_active_ban(user.user_id).await? {
+                return Err(AppError::Banned(Box::new(ban)));
+            }
+        }
+        Ok(ApiKeyAuth(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{add_user, audit, test_store};
+    use crate::models::role::{ReadNeos, WriteNeos};
+    use crate::models::user::{ApiClaims, RequirePermission};
+    use sqlx::PgPool;
+
+    fn request(scopes: Vec<ApiScope>, expires_on: Option<&str>) -> CreateApiKey {
+        CreateApiKey {
+            name: " nightly import ".to_string(),
+            scopes,
+            expires_on: expires_on.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn scopes_only_cover_their_permissions() {
+        let key = ApiKeyUser {
+            key_id: 1,
+            user_id: 1,
+            email: "script@example.com".to_string(),
+            role: "analyst".to_string(),
+            token_version: 0,
+            scopes: vec!["read:neos".to_string()],
+            expires_at: None,
+            banned: false,
+        };
+        assert!(key.allows("read_neos"));
+        assert!(!key.allows("write_neos"));
+        assert!(!key.allows("ban_users"));
+
+        assert!(ApiScope::WriteNeos.covers("write_neos"));
+        assert!(!ApiScope::WriteNeos.covers("read_neos"));
+        assert!(ApiScope::Admin.covers("manage_users"));
+    }
+
+    #[test]
+    fn validation_normalises_scopes_and_expiry() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+
+        let key = request(
+            vec![ApiScope::WriteNeos, ApiScope::ReadNeos, ApiScope::WriteNeos],
+            Some("2024-03-20"),
+        )
+        .validate(today)
+        .unwrap();
+        assert_eq!(key.name, "nightly import");
+        assert_eq!(key.scopes, vec!["read:neos", "write:neos"]);
+        assert_eq!(
+            key.expires_at.unwrap().to_rfc3339(),
+            "2024-03-21T00:00:00+00:00"
+        );
+
+        assert!(request(vec![ApiScope::ReadNeos], Some(""))
+            .validate(today)
+            .unwrap()
+            .expires_at
+            .is_none());
+        assert!(request(vec![], None).validate(today).is_err());
+        assert!(request(vec![ApiScope::ReadNeos], Some("2024-03-19"))
+            .validate(today)
+            .is_err());
+    }
+
+    #[test]
+    fn generated_keys_are_recognisable_and_only_hashed() {
+        let first = generate_key();
+        let second = generate_key();
+
+        assert!(first.key.starts_with("ecc_"));
+        assert!(first.key.starts_with(&first.prefix));
+        assert_eq!(first.hash, hash_secret_token(&first.key));
+        assert_ne!(first.key, second.key);
+        assert_ne!(first.hash, first.key);
+    }
+
+    #[test]
+    fn form_checkboxes_become_scopes() {
+        let form = ApiKeyForm {
+            name: "ci".to_string(),
+            read_neos: Some("on".to_string()),
+            write_neos: None,
+            admin: Some("on".to_string()),
+            expires_on: None,
+        };
+
+        assert_eq!(
+            CreateApiKey::from(form).scopes,
+            vec![ApiScope::ReadNeos, ApiScope::Admin]
+        );
+    }
+
+    /// The request parts of a call made with `key`
+    fn bearer(key: &str) -> Parts {
+        http::Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", key))
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn bearer_keys_are_checked_for_revocation_and_scope(pool: PgPool) {
+        let store = test_store(pool, vec![]);
+        let user_id = add_user(&store, "script@example.com").await;
+        store
+            .set_role(user_id, Role::Analyst, audit())
+            .await
+            .unwrap();
+        let generated = generate_key();
+        let key = store
+            .add_api_key(
+                user_id,
+                request(vec![ApiScope::ReadNeos], None)
+                    .validate(Utc::now().date_naive())
+                    .unwrap(),
+                &generated,
+            )
+            .await
+            .unwrap();
+
+        let ApiClaims(claims, used) =
+            ApiClaims::from_request_parts(&mut bearer(&generated.key), &store)
+                .await
+                .unwrap();
+        assert_eq!(claims.id, user_id);
+        assert_eq!(used.unwrap().key_id, key.id);
+        let RequirePermission(claims, _) =
+            RequirePermission::<ReadNeos>::from_request_parts(&mut bearer(&generated.key), &store)
+                .await
+                .unwrap();
+        assert_eq!(claims.jti, format!("api-key-{}", key.id));
+
+        // an analyst may write, but not with a read-only key
+        assert!(matches!(
+            RequirePermission::<WriteNeos>::from_request_parts(&mut bearer(&generated.key), &store)
+                .await,
+            Err(AppError::Forbidden)
+        ));
+
+        store.delete_api_key(user_id, key.id).await.unwrap();
+        assert!(matches!(
+            ApiClaims::from_request_parts(&mut bearer(&generated.key), &store).await,
+            Err(AppError::InvalidToken)
+        ));
+        assert!(matches!(
+            ApiClaims::from_request_parts(&mut bearer("ecc_not_a_key"), &store).await,
+            Err(AppError::InvalidToken)
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+/// A row of `bans` with the banned user's email
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Ban {
+    pub id: i32,
+    pub email: String,
+    pub reason: Option<String>,
+    pub banned_by: String,
+    pub created_at: DateTime<Utc>,
+    /// `None` for permanent bans
+    pub expires_at: Option<DateTime<Utc>>,
+    pub lifted_at: Option<DateTime<Utc>>,
+    pub lifted_by: Option<String>,
+    pub lift_reason: Option<String>,
+    /// Neither lifted nor expired yet
+    pub active: bool,
+}
+
+impl Ban {
+    /// What the banned user is told, without who banned them
+    pub fn notice(&self) -> String {
+        let mut notice = match self.expires_at {
+            Some(expires_at) => format!(
+                "You are banned until {}",
+                expires_at.format("%Y-%m-%d %H:%M UTC")
+            ),
+            None => "You are banned".to_string(),
+        };
+        if let Some(reason) = &self.reason {
+            notice.push_str(": ");
+            notice.push_str(reason);
+        }
+        notice
+    }
+}
+
+/// How long a ban lasts, `<number>` followed by `h`, `d` or `w`. Blank or `permanent` means it never ends
+pub fn parse_duration(duration: &str) -> Result<Option<Duration>, AppError> {
+    let duration = duration.trim().to_ascii_lowercase();
+    if duration.is_empty() || duration == "permanent" {
+        return Ok(None);
+    }
+
+    let invalid = || {
+        AppError::InvalidBan(format!(
+            "Ban durations look like 12h, 7d or 2w, not {}",
+            duration
+        ))
+    };
+    let unit = duration.chars().last().ok_or_else(invalid)?;
+    let amount: i64 = duration[..duration.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    if amount <= 0 || amount > 10_000 {
+        return Err(invalid());
+    }
+
+    match unit {
+        'h' => Ok(Some(Duration::hours(amount))),
+        'd' => Ok(Some(Duration::days(amount))),
+        'w' => Ok(Some(Duration::weeks(amount))),
+        _ => Err(invalid()),
+    }
+}
+
+/// The admin page's ban form
+#[derive(Serialize, Deserialize)]
+pub struct BanForm {
+    pub user_id: i32,
+    #[serde(default)]
+    pub reason: String,
+    /// See [`parse_duration`]
+    #[serde(default)]
+    pub duration: String,
+}
+
+/// The admin page's form lifting a ban early
+#[derive(Serialize, Deserialize)]
+pub struct UnbanForm {
+    pub user_id: i32,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// Narrows the ban history down to one user
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BanFilter {
+    pub email: Option<String>,
+}
+
+impl BanFilter {
+    pub fn email(&self) -> Option<&str> {
+        self.email
+            .as_deref()
+            .map(str::trim)
+            .filter(|email| !email.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn ban(expires_at: Option<DateTime<Utc>>, reason: Option<&str>) -> Ban {
+        Ban {
+            id: 1,
+            email: "spammer@example.com".to_string(),
+            reason: reason.map(str::to_string),
+            banned_by: "admin@example.com".to_string(),
+            created_at: Utc.with_ymd_and_hms(2024, 3, 20, 12, 0, 0).unwrap(),
+            expires_at,
+            lifted_at: None,
+            lifted_by: None,
+            lift_reason: None,
+            active: true,
+        }
+    }
+
+    #[test]
+    fn durations_take_hours_days_and_weeks() {
+        assert_eq!(parse_duration("").unwrap(), None);
+        assert_eq!(parse_duration(" Permanent ").unwrap(), None);
+        assert_eq!(parse_duration("12h").unwrap(), Some(Duration::hours(12)));
+        assert_eq!(parse_duration("7D").unwrap(), Some(Duration::days(7)));
+        assert_eq!(parse_duration("2w").unwrap(), Some(Duration::weeks(2)));
+        assert!(parse_duration("0d").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("3m").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("5é").is_err());
+        assert!(parse_duration("forever").is_err());
+    }
+
+    #[test]
+    fn notices_give_the_end_and_reason() {
+        assert_eq!(ban(None, None).notice(), "You are banned");
+        assert_eq!(
+            ban(
+                Some(Utc.with_ymd_and_hms(2024, 3, 27, 12, 0, 0).unwrap()),
+                Some("spam")
+            )
+            .notice(),
+            "You are banned until 2024-03-27 12:00 UTC: spam"
+        );
+    }
+}
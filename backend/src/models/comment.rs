@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::markdown;
+
+/// Longest comment we accept, in characters
+pub const MAX_COMMENT_LENGTH: usize = 10_000;
+
+/// A `comments` row joined with its author's email. Both are gone once the author deletes their account
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct CommentRow {
+    pub id: i32,
+    pub api_id: i32,
+    pub user_id: Option<i32>,
+    pub parent_id: Option<i32>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub email: Option<String>,
+}
+
+/// A comment ready for display, with its replies nested under it
+#[derive(Debug, Serialize)]
+pub struct Comment {
+    pub id: i32,
+    pub api_id: i32,
+    pub parent_id: Option<i32>,
+    /// Masked so other readers can't harvest email addresses, see [`author_handle`]
+    pub author: String,
+    /// Whether the user looking at the comment wrote it, and so may edit or delete it
+    pub mine: bool,
+    pub body: String,
+    /// `body` rendered from markdown, safe to put into a page unescaped
+    pub html: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    pub replies: Vec<Comment>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateComment {
+    pub body: String,
+    pub parent_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateComment {
+    pub body: String,
+}
+
+/// What the forms on `neo.html` send, `api_id` is only used to send the user back to the same page
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommentForm {
+    pub api_id: i32,
+    pub id: Option<i32>,
+    pub parent_id: Option<i32>,
+    #[serde(default)]
+    pub body: String,
+}
+
+/// Trims a comment body and checks it is neither empty nor too long
+pub fn validate_body(body: &str) -> Result<String, String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err("comment must not be empty".to_string());
+    }
+    if body.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(format!(
+            "comment must be at most {} characters",
+            MAX_COMMENT_LENGTH
+        ));
+    }
+    Ok(body.to_string())
+}
+
+/// What comments show instead of the author's email: its first letter and the domain's first letter
+/// and ending, like `o***@e***.com`
+pub fn author_handle(email: &str) -> String {
+    let (local, domain) = email.split_once('@').unwrap_or((email, ""));
+    let first = |text: &str| text.chars().next().map(String::from).unwrap_or_default();
+    match domain.rsplit_once('.') {
+        Some((name, ending)) => format!("{}***@{}***.{}", first(local), first(name), ending),
+        None => format!("{}***", first(local)),
+    }
+}
+
+impl Comment {
+    /// The comment as `viewer` sees it
+    pub fn new(row: CommentRow, viewer: i32) -> Self {
+        let deleted = row.deleted_at.is_some();
+        // Deleted comments stay in the thread so their replies still make sense, but lose their text
+        let (author, body) = match row.email {
+            Some(email) if !deleted => (author_handle(&email), row.body),
+            _ => ("[deleted]".to_string(), String::new()),
+        };
+
+        Comment {
+            id: row.id,
+            api_id: row.api_id,
+            parent_id: row.parent_id,
+            html: markdown::render(&body),
+            author,
+            mine: !deleted && row.user_id == Some(viewer),
+            body,
+            created_at: row.created_at,
+            edited_at: row.edited_at,
+            deleted,
+            replies: vec![],
+        }
+    }
+}
+
+/// Nests rows (oldest first) into threads as `viewer` sees them. Replies whose parent is missing are
+/// shown at the top level
+pub fn thread(rows: Vec<CommentRow>, viewer: i32) -> Vec<Comment> {
+    let ids: Vec<i32> = rows.iter().map(|row| row.id).collect();
+    let mut children: HashMap<Option<i32>, Vec<Comment>> = HashMap::new();
+    for row in rows {
+        let parent = row.parent_id.filter(|parent| ids.contains(parent));
+        children
+            .entry(parent)
+            .or_default()
+            .push(Comment::new(row, viewer));
+    }
+
+    fn attach(comment: &mut Comment, children: &mut HashMap<Option<i32>, Vec<Comment>>) {
+        comment.replies = children.remove(&Some(comment.id)).unwrap_or_default();
+        for reply in comment.replies.iter_mut() {
+            attach(reply, children);
+        }
+    }
+
+    let mut roots = children.remove(&None).unwrap_or_default();
+    for root in roots.iter_mut() {
+        attach(root, &mut children);
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: i32, parent_id: Option<i32>, deleted: bool) -> CommentRow {
+        CommentRow {
+            id,
+            api_id: 3542519,
+            user_id: Some(1),
+            parent_id,
+            body: format!("comment **{}**", id),
+            created_at: Utc::now(),
+            edited_at: None,
+            deleted_at: deleted.then(Utc::now),
+            email: Some("observer@example.com".to_string()),
+        }
+    }
+
+    #[test]
+    fn replies_are_nested_under_their_parent() {
+        let threads = thread(
+            vec![
+                row(1, None, false),
+                row(2, Some(1), true),
+                row(3, Some(2), false),
+                row(4, None, false),
+            ],
+            1,
+        );
+
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].replies[0].id, 2);
+        assert!(threads[0].replies[0].deleted);
+        assert_eq!(threads[0].replies[0].author, "[deleted]");
+        assert_eq!(threads[0].replies[0].replies[0].id, 3);
+        assert_eq!(threads[0].html, "<p>comment <strong>1</strong></p>");
+        assert_eq!(threads[1].id, 4);
+        assert_eq!(threads[1].author, "o***@e***.com");
+        assert!(threads[1].mine);
+        assert!(!threads[0].replies[0].mine);
+        assert!(!thread(vec![row(6, None, false)], 2)[0].mine);
+    }
+
+    #[test]
+    fn comments_of_deleted_accounts_have_no_author() {
+        let mut orphan = row(5, None, false);
+        orphan.user_id = None;
+        orphan.email = None;
+
+        let comment = Comment::new(orphan, 1);
+        assert_eq!(comment.author, "[deleted]");
+        assert_eq!(comment.body, "");
+    }
+
+    #[test]
+    fn handles_hide_the_email_address() {
+        assert_eq!(author_handle("observer@example.com"), "o***@e***.com");
+        assert_eq!(author_handle("ö@mail.observatory.org"), "ö***@m***.org");
+        assert_eq!(author_handle("nobody"), "n***");
+    }
+
+    #[test]
+    fn body_is_trimmed_and_checked() {
+        assert_eq!(validate_body("  hi \n").unwrap(), "hi");
+        assert!(validate_body(" \n ").is_err());
+        assert!(validate_body(&"a".repeat(MAX_COMMENT_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use axum::async_trait;
+use axum::extract::{FromRequest, FromRequestParts, Query};
+use axum::Json;
+use chrono::{Datelike, Duration, NaiveDate, Utc, Weekday};
+use http::Request;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+/// Raw begin/end strings as they arrive in the query string or a JSON body
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CreateDateRange {
+    pub begin_date: Option<String>,
+    pub end_date: Option<String>,
+}
+
+/// An inclusive, already validated range of days
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DateRange {
+    pub begin: NaiveDate,
+    pub end: NaiveDate,
+}
+
+impl DateRange {
+    /// Resolves both ends against `today`. When `end_date` is missing the
+    /// end of the `begin_date` expression is used, so `begin_date=this-week` works alone.
+    pub fn resolve(raw: &CreateDateRange, today: NaiveDate) -> Result<Self, AppError> {
+        let begin_input = raw
+            .begin_date
+            .as_deref()
+            .filter(|input| !input.trim().is_empty())
+            .ok_or_else(|| AppError::InvalidDateRange("begin_date is required".to_string()))?;
+
+        let (begin, begin_span_end) = parse_expression(begin_input, today)
+            .map_err(|err| AppError::InvalidDateRange(format!("begin_date: {}", err)))?;
+
+        let end = match raw
+            .end_date
+            .as_deref()
+            .filter(|input| !input.trim().is_empty())
+        {
+            Some(end_input) => {
+                parse_expression(end_input, today)
+                    .map_err(|err| AppError::InvalidDateRange(format!("end_date: {}", err)))?
+                    .1
+            }
+            None => begin_span_end,
+        };
+
+        if begin > end {
+            return Err(AppError::InvalidDateRange(format!(
+                "begin_date ({}) must not be after end_date ({})",
+                begin, end
+            )));
+        }
+
+        Ok(DateRange { begin, end })
+    }
+}
+
+/// Turns one expression into the first and last day it covers.
+///
+/// Accepts `YYYY-MM-DD`, `today`, `yesterday`, `tomorrow`, offsets such as
+/// `+7d`, `-2w`, `last-week`/`this-week`/`next-week`, `this-month` and ISO weeks like `2024-W12`.
+fn parse_expression(input: &str, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), String> {
+    let input = input.trim().to_lowercase();
+
+    let single = |date: NaiveDate| Ok((date, date));
+
+    match input.as_str() {
+        "today" => return single(today),
+        "yesterday" => return single(today - Duration::days(1)),
+        "tomorrow" => return single(today + Duration::days(1)),
+        "this-week" => return Ok(week_of(today)),
+        "last-week" => return Ok(week_of(today - Duration::weeks(1))),
+        "next-week" => return Ok(week_of(today + Duration::weeks(1))),
+        "this-month" => {
+            let first = today.with_day(1).expect("day 1 always exists");
+            let next_month = if first.month() == 12 {
+                NaiveDate::from_ymd_opt(first.year() + 1, 1, 1)
+            } else {
+                NaiveDate::from_ymd_opt(first.year(), first.month() + 1, 1)
+            }
+            .expect("first of a month always exists");
+            return Ok((first, next_month - Duration::days(1)));
+        }
+        _ => {}
+    }
+
+    if input.starts_with('+') || input.starts_with('-') {
+        let not_an_offset = || format!("'{}' is not an offset like +7d or -2w", input);
+        // split on the last character, not byte, since it can be anything the client sent
+        let (unit_at, unit) = input.char_indices().last().ok_or_else(not_an_offset)?;
+        let amount: i64 = input[..unit_at].parse().map_err(|_| not_an_offset())?;
+        // keeps `Duration` and the date arithmetic below from overflowing
+        if amount.abs() > 100_000 {
+            return Err(format!("'{}' is too far away", input));
+        }
+        let offset = match unit {
+            'd' => Duration::days(amount),
+            'w' => Duration::weeks(amount),
+            _ => return Err(format!("unknown unit in '{}', use d or w", input)),
+        };
+        return today
+            .checked_add_signed(offset)
+            .ok_or_else(|| format!("'{}' is too far away", input))
+            .and_then(single);
+    }
+
+    if let Some((year, week)) = input.split_once("-w") {
+        let year: i32 = year
+            .parse()
+            .map_err(|_| format!("'{}' is not an ISO week like 2024-W12", input))?;
+        let week: u32 = week
+            .parse()
+            .map_err(|_| format!("'{}' is not an ISO week like 2024-W12", input))?;
+        let monday = NaiveDate::from_isoywd_opt(year, week, Weekday::Mon)
+            .ok_or_else(|| format!("{} has no ISO week {}", year, week))?;
+        return Ok((monday, monday + Duration::days(6)));
+    }
+
+    NaiveDate::parse_from_str(&input, "%Y-%m-%d")
+        .map(|date| (date, date))
+        .map_err(|err| format!("'{}' is not a date or date expression: {}", input, err))
+}
+
+fn week_of(date: NaiveDate) -> (NaiveDate, NaiveDate) {
+    let monday = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+    (monday, monday + Duration::days(6))
+}
+
+/// Reads the range from the query string, falling back to the JSON body older clients send
+#[async_trait]
+impl<S, B> FromRequest<S, B> for DateRange
+where
+    B: Send + 'static,
+    S: Send + Sync,
+    Json<CreateDateRange>: FromRequest<S, B>,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let (mut parts, body) = req.into_parts();
+
+        let Query(mut raw) = Query::<CreateDateRange>::from_request_parts(&mut parts, state)
+            .await
+            .map_err(|err| AppError::InvalidDateRange(err.to_string()))?;
+
+        if raw.begin_date.is_none() && raw.end_date.is_none() {
+            let req = Request::from_parts(parts, body);
+            if let Ok(Json(body_raw)) = Json::<CreateDateRange>::from_request(req, state).await {
+                raw = body_raw;
+            }
+        }
+
+        DateRange::resolve(&raw, Utc::now().date_naive())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(input: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(input, "%Y-%m-%d").unwrap()
+    }
+
+    fn range(begin: &str, end: Option<&str>) -> Result<DateRange, AppError> {
+        // A Wednesday
+        let today = date("2024-03-20");
+        DateRange::resolve(
+            &CreateDateRange {
+                begin_date: Some(begin.to_string()),
+                end_date: end.map(str::to_string),
+            },
+            today,
+        )
+    }
+
+    #[test]
+    fn resolves_expressions() {
+        assert_eq!(
+            range("2015-09-07", Some("2015-09-08")).unwrap(),
+            DateRange {
+                begin: date("2015-09-07"),
+                end: date("2015-09-08")
+            }
+        );
+        assert_eq!(
+            range("today", Some("+7d")).unwrap(),
+            DateRange {
+                begin: date("2024-03-20"),
+                end: date("2024-03-27")
+            }
+        );
+        assert_eq!(
+            range("this-week", None).unwrap(),
+            DateRange {
+                begin: date("2024-03-18"),
+                end: date("2024-03-24")
+            }
+        );
+        assert_eq!(
+            range("2024-W12", None).unwrap(),
+            DateRange {
+                begin: date("2024-03-18"),
+                end: date("2024-03-24")
+            }
+        );
+        assert_eq!(range("this-month", None).unwrap().end, date("2024-03-31"));
+    }
+
+    #[test]
+    fn rejects_bad_input_with_a_message() {
+        match range("2024-03-21", Some("2024-03-20")) {
+            Err(AppError::InvalidDateRange(message)) => assert!(message.contains("after")),
+            _ => panic!("expected an inverted range to be rejected"),
+        }
+        match range("next-tuesday", None) {
+            Err(AppError::InvalidDateRange(message)) => assert!(message.starts_with("begin_date")),
+            _ => panic!("expected an unknown expression to be rejected"),
+        }
+        assert!(range("2024-W54", None).is_err());
+        assert!(range("+3y", None).is_err());
+        assert!(range("+é", None).is_err());
+        assert!(range("-7日", None).is_err());
+        assert!(range("+", None).is_err());
+        assert!(range("+99999999999999d", None).is_err());
+        assert!(range("-100001w", None).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::neo::Neo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NeoEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl NeoEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NeoEventKind::Created => "created",
+            NeoEventKind::Updated => "updated",
+            NeoEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// Published on the internal broadcast channel whenever an approach is stored or deleted, however that happened
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NeoEvent {
+    pub kind: NeoEventKind,
+    pub neo: Neo,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EventFilter {
+    pub hazardous: Option<bool>,
+    #[serde(default)]
+    pub watchlist: bool,
+}
+
+impl EventFilter {
+    /// `watched` is only consulted when the subscriber asked for watchlist events
+    pub fn accepts(&self, event: &NeoEvent, watched: &HashSet<i32>) -> bool {
+        if let Some(hazardous) = self.hazardous {
+            if event.neo.hazardous_asteroid != hazardous {
+                return false;
+            }
+        }
+
+        !self.watchlist || watched.contains(&event.neo.api_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::neo::NeoId;
+
+    fn event(api_id: i32, hazardous_asteroid: bool) -> NeoEvent {
+        NeoEvent {
+            kind: NeoEventKind::Created,
+            neo: Neo {
+                id: NeoId(1),
+                api_id,
+                designation: "2024 AB".to_string(),
+                diameter_min: 0.1,
+                diameter_max: 0.2,
+                hazardous_asteroid,
+                approach_date: "2024-03-20".to_string(),
+                velocity: 40000.0,
+                miss_distance: 4_000_000.0,
+                orbiting_body: "Earth".to_string(),
+                approach_time: None,
+            },
+        }
+    }
+
+    #[test]
+    fn filters_on_the_hazardous_flag_and_the_watchlist() {
+        let watched = HashSet::from([7]);
+        let everything = EventFilter::default();
+        let hazardous = EventFilter {
+            hazardous: Some(true),
+            watchlist: false,
+        };
+        let harmless_watched = EventFilter {
+            hazardous: Some(false),
+            watchlist: true,
+        };
+
+        assert!(everything.accepts(&event(1, false), &HashSet::new()));
+        assert!(hazardous.accepts(&event(1, true), &watched));
+        assert!(!hazardous.accepts(&event(1, false), &watched));
+        assert!(harmless_watched.accepts(&event(7, false), &watched));
+        assert!(!harmless_watched.accepts(&event(8, false), &watched));
+        assert!(!harmless_watched.accepts(&event(7, true), &watched));
+    }
+}
@@ -1,4 +1,15 @@
+pub mod alert;
+pub mod api_key;
+pub mod audit;
+pub mod ban;
+pub mod comment;
+pub mod date_range;
+pub mod event;
 pub mod neo;
 pub mod neo_id_json;
 pub mod page;
+pub mod role;
+pub mod saved_query;
+pub mod settings;
 pub mod user;
+pub mod watchlist;
@@ -1,3 +1,4 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
 
@@ -26,10 +27,13 @@ pub struct Neo {
     pub velocity: f32,
     pub miss_distance: f32,
     pub orbiting_body: String,
+    /// Exact time of closest approach, when NeoWs gave us one
+    #[serde(default)]
+    pub approach_time: Option<DateTime<Utc>>,
 }
 
 impl Neo {
-    #[allow(dead_code)]
+    #[allow(dead_code, clippy::too_many_arguments)]
     pub fn new(
         id: NeoId,
         api_id: i32,
@@ -41,6 +45,7 @@ impl Neo {
         velocity: f32,
         miss_distance: f32,
         orbiting_body: String,
+        approach_time: Option<DateTime<Utc>>,
     ) -> Self {
         Neo {
             id,
@@ -53,6 +58,49 @@ impl Neo {
             velocity,
             miss_distance,
             orbiting_body,
+            approach_time,
+        }
+    }
+}
+
+/// Raw `neos` row, used when rows are fetched with `query_as` instead of `query!`
+#[derive(Debug, sqlx::FromRow)]
+pub struct NeoRow {
+    pub id: i32,
+    pub api_id: i32,
+    pub designation: String,
+    pub diameter_min: f32,
+    pub diameter_max: f32,
+    pub is_potentially_hazardous_asteroid: bool,
+    pub close_approach_date: NaiveDate,
+    pub relative_velocity: f32,
+    pub miss_distance: f32,
+    pub orbiting_body: String,
+    pub close_approach_at: Option<DateTime<Utc>>,
+}
+
+/// A row together with when we last pulled it from NeoWs
+#[derive(Debug, sqlx::FromRow)]
+pub struct IngestedNeoRow {
+    #[sqlx(flatten)]
+    pub neo: NeoRow,
+    pub ingested_at: DateTime<Utc>,
+}
+
+impl From<NeoRow> for Neo {
+    fn from(row: NeoRow) -> Self {
+        Neo {
+            id: row.id.into(),
+            api_id: row.api_id,
+            designation: row.designation,
+            diameter_min: row.diameter_min,
+            diameter_max: row.diameter_max,
+            hazardous_asteroid: row.is_potentially_hazardous_asteroid,
+            approach_date: row.close_approach_date.to_string(),
+            velocity: row.relative_velocity,
+            miss_distance: row.miss_distance,
+            orbiting_body: row.orbiting_body,
+            approach_time: row.close_approach_at,
         }
     }
 }
@@ -113,21 +161,114 @@ pub struct CreateNeo {
     pub orbiting_body: String,
 }
 
-//make_db_id!(NeoId);
+impl From<CreateNeo> for Neo {
+    /// A record that hasn't been stored yet, so it has no id
+    fn from(neo: CreateNeo) -> Self {
+        Neo {
+            id: NeoId(0),
+            api_id: neo.api_id,
+            designation: neo.designation,
+            diameter_min: neo.diameter_min,
+            diameter_max: neo.diameter_max,
+            hazardous_asteroid: neo.hazardous_asteroid,
+            approach_date: neo.approach_date,
+            velocity: neo.velocity,
+            miss_distance: neo.miss_distance,
+            orbiting_body: neo.orbiting_body,
+            approach_time: None,
+        }
+    }
+}
 
-#[derive(Deserialize)]
-pub struct GetNeoById {
-    pub neo_id: i32,
+/// Partial update, only the fields that are present get changed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateNeo {
+    pub api_id: Option<i32>,
+    pub designation: Option<String>,
+    pub diameter_min: Option<f32>,
+    pub diameter_max: Option<f32>,
+    pub hazardous_asteroid: Option<bool>,
+    pub approach_date: Option<String>,
+    pub velocity: Option<f32>,
+    pub miss_distance: Option<f32>,
+    pub orbiting_body: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct DateRange {
-    pub begin_date: String,
-    pub end_date: String,
+impl UpdateNeo {
+    /// Layers the patch over an existing record
+    pub fn apply(self, neo: Neo) -> CreateNeo {
+        CreateNeo {
+            api_id: self.api_id.unwrap_or(neo.api_id),
+            designation: self.designation.unwrap_or(neo.designation),
+            diameter_min: self.diameter_min.unwrap_or(neo.diameter_min),
+            diameter_max: self.diameter_max.unwrap_or(neo.diameter_max),
+            hazardous_asteroid: self.hazardous_asteroid.unwrap_or(neo.hazardous_asteroid),
+            approach_date: self.approach_date.unwrap_or(neo.approach_date),
+            velocity: self.velocity.unwrap_or(neo.velocity),
+            miss_distance: self.miss_distance.unwrap_or(neo.miss_distance),
+            orbiting_body: self.orbiting_body.unwrap_or(neo.orbiting_body),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
-pub struct CreateDateRange {
-    pub begin_date: String,
-    pub end_date: String,
+impl CreateNeo {
+    /// Checks the fields a client can get wrong before the row reaches the database
+    pub fn validate(&self) -> Result<NaiveDate, String> {
+        if self.designation.trim().is_empty() {
+            return Err("designation must not be empty".to_string());
+        }
+        if self.orbiting_body.trim().is_empty() {
+            return Err("orbiting_body must not be empty".to_string());
+        }
+        if self.diameter_min < 0.0 || self.diameter_max < self.diameter_min {
+            return Err("diameter_min must be >= 0 and <= diameter_max".to_string());
+        }
+        if self.velocity < 0.0 || self.miss_distance < 0.0 {
+            return Err("velocity and miss_distance must be >= 0".to_string());
+        }
+
+        NaiveDate::parse_from_str(&self.approach_date, "%Y-%m-%d")
+            .map_err(|err| format!("approach_date must be YYYY-MM-DD: {}", err))
+    }
+}
+
+/// Query string filters shared by `GET /neos` and the Atom feed
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NeoFilter {
+    pub hazardous: Option<bool>,
+    pub orbiting_body: Option<String>,
+    /// In miles, like `miss_distance` itself
+    pub max_miss_distance: Option<f32>,
+}
+
+impl NeoFilter {
+    /// Same conditions as the SQL version in `db.rs`, for results that never touched the database
+    pub fn matches(&self, neo: &Neo) -> bool {
+        if let Some(hazardous) = self.hazardous {
+            if neo.hazardous_asteroid != hazardous {
+                return false;
+            }
+        }
+
+        if let Some(body) = &self.orbiting_body {
+            if &neo.orbiting_body != body {
+                return false;
+            }
+        }
+
+        if let Some(max) = self.max_miss_distance {
+            if neo.miss_distance > max {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+//make_db_id!(NeoId);
+
+#[derive(Deserialize)]
+pub struct GetNeoById {
+    pub neo_id: i32,
 }
@@ -23,6 +23,7 @@ pub struct MilesDiameter {
 #[derive(Clone, Debug, Deserialize)]
 pub struct ApproachData {
     pub close_approach_date: String,
+    pub epoch_date_close_approach: Option<i64>,
     pub relative_velocity: Velocity,
     pub miss_distance: MissedDistance,
     pub orbiting_body: String,
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The roles seeded by the roles migration. What each one may do lives in `role_permissions`.
+/// They are ordered by rank, a user can only ban users ranked below them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Analyst,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Viewer, Role::Analyst, Role::Moderator, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Analyst => "analyst",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == input.trim().to_lowercase())
+            .ok_or_else(|| {
+                format!(
+                    "unknown role '{}', expected one of viewer, analyst, moderator or admin",
+                    input
+                )
+            })
+    }
+}
+
+/// A row of the `permissions` table, as a type so handlers can ask for it with `RequirePermission<P>`
+pub trait Permission: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($(#[$doc:meta])* $permission:ident => $name:literal),* $(,)?) => {
+        $(
+            $(#[$doc])*
+            pub struct $permission;
+
+            impl Permission for $permission {
+                const NAME: &'static str = $name;
+            }
+        )*
+    };
+}
+
+permissions! {
+    /// View close approaches and use the personal features built on them
+    ReadNeos => "read_neos",
+    /// Create, edit, delete and import close approaches
+    WriteNeos => "write_neos",
+    /// Post, edit and delete your own comments
+    Comment => "comment",
+    /// Delete anyone's comments
+    ModerateComments => "moderate_comments",
+    BanUsers => "ban_users",
+    /// Create admins and change user roles
+    ManageUsers => "manage_users",
+    ViewAuditLog => "view_audit_log",
+}
+
+/// Form body for changing someone's role on the admin page
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRole {
+    pub user_id: i32,
+    pub role: String,
+}
+
+/// What the admin page lists for each account
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserSummary {
+    pub id: i32,
+    pub email: String,
+    pub role: String,
+    pub banned: bool,
+    /// When a timed ban ends
+    pub banned_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_round_trip_through_their_names() {
+        for role in Role::ALL {
+            assert_eq!(role.as_str().parse::<Role>().unwrap(), role);
+        }
+        assert_eq!(" Admin ".parse::<Role>().unwrap(), Role::Admin);
+        assert!("superuser".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn roles_rank_from_viewer_to_admin() {
+        assert!(Role::ALL.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::models::date_range::{CreateDateRange, DateRange};
+use crate::models::neo::NeoFilter;
+
+/// A named search. The dates are kept as typed, so `this-week` means the current week every time it runs
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SavedQuery {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub begin_date: String,
+    pub end_date: Option<String>,
+    pub hazardous: Option<bool>,
+    pub orbiting_body: Option<String>,
+    pub max_miss_distance: Option<f32>,
+    pub share_token: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateSavedQuery {
+    pub name: String,
+    pub begin_date: String,
+    pub end_date: Option<String>,
+    pub hazardous: Option<bool>,
+    pub orbiting_body: Option<String>,
+    pub max_miss_distance: Option<f32>,
+}
+
+/// Identifies a saved query in the dashboard forms
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedQueryId {
+    pub id: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareLink {
+    pub token: String,
+    pub url: String,
+}
+
+impl ShareLink {
+    pub fn new(token: String) -> Self {
+        ShareLink {
+            url: format!("/shared/{}", token),
+            token,
+        }
+    }
+}
+
+impl CreateSavedQuery {
+    /// Trims the input, turns blank form fields into "not set" and checks the dates parse
+    pub fn validate(mut self, today: NaiveDate) -> Result<Self, AppError> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() {
+            return Err(AppError::InvalidQuery("name must not be empty".to_string()));
+        }
+        self.end_date = self.end_date.filter(|end| !end.trim().is_empty());
+        self.orbiting_body = self
+            .orbiting_body
+            .map(|body| body.trim().to_string())
+            .filter(|body| !body.is_empty());
+
+        DateRange::resolve(
+            &CreateDateRange {
+                begin_date: Some(self.begin_date.clone()),
+                end_date: self.end_date.clone(),
+            },
+            today,
+        )?;
+
+        Ok(self)
+    }
+}
+
+impl SavedQuery {
+    /// Resolves the stored date expressions against `today`
+    pub fn date_range(&self, today: NaiveDate) -> Result<DateRange, AppError> {
+        DateRange::resolve(
+            &CreateDateRange {
+                begin_date: Some(self.begin_date.clone()),
+                end_date: self.end_date.clone(),
+            },
+            today,
+        )
+    }
+
+    pub fn filter(&self) -> NeoFilter {
+        NeoFilter {
+            hazardous: self.hazardous,
+            orbiting_body: self.orbiting_body.clone(),
+            max_miss_distance: self.max_miss_distance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(begin_date: &str, end_date: &str) -> CreateSavedQuery {
+        CreateSavedQuery {
+            name: " next week ".to_string(),
+            begin_date: begin_date.to_string(),
+            end_date: Some(end_date.to_string()),
+            hazardous: Some(true),
+            orbiting_body: Some("".to_string()),
+            max_miss_distance: None,
+        }
+    }
+
+    #[test]
+    fn relative_window_moves_with_today() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+        let create = create("today", "+7d").validate(today).unwrap();
+        assert_eq!(create.name, "next week");
+        assert_eq!(create.orbiting_body, None);
+
+        let query = SavedQuery {
+            id: 1,
+            user_id: 1,
+            name: create.name,
+            begin_date: create.begin_date,
+            end_date: create.end_date,
+            hazardous: create.hazardous,
+            orbiting_body: create.orbiting_body,
+            max_miss_distance: create.max_miss_distance,
+            share_token: None,
+            created_at: Utc::now(),
+        };
+
+        let later = NaiveDate::from_ymd_opt(2024, 4, 1).unwrap();
+        assert_eq!(query.date_range(later).unwrap().begin, later);
+        assert_eq!(
+            query.date_range(later).unwrap().end,
+            NaiveDate::from_ymd_opt(2024, 4, 8).unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_dates_are_rejected_when_saving() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 20).unwrap();
+        assert!(create("someday", "").validate(today).is_err());
+        assert!(create("+7d", "today").validate(today).is_err());
+    }
+}
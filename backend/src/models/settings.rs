@@ -0,0 +1,114 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::alert::AlertRule;
+use crate::models::api_key::ApiKey;
+use crate::models::saved_query::SavedQuery;
+
+/// The settings page's form for a new password
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePassword {
+    /// Left empty after confirming through single sign-on
+    #[serde(default)]
+    pub current_password: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+/// The settings page's form for deleting the account, the password or signing in again confirms it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteAccount {
+    #[serde(default)]
+    pub password: String,
+}
+
+/// The account itself, as shown on the settings page and exported
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Profile {
+    pub email: String,
+    pub role: String,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
+    /// Issuer of the single sign-on identity the account is linked to
+    pub oidc_issuer: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WatchedAsteroid {
+    pub api_id: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// One of the user's own comments, deleted ones included with whatever is left of them
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OwnComment {
+    pub id: i32,
+    pub api_id: i32,
+    pub parent_id: Option<i32>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Everything stored about a user that they can have a copy of.
+/// Secrets like the password hash, 2FA secret and API key hashes are left out
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    pub exported_at: DateTime<Utc>,
+    pub profile: Profile,
+    pub watchlist: Vec<WatchedAsteroid>,
+    pub saved_queries: Vec<SavedQuery>,
+    pub comments: Vec<OwnComment>,
+    pub alert_rules: Vec<AlertRule>,
+    pub api_keys: Vec<ApiKey>,
+}
+
+/// Name the export is downloaded under
+pub fn export_filename(day: NaiveDate) -> String {
+    format!("earths-close-calls-data-{}.json", day.format("%Y-%m-%d"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_are_named_after_the_day() {
+        assert_eq!(
+            export_filename(NaiveDate::from_ymd_opt(2024, 3, 20).unwrap()),
+            "earths-close-calls-data-2024-03-20.json"
+        );
+    }
+
+    #[test]
+    fn exports_have_a_section_per_kind_of_data() {
+        let export = AccountExport {
+            exported_at: Utc::now(),
+            profile: Profile {
+                email: "user@example.com".to_string(),
+                role: "viewer".to_string(),
+                email_verified: true,
+                two_factor_enabled: false,
+                oidc_issuer: None,
+            },
+            watchlist: vec![],
+            saved_queries: vec![],
+            comments: vec![],
+            alert_rules: vec![],
+            api_keys: vec![],
+        };
+
+        let json = serde_json::to_value(&export).unwrap();
+        assert_eq!(json["profile"]["email"], "user@example.com");
+        for section in [
+            "watchlist",
+            "saved_queries",
+            "comments",
+            "alert_rules",
+            "api_keys",
+        ] {
+            assert!(json[section].is_array(), "{} is missing", section);
+        }
+    }
+}
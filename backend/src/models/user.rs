@@ -1,12 +1,18 @@
 use axum::async_trait;
 use axum::extract::FromRequestParts;
-use cookie::Cookie;
+use http::header::AUTHORIZATION;
 use http::request::Parts;
-use jsonwebtoken::{decode, DecodingKey, EncodingKey, Validation};
+use jsonwebtoken::{DecodingKey, EncodingKey};
 use once_cell::sync::Lazy;
 use std::convert::Infallible;
+use std::marker::PhantomData;
 
+use crate::db::Store;
 use crate::error::AppError;
+use crate::models::api_key::{ApiKeyAuth, ApiKeyUser};
+use crate::models::ban::Ban;
+use crate::models::role::{Permission, Role};
+use crate::session;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
@@ -22,84 +28,208 @@ pub struct UserSignup {
     pub confirm_password: String,
 }
 
+#[allow(dead_code)]
 pub struct LoggedInUser {
     pub token: Claims,
     pub admin: bool,
 }
 
-#[derive(Serialize, Deserialize, derive_more::Display)]
-#[display(fmt = "id: {}, email: {}, exp: {}", id, email, exp)]
+#[derive(Clone, Serialize, Deserialize, derive_more::Display)]
+#[display(
+    fmt = "id: {}, email: {}, role: {}, banned: {}, token_version: {}, exp: {}, jti: {}",
+    id,
+    email,
+    role,
+    banned,
+    token_version,
+    exp,
+    jti
+)]
 pub struct Claims {
     pub id: i32,
     pub email: String,
+    /// What the user may do, see [`RequirePermission`]
+    pub role: Role,
+    /// The user was banned when the token was issued. Banning and unbanning bump `token_version`,
+    /// so this only goes stale when a timed ban runs out
+    pub banned: bool,
+    /// Has to match `users.token_version`, bumping that invalidates every token issued before
+    pub token_version: i32,
     pub exp: u64,
+    /// Unique id of the token, what logging out revokes
+    pub jti: String,
 }
 
+/// What the email verification and password reset flows need to know about a user
+#[derive(Debug, sqlx::FromRow)]
+pub struct Account {
+    pub id: i32,
+    pub email: String,
+    pub password: String,
+    pub email_verified: bool,
+}
+
+/// Form asking for a verification or reset email to be sent
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailForm {
+    pub email: String,
+}
+
+/// The token from a link in a verification or reset email. Blank when the page is opened directly
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenQuery {
+    #[serde(default)]
+    pub token: String,
+}
+
+/// The form behind a password reset link
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+/// What an access token is issued from
+#[derive(Debug, sqlx::FromRow)]
+pub struct SessionUser {
+    pub id: i32,
+    pub email: String,
+    pub role: String,
+    pub token_version: i32,
+    pub two_factor_enabled: bool,
+    pub banned: bool,
+}
+
+/// Where a user stands with two-factor authentication
+#[derive(Debug, sqlx::FromRow)]
+pub struct TwoFactorUser {
+    pub id: i32,
+    pub email: String,
+    pub role: String,
+    /// Set as soon as setup starts, see `totp_enabled`
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+    pub recovery_codes_left: i64,
+}
+
+/// A code from an authenticator app, or a recovery code
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CodeForm {
+    pub code: String,
+}
+
+/// The admin page's form lifting a lockout after failed logins
 #[derive(Serialize, Deserialize)]
-pub struct GetUserByEmail {
+pub struct UnlockForm {
     pub email: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Claims {
+    /// The ban keeping the user out, only looked up for tokens issued during one
+    pub async fn active_ban(&self, store: &Store) -> Result<Option<Ban>, AppError> {
+        if !self.banned {
+            return Ok(None);
+        }
+        store.get_active_ban(self.id).await
+    }
+}
+
+/// Claims of whoever the login cookie belongs to, banned or not
+async fn session_claims(parts: &Parts, state: &Store) -> Result<Claims, AppError> {
+    // The session layer already checked the token against the database for this request
+    if let Some(claims) = parts.extensions.get::<Claims>() {
+        return Ok(claims.clone());
+    }
+
+    //extract a token claims from our jwt cookie
+    let jwt_token = session::cookie_value(&parts.headers, session::ACCESS_COOKIE)
+        .ok_or(AppError::InvalidToken)?;
+
+    let claims = session::decode_access_token(&jwt_token)?;
+    // Logged out and outdated tokens are still validly signed until they expire
+    if !state.is_token_current(&claims).await? {
+        return Err(AppError::InvalidToken);
+    }
+
+    Ok(claims)
 }
 
+/// Claims of a logged in user who isn't banned. Banned users are turned away with 403 and their ban notice
 #[async_trait]
-impl<S> FromRequestParts<S> for Claims
-where
-    S: Send + Sync,
-{
+impl FromRequestParts<Store> for Claims {
     type Rejection = AppError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        //extract a token claims from our Authorization header
-        let jwt_token = parts
-            .headers
-            .get("cookie")
-            .and_then(|value| Cookie::parse(value.to_str().unwrap_or_default()).ok())
-            .and_then(|cookie| {
-                if cookie.name() == "jwt" {
-                    Some(cookie.value().to_string())
-                } else {
-                    None
-                }
-            })
-            .ok_or(AppError::InvalidToken)?;
-
-        let token_data = decode::<Claims>(&jwt_token, &KEYS.decoding, &Validation::default())
-            .map_err(|_| AppError::InvalidToken)?;
-
-        Ok(token_data.claims)
+    async fn from_request_parts(parts: &mut Parts, state: &Store) -> Result<Self, Self::Rejection> {
+        let claims = session_claims(parts, state).await?;
+        if let Some(ban) = claims.active_ban(state).await? {
+            return Err(AppError::Banned(Box::new(ban)));
+        }
+
+        Ok(claims)
     }
 }
 
-pub struct OptionalClaims(pub Option<Claims>);
+/// Claims of a logged in, non-banned user, from an API key when the request has an `Authorization`
+/// header and from the login cookie otherwise. The key comes along so its scopes can be checked,
+/// routes that need a permission should use [`RequirePermission`], which does that
+pub struct ApiClaims(pub Claims, pub Option<ApiKeyUser>);
 
 #[async_trait]
-impl<S> FromRequestParts<S> for OptionalClaims
-where
-    S: Send + Sync,
-{
-    type Rejection = Infallible; // Use Infallible since we're not rejecting the request
+impl FromRequestParts<Store> for ApiClaims {
+    type Rejection = AppError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Try extracting the JWT token from the "jwt" cookie
-        let jwt_token = parts
-            .headers
-            .get("cookie")
-            .and_then(|value| Cookie::parse(value.to_str().unwrap_or_default()).ok())
-            .and_then(|cookie| {
-                if cookie.name() == "jwt" {
-                    Some(cookie.value().to_string())
-                } else {
-                    None
-                }
-            });
-
-        // If we have a JWT token, try to decode it
-        if let Some(jwt) = jwt_token {
-            if let Ok(token_data) = decode::<Claims>(&jwt, &KEYS.decoding, &Validation::default()) {
-                return Ok(OptionalClaims(Some(token_data.claims)));
-            }
+    async fn from_request_parts(parts: &mut Parts, state: &Store) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(AUTHORIZATION) {
+            let ApiKeyAuth(key) = ApiKeyAuth::from_request_parts(parts, state).await?;
+            return Ok(ApiClaims(key.claims()?, Some(key)));
         }
 
-        Ok(OptionalClaims(None))
+        Ok(ApiClaims(
+            Claims::from_request_parts(parts, state).await?,
+            None,
+        ))
+    }
+}
+
+/// Claims of a logged in, non-banned user whose role grants `P`, going by the role in the token.
+/// Rejects everyone else with 403.
+///
+/// Requests with an `Authorization` header are authenticated by API key instead of the login cookie,
+/// and the key's scopes have to cover `P` as well.
+pub struct RequirePermission<P: Permission>(pub Claims, pub PhantomData<P>);
+
+#[async_trait]
+impl<P: Permission> FromRequestParts<Store> for RequirePermission<P> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Store) -> Result<Self, Self::Rejection> {
+        let ApiClaims(claims, key) = ApiClaims::from_request_parts(parts, state).await?;
+
+        if key.is_some_and(|key| !key.allows(P::NAME))
+            || !state.role_allows(claims.role, P::NAME).await?
+        {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(RequirePermission(claims, PhantomData))
+    }
+}
+
+/// Claims of the logged in user if there is one. Banned users are included, so pages have to check
+/// for a ban themselves and can tell the user about it
+pub struct OptionalClaims(pub Option<Claims>);
+
+#[async_trait]
+impl FromRequestParts<Store> for OptionalClaims {
+    type Rejection = Infallible; // Use Infallible since we're not rejecting the request
+
+    async fn from_request_parts(parts: &mut Parts, state: &Store) -> Result<Self, Self::Rejection> {
+        // Anything wrong with the token, including it being revoked, just means nobody is logged in
+        Ok(OptionalClaims(session_claims(parts, state).await.ok()))
     }
 }
 
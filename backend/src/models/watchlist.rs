@@ -0,0 +1,41 @@
+use chrono::NaiveDate;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddWatch {
+    pub api_id: i32,
+}
+
+/// A watched asteroid joined with its next stored approach, if there is one
+#[derive(Debug, sqlx::FromRow)]
+pub struct WatchlistRow {
+    pub api_id: i32,
+    pub designation: Option<String>,
+    pub close_approach_date: Option<NaiveDate>,
+    pub miss_distance: Option<f32>,
+    pub relative_velocity: Option<f32>,
+    pub is_potentially_hazardous_asteroid: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WatchlistEntry {
+    pub api_id: i32,
+    pub designation: Option<String>,
+    pub next_approach: Option<String>,
+    pub miss_distance: Option<f32>,
+    pub velocity: Option<f32>,
+    pub hazardous_asteroid: Option<bool>,
+}
+
+impl From<WatchlistRow> for WatchlistEntry {
+    fn from(row: WatchlistRow) -> Self {
+        WatchlistEntry {
+            api_id: row.api_id,
+            designation: row.designation,
+            next_approach: row.close_approach_date.map(|date| date.to_string()),
+            miss_distance: row.miss_distance,
+            velocity: row.relative_velocity,
+            hazardous_asteroid: row.is_potentially_hazardous_asteroid,
+        }
+    }
+}
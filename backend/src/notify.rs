@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use sqlx::PgPool;
+use tracing::info;
+
+use crate::error::AppError;
+use crate::mailer::{Email, Mailer, QueuedMailer, SmtpMailer};
+use crate::models::alert::{AlertRule, MILES_PER_AU};
+use crate::models::neo::Neo;
+
+/// Somewhere a triggered alert can be delivered to
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(
+        &self,
+        alert_id: i32,
+        email: &str,
+        rule: &AlertRule,
+        neo: &Neo,
+    ) -> Result<(), AppError>;
+}
+
+/// Every notifier that is configured. The in-app inbox is always on, email only when `SMTP_HOST` is set
+pub fn notifiers_from_env(pool: PgPool) -> Vec<Arc<dyn Notifier>> {
+    let mut notifiers: Vec<Arc<dyn Notifier>> = vec![Arc::new(InboxNotifier { pool })];
+
+    match EmailNotifier::from_env() {
+        Ok(Some(smtp)) => notifiers.push(Arc::new(smtp)),
+        Ok(None) => info!("SMTP_HOST not set, alert emails are disabled"),
+        Err(err) => tracing::error!("Could not configure SMTP alerts: {:?}", err),
+    }
+
+    notifiers
+}
+
+pub fn alert_subject(rule: &AlertRule, neo: &Neo) -> String {
+    format!(
+        "[{}] {} approaches on {}",
+        rule.name, neo.designation, neo.approach_date
+    )
+}
+
+pub fn alert_body(rule: &AlertRule, neo: &Neo) -> String {
+    format!(
+        "Your alert rule \"{}\" matched a close approach.\n\n\
+         Object: {} (NASA id {})\n\
+         Approach date: {}\n\
+         Miss distance: {} miles ({:.4} AU)\n\
+         Velocity: {} mph\n\
+         Potentially hazardous: {}\n",
+        rule.name,
+        neo.designation,
+        neo.api_id,
+        neo.approach_date,
+        neo.miss_distance,
+        neo.miss_distance as f64 / MILES_PER_AU,
+        neo.velocity,
+        neo.hazardous_asteroid,
+    )
+}
+
+/// Writes alerts into the user's in-app inbox
+pub struct InboxNotifier {
+    pool: PgPool,
+}
+
+#[async_trait]
+impl Notifier for InboxNotifier {
+    async fn notify(
+        &self,
+        alert_id: i32,
+        _email: &str,
+        rule: &AlertRule,
+        neo: &Neo,
+    ) -> Result<(), AppError> {
+        sqlx::query!(
+            "INSERT INTO inbox_messages(user_id, alert_id, subject, body) VALUES ($1, $2, $3, $4)",
+            rule.user_id,
+            alert_id,
+            alert_subject(rule, neo),
+            alert_body(rule, neo),
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Emails alerts to the rule's owner
+pub struct EmailNotifier {
+    mailer: Arc<dyn Mailer>,
+}
+
+impl EmailNotifier {
+    pub fn new(mailer: Arc<dyn Mailer>) -> Self {
+        EmailNotifier { mailer }
+    }
+
+    /// Sends through SMTP, see [`SmtpMailer::from_env`] for the settings. The emails are queued,
+    /// so evaluating alerts doesn't wait on the mail server
+    pub fn from_env() -> Result<Option<Self>, AppError> {
+        Ok(SmtpMailer::from_env()?
+            .map(|smtp| EmailNotifier::new(Arc::new(QueuedMailer::spawn(Arc::new(smtp))))))
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    async fn notify(
+        &self,
+        _alert_id: i32,
+        email: &str,
+        rule: &AlertRule,
+        neo: &Neo,
+    ) -> Result<(), AppError> {
+        self.mailer
+            .send(&Email {
+                to: email.to_string(),
+                subject: alert_subject(rule, neo),
+                body: alert_body(rule, neo),
+            })
+            .await
+    }
+}
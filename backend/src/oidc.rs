@@ -0,0 +1,618 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use data_encoding::BASE64URL_NOPAD;
+use http::{HeaderMap, HeaderValue};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, Header, Validation};
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::error::AppError;
+use crate::models::role::Role;
+use crate::models::user::KEYS;
+use crate::session::{cookie_header, cookie_value};
+use crate::{generate_secret_token, get_timestamp_after};
+
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../examples/mock_oidc/main.rs"]
+mod mock_oidc;
+
+/// Cookie carrying the state, nonce and PKCE verifier while the user is at the identity provider
+pub const LOGIN_COOKIE: &str = "oidc_login";
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long the provider's discovery document and signing keys are used before they are fetched again
+const PROVIDER_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Audience of the login cookie's token, so it's never taken for an access token
+const LOGIN_AUDIENCE: &str = "oidc_login";
+
+/// Cookie proving the user just signed in at the provider again, see [`reauth_cookie`]
+pub const REAUTH_COOKIE: &str = "oidc_reauth";
+const REAUTH_TTL: Duration = Duration::from_secs(5 * 60);
+const REAUTH_AUDIENCE: &str = "oidc_reauth";
+
+/// ID tokens signed with the client secret (HS256) aren't accepted, only the provider's published keys
+const ID_TOKEN_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+/// The identity provider we let users sign in with
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    /// Confidential clients only, PKCE is used either way
+    client_secret: Option<String>,
+    /// Defaults to `/auth/oidc/callback` under `PUBLIC_URL`
+    redirect_url: Option<String>,
+    /// Role of accounts created on their first single sign-on
+    pub default_role: Role,
+    /// See [`OidcConfig::provider`]
+    provider: Mutex<Option<Arc<Provider>>>,
+}
+
+impl OidcConfig {
+    /// What the provider publishes about itself, fetched on the first login and again once it's
+    /// [`PROVIDER_TTL`] old
+    pub async fn provider(&self) -> Result<Arc<Provider>, AppError> {
+        // Held while fetching, so logins arriving meanwhile wait for this fetch instead of starting their own
+        let mut cached = self.provider.lock().await;
+        if let Some(provider) = cached.as_ref() {
+            if provider.fetched_at.elapsed() < PROVIDER_TTL {
+                return Ok(provider.clone());
+            }
+        }
+
+        let provider = Arc::new(fetch_provider(self).await?);
+        *cached = Some(provider.clone());
+        Ok(provider)
+    }
+
+    /// Fetches the provider again before the cached copy expires, for when it signs with a key we don't know
+    async fn refresh_provider(&self) -> Result<Arc<Provider>, AppError> {
+        self.provider.lock().await.take();
+        self.provider().await
+    }
+
+    pub fn redirect_url(&self, public_url: &str) -> String {
+        self.redirect_url
+            .clone()
+            .unwrap_or_else(|| format!("{}/auth/oidc/callback", public_url.trim_end_matches('/')))
+    }
+}
+
+/// Single sign-on is on when `OIDC_ISSUER` and `OIDC_CLIENT_ID` are set
+pub fn config_from_env() -> Option<Arc<OidcConfig>> {
+    let (issuer, client_id) = match (
+        std::env::var("OIDC_ISSUER"),
+        std::env::var("OIDC_CLIENT_ID"),
+    ) {
+        (Ok(issuer), Ok(client_id)) => (issuer, client_id),
+        _ => return None,
+    };
+
+    let default_role = match std::env::var("OIDC_DEFAULT_ROLE") {
+        Ok(role) => match role.parse::<Role>() {
+            Ok(role) => role,
+            Err(err) => {
+                error!(
+                    "Single sign-on is disabled, OIDC_DEFAULT_ROLE is invalid: {}",
+                    err
+                );
+                return None;
+            }
+        },
+        Err(_) => Role::Viewer,
+    };
+
+    info!("Single sign-on through {}", issuer);
+    Some(Arc::new(OidcConfig {
+        issuer: issuer.trim_end_matches('/').to_string(),
+        client_id,
+        client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+        redirect_url: std::env::var("OIDC_REDIRECT_URL").ok(),
+        default_role,
+        provider: Mutex::default(),
+    }))
+}
+
+/// The parts of the provider's `/.well-known/openid-configuration` the login needs
+#[derive(Debug, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// The provider's discovery document and the signing keys it points to
+pub struct Provider {
+    pub discovery: Discovery,
+    pub keys: JwkSet,
+    fetched_at: Instant,
+}
+
+async fn fetch_provider(config: &OidcConfig) -> Result<Provider, AppError> {
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let discovery: Discovery = reqwest::get(url).await?.error_for_status()?.json().await?;
+
+    if discovery.issuer.trim_end_matches('/') != config.issuer {
+        return Err(AppError::SingleSignOn(format!(
+            "the provider calls itself {}, not {}",
+            discovery.issuer, config.issuer
+        )));
+    }
+
+    let keys: JwkSet = reqwest::get(&discovery.jwks_uri)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(Provider {
+        discovery,
+        keys,
+        fetched_at: Instant::now(),
+    })
+}
+
+/// RFC 7636 `S256` code challenge for `verifier`
+pub fn pkce_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()))
+}
+
+/// What the callback checks the provider's answer against
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginState {
+    pub state: String,
+    pub nonce: String,
+    pub verifier: String,
+    /// Set when a logged in user is confirming who they are rather than logging in
+    pub reauth_user: Option<i32>,
+    aud: String,
+    exp: u64,
+}
+
+/// `/auth/oidc/login?reauth=true` confirms the logged in user instead of logging someone in
+#[derive(Debug, Default, Deserialize)]
+pub struct LoginQuery {
+    #[serde(default)]
+    pub reauth: bool,
+}
+
+/// The provider's authorization URL to send the user to, and the cookie to remember the login by.
+/// With `reauth_user` the provider is asked to make the user sign in again even if they have a session there
+pub fn start_login(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    redirect_url: &str,
+    reauth_user: Option<i32>,
+) -> Result<(String, HeaderValue), AppError> {
+    let login = LoginState {
+        state: generate_secret_token(),
+        nonce: generate_secret_token(),
+        // 80 characters, RFC 7636 wants 43 to 128
+        verifier: format!("{}{}", generate_secret_token(), generate_secret_token()),
+        reauth_user,
+        aud: LOGIN_AUDIENCE.to_string(),
+        exp: get_timestamp_after(LOGIN_TTL),
+    };
+
+    let challenge = pkce_challenge(&login.verifier);
+    let mut params = vec![
+        ("response_type", "code"),
+        ("client_id", &config.client_id),
+        ("redirect_uri", redirect_url),
+        ("scope", "openid email"),
+        ("state", &login.state),
+        ("nonce", &login.nonce),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+    ];
+    if reauth_user.is_some() {
+        params.extend([("prompt", "login"), ("max_age", "0")]);
+    }
+
+    let url = Url::parse_with_params(&discovery.authorization_endpoint, params)
+        .map_err(|err| AppError::SingleSignOn(format!("bad authorization endpoint: {}", err)))?;
+
+    let token = encode(&Header::default(), &login, &KEYS.encoding)
+        .map_err(|_| AppError::InternalServerError)?;
+    Ok((
+        url.to_string(),
+        cookie_header(LOGIN_COOKIE, &token, LOGIN_TTL),
+    ))
+}
+
+/// The login started by [`start_login`] in this browser
+pub fn login_state(headers: &HeaderMap) -> Result<LoginState, AppError> {
+    let token = cookie_value(headers, LOGIN_COOKIE).ok_or_else(|| {
+        AppError::SingleSignOn("the login took too long or was started elsewhere".to_string())
+    })?;
+    let mut validation = Validation::default();
+    validation.set_audience(&[LOGIN_AUDIENCE]);
+
+    decode::<LoginState>(&token, &KEYS.decoding, &validation)
+        .map(|data| data.claims)
+        .map_err(|_| AppError::SingleSignOn("the login took too long".to_string()))
+}
+
+pub fn cleared_login_cookie() -> HeaderValue {
+    cookie_header(LOGIN_COOKIE, "", Duration::ZERO)
+}
+
+/// What the re-authentication cookie's token says
+#[derive(Debug, Serialize, Deserialize)]
+struct Reauthenticated {
+    sub: i32,
+    aud: String,
+    exp: u64,
+}
+
+/// Cookie standing in for the password on the settings page for a few minutes, once `user_id`
+/// signed in at the provider again. Accounts created by single sign-on have no password they know
+pub fn reauth_cookie(user_id: i32) -> Result<HeaderValue, AppError> {
+    let claims = Reauthenticated {
+        sub: user_id,
+        aud: REAUTH_AUDIENCE.to_string(),
+        exp: get_timestamp_after(REAUTH_TTL),
+    };
+    let token = encode(&Header::default(), &claims, &KEYS.encoding)
+        .map_err(|_| AppError::InternalServerError)?;
+    Ok(cookie_header(REAUTH_COOKIE, &token, REAUTH_TTL))
+}
+
+/// Whether `user_id` signed in at the provider again within the last few minutes
+pub fn reauthenticated(headers: &HeaderMap, user_id: i32) -> bool {
+    let Some(token) = cookie_value(headers, REAUTH_COOKIE) else {
+        return false;
+    };
+    let mut validation = Validation::default();
+    validation.set_audience(&[REAUTH_AUDIENCE]);
+
+    decode::<Reauthenticated>(&token, &KEYS.decoding, &validation)
+        .map(|data| data.claims.sub == user_id)
+        .unwrap_or(false)
+}
+
+pub fn cleared_reauth_cookie() -> HeaderValue {
+    cookie_header(REAUTH_COOKIE, "", Duration::ZERO)
+}
+
+/// Where the provider sends the user back to, with a code or an error
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims of a validated ID token we use. `iss`, `aud` and `exp` are checked while decoding
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    nonce: Option<String>,
+}
+
+/// Trades the authorization code for an ID token and validates it
+pub async fn exchange_code(
+    config: &OidcConfig,
+    provider: &Provider,
+    login: &LoginState,
+    code: &str,
+    redirect_url: &str,
+) -> Result<IdTokenClaims, AppError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_url),
+        ("client_id", &config.client_id),
+        ("code_verifier", &login.verifier),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(&provider.discovery.token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(AppError::SingleSignOn(format!(
+            "the provider turned the code down: {}",
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    let tokens: TokenResponse = response.json().await?;
+
+    let refreshed;
+    let keys = if knows_signing_key(&tokens.id_token, &provider.keys) {
+        &provider.keys
+    } else {
+        // The provider may have rotated its keys since we fetched them
+        refreshed = config.refresh_provider().await?;
+        &refreshed.keys
+    };
+
+    validate_id_token(
+        &tokens.id_token,
+        keys,
+        &provider.discovery.issuer,
+        &config.client_id,
+        &login.nonce,
+    )
+}
+
+/// Whether the key the token names is in `keys`. Tokens that don't name one are left to [`validate_id_token`]
+fn knows_signing_key(id_token: &str, keys: &JwkSet) -> bool {
+    match decode_header(id_token).map(|header| header.kid) {
+        Ok(Some(kid)) => keys.find(&kid).is_some(),
+        _ => true,
+    }
+}
+
+/// Checks the ID token's signature against the provider's keys, its issuer, audience, expiry and nonce
+pub fn validate_id_token(
+    id_token: &str,
+    keys: &JwkSet,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, AppError> {
+    let invalid = |reason: &str| AppError::SingleSignOn(format!("invalid ID token, {}", reason));
+
+    let header = decode_header(id_token).map_err(|_| invalid("unreadable header"))?;
+    if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+        return Err(invalid("unsupported algorithm"));
+    }
+    let jwk = match &header.kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| invalid("unknown signing key"))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| invalid("unusable signing key"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[client_id]);
+    validation.set_issuer(&[issuer]);
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|err| invalid(&err.to_string()))?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(invalid("nonce doesn't match"));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_store;
+    use crate::db::Store;
+    use crate::{handlers, session};
+    use axum::body::Body;
+    use axum::response::Response;
+    use axum::routing::get;
+    use axum::Router;
+    use http::header::{COOKIE, LOCATION, SET_COOKIE};
+    use http::Request;
+    use jsonwebtoken::EncodingKey;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    /// The mock provider's signing key, see `examples/mock_oidc`
+    const MOCK_KEY: &str = include_str!("../examples/mock_oidc/key.pem");
+    const MOCK_JWKS: &str = include_str!("../examples/mock_oidc/jwks.json");
+
+    fn id_token(claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("mock".to_string());
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(MOCK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn claims(nonce: &str) -> serde_json::Value {
+        json!({
+            "iss": "http://127.0.0.1:4000",
+            "aud": "ecc",
+            "sub": "mock-1",
+            "email": "employee@example.com",
+            "email_verified": true,
+            "nonce": nonce,
+            "exp": get_timestamp_after(Duration::from_secs(60)),
+        })
+    }
+
+    #[test]
+    fn pkce_challenges_are_base64url_sha256() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mJ92K9TPlRq2W9kQ3Sl0k8V8VNm3Ro"),
+            "6K83JbdiaRbcwfEjxY4CVhEz5V1n9rRSaba5JBPtDvY"
+        );
+    }
+
+    #[test]
+    fn id_tokens_are_checked_against_the_provider() {
+        let keys: JwkSet = serde_json::from_str(MOCK_JWKS).unwrap();
+        let issuer = "http://127.0.0.1:4000";
+
+        let validated =
+            validate_id_token(&id_token(claims("n1")), &keys, issuer, "ecc", "n1").unwrap();
+        assert_eq!(validated.sub, "mock-1");
+        assert_eq!(validated.email.as_deref(), Some("employee@example.com"));
+        assert_eq!(validated.email_verified, Some(true));
+
+        let token = id_token(claims("n1"));
+        assert!(validate_id_token(&token, &keys, issuer, "ecc", "n2").is_err());
+        assert!(validate_id_token(&token, &keys, issuer, "other-client", "n1").is_err());
+        assert!(validate_id_token(&token, &keys, "http://evil", "ecc", "n1").is_err());
+    }
+
+    #[test]
+    fn tokens_signed_with_a_shared_secret_are_refused() {
+        std::env::set_var("JWT_SECRET", "test secret");
+        let keys: JwkSet = serde_json::from_str(MOCK_JWKS).unwrap();
+        let token = encode(&Header::default(), &claims("n1"), &KEYS.encoding).unwrap();
+
+        assert!(validate_id_token(&token, &keys, "http://127.0.0.1:4000", "ecc", "n1").is_err());
+    }
+
+    #[test]
+    fn reauth_cookies_only_confirm_their_own_user() {
+        std::env::set_var("JWT_SECRET", "test secret");
+        let cookie = reauth_cookie(7).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(cookie.to_str().unwrap().split(';').next().unwrap()).unwrap(),
+        );
+
+        assert!(reauthenticated(&headers, 7));
+        assert!(!reauthenticated(&headers, 8));
+        assert!(!reauthenticated(&HeaderMap::new(), 7));
+    }
+
+    fn location(response: &Response) -> String {
+        response.headers()[LOCATION].to_str().unwrap().to_string()
+    }
+
+    fn get_request(uri: &str, cookie: Option<&str>) -> Request<Body> {
+        let mut request = Request::get(uri);
+        if let Some(cookie) = cookie {
+            request = request.header(COOKIE, cookie);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    /// Starts a login on `app`, lets the mock provider at `issuer` answer it with `provider_params`
+    /// added, and returns what the callback made of that
+    async fn sign_in(app: &Router, issuer: &str, provider_params: &str) -> Response {
+        let started = app
+            .clone()
+            .oneshot(get_request("/auth/oidc/login", None))
+            .await
+            .unwrap();
+        let authorize = location(&started);
+        assert!(authorize.starts_with(&format!("{}/authorize?", issuer)));
+        let login_cookie = started.headers()[SET_COOKIE].to_str().unwrap();
+        let login_cookie = login_cookie.split(';').next().unwrap().to_string();
+
+        // with a login_hint the mock signs in straight away
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let answered = client
+            .get(format!("{}&{}", authorize, provider_params))
+            .send()
+            .await
+            .unwrap();
+        let callback = Url::parse(answered.headers()[LOCATION].to_str().unwrap()).unwrap();
+        assert_eq!(callback.path(), "/auth/oidc/callback");
+
+        app.clone()
+            .oneshot(get_request(
+                &format!("{}?{}", callback.path(), callback.query().unwrap()),
+                Some(&login_cookie),
+            ))
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    async fn logging_in_through_the_mock_provider(pool: PgPool) {
+        std::env::set_var("JWT_SECRET", "test secret");
+        std::env::set_var("PUBLIC_URL", "http://localhost:3000");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let mock = tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(mock_oidc::app(issuer.clone()).into_make_service()),
+        );
+
+        let config = Arc::new(OidcConfig {
+            issuer: issuer.clone(),
+            client_id: "ecc".to_string(),
+            client_secret: None,
+            redirect_url: None,
+            default_role: Role::Viewer,
+            provider: Mutex::default(),
+        });
+        let store = Store {
+            oidc: Some(config),
+            ..test_store(pool.clone(), vec![])
+        };
+        let app = Router::new()
+            .route("/auth/oidc/login", get(handlers::oidc_login))
+            .route("/auth/oidc/callback", get(handlers::oidc_callback))
+            .with_state(store);
+
+        let finished = sign_in(&app, &issuer, "login_hint=employee@example.com").await;
+        assert_eq!(location(&finished), "/");
+        assert!(finished
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .any(|cookie| cookie.to_str().unwrap().starts_with(session::ACCESS_COOKIE)));
+        let (role, oidc_issuer): (String, String) = sqlx::query_as(
+            "SELECT role, oidc_issuer FROM users WHERE email = 'employee@example.com'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(role, "viewer");
+        assert_eq!(oidc_issuer, issuer);
+
+        // nobody gets an account for an address the provider hasn't verified
+        let refused = sign_in(
+            &app,
+            &issuer,
+            "login_hint=intruder@example.com&email_verified=false",
+        )
+        .await;
+        assert_eq!(refused.status(), http::StatusCode::UNAUTHORIZED);
+        let intruders: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE email = 'intruder@example.com'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(intruders, 0);
+
+        // discovery is cached, so logins still start while the provider can't be reached
+        mock.abort();
+        let _ = mock.await;
+        let restarted = app
+            .oneshot(get_request("/auth/oidc/login", None))
+            .await
+            .unwrap();
+        assert!(location(&restarted).starts_with(&issuer));
+    }
+}
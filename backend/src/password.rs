@@ -0,0 +1,100 @@
+use argon2::{Config, Variant, Version};
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::error::AppError;
+
+/// Bytes of random salt generated for every hash
+const SALT_LENGTH: usize = 16;
+
+/// Argon2id with the OWASP minimums: 19 MiB of memory, 2 passes, 1 lane
+fn config() -> Config<'static> {
+    Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: 19 * 1024,
+        time_cost: 2,
+        lanes: 1,
+        ..Config::default()
+    }
+}
+
+/// The `$argon2id$v=19$m=..,t=..,p=..$` prefix every hash made with the current parameters starts with
+fn current_prefix() -> String {
+    let config = config();
+    format!(
+        "${}$v={}$m={},t={},p={}$",
+        config.variant.as_lowercase_str(),
+        config.version.as_u32(),
+        config.mem_cost,
+        config.time_cost,
+        config.lanes
+    )
+}
+
+/// Hashes a password with a fresh random salt, so equal passwords never share a hash
+pub fn hash(password: &str) -> Result<String, AppError> {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+
+    argon2::hash_encoded(password.as_bytes(), &salt, &config())
+        .map_err(|_| AppError::Any(anyhow::anyhow!("Password hashing failed")))
+}
+
+/// Checks a password against any encoded argon2 hash, including ones made with older parameters
+pub fn verify(encoded: &str, password: &str) -> Result<bool, AppError> {
+    argon2::verify_encoded(encoded, password.as_bytes()).map_err(|_| AppError::InternalServerError)
+}
+
+/// Takes as long as [`verify`] on a real hash, so logins with unknown emails aren't answered any faster
+pub fn verify_nothing(password: &str) {
+    static HASH: Lazy<String> = Lazy::new(|| hash("no account has this password").unwrap());
+    let _ = verify(&HASH, password);
+}
+
+/// Whether a stored hash predates the current parameters and should be replaced after the next login.
+/// That covers the old Argon2i hashes which all shared the `SALT` env var.
+pub fn needs_rehash(encoded: &str) -> bool {
+    !encoded.starts_with(&current_prefix())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How passwords were hashed before per-user salts: Argon2i defaults and one shared salt
+    fn legacy_hash(password: &str) -> String {
+        argon2::hash_encoded(password.as_bytes(), b"saltsaltsalt", &Config::default()).unwrap()
+    }
+
+    #[test]
+    fn legacy_hashes_verify_and_need_a_rehash() {
+        let legacy = legacy_hash("hunter2");
+
+        assert!(legacy.starts_with("$argon2i$"));
+        assert_eq!(legacy, legacy_hash("hunter2"));
+        assert!(verify(&legacy, "hunter2").unwrap());
+        assert!(!verify(&legacy, "hunter3").unwrap());
+        assert!(needs_rehash(&legacy));
+    }
+
+    #[test]
+    fn new_hashes_are_salted_per_user_argon2id() {
+        let first = hash("hunter2").unwrap();
+        let second = hash("hunter2").unwrap();
+
+        assert!(first.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+        assert_ne!(first, second);
+        assert!(verify(&first, "hunter2").unwrap());
+        assert!(verify(&second, "hunter2").unwrap());
+        assert!(!verify(&first, "hunter3").unwrap());
+        assert!(!needs_rehash(&first));
+    }
+
+    #[test]
+    fn unreadable_hashes_are_an_error() {
+        assert!(verify("not a hash", "hunter2").is_err());
+        assert!(needs_rehash("not a hash"));
+    }
+}
@@ -0,0 +1,65 @@
+use axum::routing::*;
+use axum::Router;
+
+use crate::db::Store;
+use crate::handlers;
+
+/// JSON API, nested under `/api/v1` by `main_routes::app`
+pub fn router() -> Router<Store> {
+    Router::new()
+        .route("/neos", get(handlers::get_neos).post(handlers::create_neo))
+        .route("/neos/import", post(handlers::import_neos))
+        .route("/neos/date-range", get(handlers::get_neo_by_date))
+        .route(
+            "/neos/:id",
+            get(handlers::get_neo_record)
+                .patch(handlers::update_neo)
+                .delete(handlers::delete_neo),
+        )
+        .route("/asteroids/:api_id", get(handlers::get_neo_by_id))
+        .route(
+            "/asteroids/:api_id/comments",
+            get(handlers::get_comments).post(handlers::create_comment),
+        )
+        .route(
+            "/comments/:id",
+            patch(handlers::update_comment).delete(handlers::delete_comment),
+        )
+        .route(
+            "/watchlist",
+            get(handlers::get_watchlist).post(handlers::add_to_watchlist),
+        )
+        .route(
+            "/watchlist/:api_id",
+            delete(handlers::remove_from_watchlist),
+        )
+        .route(
+            "/alerts/rules",
+            get(handlers::get_alert_rules).post(handlers::create_alert_rule),
+        )
+        .route("/alerts/rules/:id", delete(handlers::delete_alert_rule))
+        .route("/alerts/inbox", get(handlers::get_inbox))
+        .route("/alerts/inbox/:id/read", post(handlers::mark_inbox_read))
+        .route(
+            "/queries",
+            get(handlers::get_saved_queries).post(handlers::create_saved_query),
+        )
+        .route("/queries/:id", delete(handlers::delete_saved_query))
+        .route(
+            "/queries/:id/results",
+            get(handlers::get_saved_query_results),
+        )
+        .route(
+            "/queries/:id/share",
+            post(handlers::share_saved_query).delete(handlers::revoke_saved_query_share),
+        )
+        .route(
+            "/keys",
+            get(handlers::get_api_keys).post(handlers::create_api_key),
+        )
+        .route("/keys/:id", delete(handlers::delete_api_key))
+        .route(
+            "/calendar/token",
+            get(handlers::get_calendar_token).post(handlers::rotate_calendar_token),
+        )
+}
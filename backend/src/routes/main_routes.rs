@@ -1,36 +1,117 @@
+use axum::middleware;
 use axum::response::Response;
 use axum::routing::*;
 use axum::Router;
 use http::StatusCode;
 use hyper::Body;
-use sqlx::PgPool;
 
 use crate::db::Store;
 use crate::handlers::{admin_page, neo_date_page, neo_id_page, register_page, root};
+use crate::routes::api_v1;
 use crate::{handlers, layers};
 
-pub async fn app(pool: PgPool) -> Router {
-    let db = Store::with_pool(pool);
-
+pub async fn app(db: Store) -> Router {
     let (cors_layer, trace_layer) = layers::get_layers();
 
+    // The JSON paths from before /api/v1, kept until scripts have moved over.
+    // Endpoints added since only exist under /api/v1
+    let deprecated = Router::new()
+        .route("/neos", get(handlers::get_neos))
+        .route("/neos/import", post(handlers::import_neos))
+        .route("/neo/date/", get(handlers::get_neo_by_date))
+        .route("/neo/:neo_id", get(handlers::get_neo_by_id))
+        .route("/neo", post(handlers::create_neo))
+        .route(
+            "/neo/record/:id",
+            patch(handlers::update_neo).delete(handlers::delete_neo),
+        )
+        .route_layer(middleware::from_fn(layers::deprecated));
+
     Router::new()
         // The router matches these FROM TOP TO BOTTOM explicitly!
         .route("/", get(root))
         .route("/register", get(register_page))
         .route("/admin", get(admin_page))
+        .route("/admin/audit", get(handlers::audit_page))
+        .route("/admin/audit.csv", get(handlers::audit_export))
+        .route("/admin/bans", get(handlers::bans_page))
         .route("/ban", post(handlers::ban_user))
-        .route("/neos", get(handlers::get_neos))
-        .route("/neo/date/", get(handlers::get_neo_by_date))
+        .route("/unban", post(handlers::unban_user))
         .route("/neo/date", get(neo_date_page))
-        .route("/neo/:neo_id", get(handlers::get_neo_by_id))
         .route("/neo/id", get(neo_id_page))
-        .route("/neo", post(handlers::create_neo))
+        .route("/events", get(handlers::events))
+        .route("/calendar.ics", get(handlers::calendar_feed))
+        .route("/feeds/hazardous.atom", get(handlers::hazardous_feed))
+        .route("/watchlist", post(handlers::watch_form))
+        .route("/watchlist/remove", post(handlers::unwatch_form))
+        .route("/queries", post(handlers::save_query_form))
+        .route("/queries/:id", get(handlers::saved_query_page))
+        .route("/queries/remove", post(handlers::delete_query_form))
+        .route("/queries/share", post(handlers::share_query_form))
+        .route("/queries/unshare", post(handlers::unshare_query_form))
+        .route("/shared/:token", get(handlers::shared_query_page))
+        .route(
+            "/keys",
+            get(handlers::api_keys_page).post(handlers::create_api_key_form),
+        )
+        .route("/keys/revoke", post(handlers::revoke_api_key_form))
+        .route("/comments", post(handlers::comment_form))
+        .route("/comments/edit", post(handlers::edit_comment_form))
+        .route("/comments/remove", post(handlers::delete_comment_form))
+        .nest("/api/v1", api_v1::router())
+        .merge(deprecated)
         .route("/users", post(handlers::register))
         .route("/users/admin", post(handlers::register_admin))
+        .route("/users/role", post(handlers::set_role_form))
+        .route("/users/unlock", post(handlers::unlock_account_form))
         .route("/login", post(handlers::login))
+        .route("/verify-email", get(handlers::verify_email_page))
+        .route(
+            "/verify-email/resend",
+            post(handlers::resend_verification_form),
+        )
+        .route(
+            "/password/forgot",
+            get(handlers::forgot_password_page).post(handlers::forgot_password_form),
+        )
+        .route(
+            "/password/reset",
+            get(handlers::reset_password_page).post(handlers::reset_password_form),
+        )
+        .route("/auth/oidc/login", get(handlers::oidc_login))
+        .route("/auth/oidc/callback", get(handlers::oidc_callback))
+        .route(
+            "/login/2fa",
+            get(handlers::login_two_factor_page).post(handlers::login_two_factor_form),
+        )
+        .route("/account/2fa", get(handlers::two_factor_page))
+        .route("/account/2fa/setup", post(handlers::two_factor_setup_form))
+        .route(
+            "/account/2fa/enable",
+            post(handlers::two_factor_enable_form),
+        )
+        .route(
+            "/account/2fa/recovery-codes",
+            post(handlers::two_factor_recovery_codes_form),
+        )
+        .route(
+            "/account/2fa/disable",
+            post(handlers::two_factor_disable_form),
+        )
+        .route("/account/settings", get(handlers::settings_page))
+        .route("/account/password", post(handlers::change_password_form))
+        .route("/account/export", get(handlers::export_account_data))
+        .route("/account/delete", post(handlers::delete_account_form))
+        .route("/refresh", post(handlers::refresh))
+        .route("/logout", post(handlers::logout))
         .route("/protected", get(handlers::protected))
         .route("/*_", get(handle_404))
+        .layer(middleware::from_fn_with_state(
+            db.clone(),
+            layers::refresh_session,
+        ))
+        .layer(middleware::from_fn(layers::csrf))
+        .layer(middleware::from_fn(layers::request_id))
         .layer(cors_layer)
         .layer(trace_layer)
         .with_state(db)
@@ -1 +1,2 @@
+pub mod api_v1;
 pub mod main_routes;
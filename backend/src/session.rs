@@ -0,0 +1,340 @@
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use cookie::{Cookie, SameSite};
+use http::header::COOKIE;
+use http::{HeaderMap, HeaderValue};
+use jsonwebtoken::{decode, Header, Validation};
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::models::user::{Claims, SessionUser, KEYS};
+use crate::two_factor;
+use crate::{generate_secret_token, get_timestamp_after, hash_secret_token};
+
+/// Cookie carrying the access token, a JWT
+pub const ACCESS_COOKIE: &str = "jwt";
+/// Cookie carrying the refresh token, a random secret only stored hashed
+pub const REFRESH_COOKIE: &str = "refresh_token";
+
+/// Access tokens are only checked against the revocation list, so they are kept short
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A freshly issued access and refresh token pair
+pub struct Session {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+impl Session {
+    /// `Set-Cookie` values handing both tokens to the browser
+    pub fn cookies(&self) -> [HeaderValue; 2] {
+        [
+            cookie_header(ACCESS_COOKIE, &self.access_token, ACCESS_TOKEN_TTL),
+            cookie_header(REFRESH_COOKIE, &self.refresh_token, REFRESH_TOKEN_TTL),
+        ]
+    }
+}
+
+/// `Set-Cookie` values that make the browser forget both tokens
+pub fn cleared_cookies() -> [HeaderValue; 2] {
+    [
+        cookie_header(ACCESS_COOKIE, "", Duration::ZERO),
+        cookie_header(REFRESH_COOKIE, "", Duration::ZERO),
+    ]
+}
+
+/// Attributes every cookie is set with, so deployments behind HTTPS can tighten them
+#[derive(Debug, PartialEq)]
+pub struct CookieSettings {
+    /// `COOKIE_SECURE`, only send the cookies over HTTPS
+    pub secure: bool,
+    /// `COOKIE_SAMESITE`, `lax` unless set to `strict` or `none`
+    pub same_site: SameSite,
+    /// `COOKIE_DOMAIN`, to share the cookies with subdomains
+    pub domain: Option<String>,
+}
+
+impl CookieSettings {
+    /// Reads the settings through `var`, which looks up an environment variable
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let secure = match var("COOKIE_SECURE").as_deref().map(str::trim) {
+            None | Some("") | Some("false") => false,
+            Some("true") => true,
+            Some(other) => {
+                return Err(format!(
+                    "COOKIE_SECURE must be true or false, not {}",
+                    other
+                ))
+            }
+        };
+        let same_site = match var("COOKIE_SAMESITE")
+            .map(|value| value.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("") | Some("lax") => SameSite::Lax,
+            Some("strict") => SameSite::Strict,
+            Some("none") => SameSite::None,
+            Some(other) => {
+                return Err(format!(
+                    "COOKIE_SAMESITE must be lax, strict or none, not {}",
+                    other
+                ))
+            }
+        };
+        // browsers drop SameSite=None cookies that aren't Secure
+        if same_site == SameSite::None && !secure {
+            return Err("COOKIE_SAMESITE=none needs COOKIE_SECURE=true".to_string());
+        }
+        let domain = var("COOKIE_DOMAIN")
+            .map(|domain| domain.trim().to_string())
+            .filter(|domain| !domain.is_empty());
+
+        Ok(CookieSettings {
+            secure,
+            same_site,
+            domain,
+        })
+    }
+}
+
+/// Read once, `run_backend` checks them at startup
+pub static COOKIE_SETTINGS: Lazy<CookieSettings> = Lazy::new(|| {
+    CookieSettings::from_vars(|name| std::env::var(name).ok())
+        .unwrap_or_else(|err| panic!("Invalid cookie settings: {}", err))
+});
+
+pub(crate) fn cookie_header(name: &str, value: &str, max_age: Duration) -> HeaderValue {
+    let settings = &*COOKIE_SETTINGS;
+    let mut cookie = Cookie::build(name, value)
+        .path("/")
+        .http_only(true)
+        .secure(settings.secure)
+        .same_site(settings.same_site)
+        .max_age(cookie::time::Duration::seconds(max_age.as_secs() as i64))
+        .finish();
+    if let Some(domain) = &settings.domain {
+        cookie.set_domain(domain.clone());
+    }
+
+    HeaderValue::from_str(&cookie.to_string()).expect("cookies are valid header values")
+}
+
+/// The value of the cookie called `name`, looking through every `Cookie` header
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// A `Cookie` header with the session cookies replaced by `session`, keeping every other cookie
+pub fn replace_session_cookies(headers: &HeaderMap, session: &Session) -> HeaderValue {
+    let mut cookies: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .filter(|cookie| cookie.name() != ACCESS_COOKIE && cookie.name() != REFRESH_COOKIE)
+        .map(|cookie| cookie.to_string())
+        .collect();
+    cookies.push(format!("{}={}", ACCESS_COOKIE, session.access_token));
+    cookies.push(format!("{}={}", REFRESH_COOKIE, session.refresh_token));
+
+    HeaderValue::from_str(&cookies.join("; ")).expect("cookies are valid header values")
+}
+
+/// Signs a short lived access token for `user` with a fresh `jti`
+pub fn access_token(user: &SessionUser) -> Result<String, AppError> {
+    let claims = Claims {
+        id: user.id,
+        email: user.email.clone(),
+        role: user.role.parse().map_err(AppError::InvalidRole)?,
+        banned: user.banned,
+        token_version: user.token_version,
+        exp: get_timestamp_after(ACCESS_TOKEN_TTL),
+        jti: Uuid::new_v4().to_string(),
+    };
+
+    jsonwebtoken::encode(&Header::default(), &claims, &KEYS.encoding)
+        .map_err(|_| AppError::MissingCredentials)
+}
+
+/// Checks the signature and expiry of an access token. Revocation needs the database, see [`Claims`]
+pub fn decode_access_token(token: &str) -> Result<Claims, AppError> {
+    decode::<Claims>(token, &KEYS.decoding, &Validation::default())
+        .map(|data| data.claims)
+        .map_err(|_| AppError::InvalidToken)
+}
+
+/// Logs `email` in, starting a new refresh token family
+pub async fn start(store: &Store, email: &str) -> Result<Session, AppError> {
+    let user = store.get_session_user(email).await?;
+    let refresh_token = generate_secret_token();
+    store
+        .create_refresh_token(
+            user.id,
+            &hash_secret_token(&refresh_token),
+            &Uuid::new_v4().to_string(),
+            Utc::now() + chrono::Duration::from_std(REFRESH_TOKEN_TTL).unwrap(),
+        )
+        .await?;
+
+    Ok(Session {
+        access_token: access_token(&user)?,
+        refresh_token,
+    })
+}
+
+/// Trades a refresh token for a new pair. The old refresh token stops working
+pub async fn refresh(store: &Store, refresh_token: &str) -> Result<Option<Session>, AppError> {
+    let next_token = generate_secret_token();
+    let Some(user) = store
+        .rotate_refresh_token(
+            &hash_secret_token(refresh_token),
+            &hash_secret_token(&next_token),
+            Utc::now() + chrono::Duration::from_std(REFRESH_TOKEN_TTL).unwrap(),
+        )
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    // Sessions from before the policy applied to them end here, logging in again sets up 2FA
+    let role = user.role.parse().map_err(AppError::InvalidRole)?;
+    if two_factor::required_for(&role) && !user.two_factor_enabled {
+        return Err(AppError::TwoFactorRequired);
+    }
+
+    Ok(Some(Session {
+        access_token: access_token(&user)?,
+        refresh_token: next_token,
+    }))
+}
+
+/// Revokes the access token until it would have expired anyway, and the refresh token's whole family
+pub async fn end(
+    store: &Store,
+    claims: Option<&Claims>,
+    refresh_token: Option<&str>,
+) -> Result<(), AppError> {
+    if let Some(claims) = claims {
+        if let Some(expires_at) = Utc.timestamp_opt(claims.exp as i64, 0).single() {
+            store.revoke_access_token(&claims.jti, expires_at).await?;
+        }
+    }
+    if let Some(refresh_token) = refresh_token {
+        store
+            .revoke_refresh_token_family(&hash_secret_token(refresh_token))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::role::Role;
+
+    #[test]
+    fn access_tokens_are_short_lived_and_unique() {
+        std::env::set_var("JWT_SECRET", "test secret");
+        let user = SessionUser {
+            id: 7,
+            email: "user@example.com".to_string(),
+            role: "analyst".to_string(),
+            token_version: 3,
+            two_factor_enabled: false,
+            banned: false,
+        };
+
+        let first = decode_access_token(&access_token(&user).unwrap()).unwrap();
+        let second = decode_access_token(&access_token(&user).unwrap()).unwrap();
+
+        assert_eq!(first.id, 7);
+        assert_eq!(first.email, "user@example.com");
+        assert_eq!(first.role, Role::Analyst);
+        assert_eq!(first.token_version, 3);
+        assert_ne!(first.jti, second.jti);
+        assert!(first.exp <= get_timestamp_after(ACCESS_TOKEN_TTL));
+        assert!(decode_access_token("not.a.token").is_err());
+    }
+
+    #[test]
+    fn session_cookies_are_http_only_and_found_among_others() {
+        let session = Session {
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+        };
+        let [access, refresh] = session.cookies();
+        let access = access.to_str().unwrap();
+        assert!(access.starts_with("jwt=access;"));
+        assert!(access.contains("HttpOnly"));
+        assert!(access.contains("Path=/"));
+        assert!(refresh.to_str().unwrap().contains("Max-Age=2592000"));
+        assert!(cleared_cookies()[1].to_str().unwrap().contains("Max-Age=0"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("theme=dark; refresh_token=old; jwt=expired"),
+        );
+        assert_eq!(
+            cookie_value(&headers, REFRESH_COOKIE).as_deref(),
+            Some("old")
+        );
+        assert_eq!(cookie_value(&headers, "missing"), None);
+
+        headers.insert(COOKIE, replace_session_cookies(&headers, &session));
+        assert_eq!(
+            headers.get(COOKIE).unwrap(),
+            "theme=dark; jwt=access; refresh_token=refresh"
+        );
+    }
+
+    #[test]
+    fn cookie_settings_default_to_lax_and_check_their_values() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        assert_eq!(
+            CookieSettings::from_vars(vars(&[])).unwrap(),
+            CookieSettings {
+                secure: false,
+                same_site: SameSite::Lax,
+                domain: None,
+            }
+        );
+        assert_eq!(
+            CookieSettings::from_vars(vars(&[
+                ("COOKIE_SECURE", "true"),
+                ("COOKIE_SAMESITE", "Strict"),
+                ("COOKIE_DOMAIN", "example.com"),
+            ]))
+            .unwrap(),
+            CookieSettings {
+                secure: true,
+                same_site: SameSite::Strict,
+                domain: Some("example.com".to_string()),
+            }
+        );
+        assert!(CookieSettings::from_vars(vars(&[("COOKIE_SAMESITE", "none")])).is_err());
+        assert!(CookieSettings::from_vars(vars(&[("COOKIE_SAMESITE", "sometimes")])).is_err());
+        assert!(CookieSettings::from_vars(vars(&[("COOKIE_SECURE", "yes")])).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use tracing::{error, info};
+
+use crate::db::Store;
+
+/// Periodically refreshes watched asteroids whose stored data has gone stale.
+/// The age limit comes from `WATCHLIST_STALE_HOURS` and defaults to a day.
+pub fn spawn_watchlist_refresh(store: Store) {
+    let stale_hours: i64 = std::env::var("WATCHLIST_STALE_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(24);
+    let max_age = chrono::Duration::hours(stale_hours);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            // Each round runs as its own task, so even a panic only costs that round
+            let mut round_store = store.clone();
+            let round =
+                tokio::spawn(async move { round_store.refresh_stale_watched(max_age).await });
+            match round.await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(refreshed)) => {
+                    info!("Refreshed {} watched asteroids from NeoWs", refreshed)
+                }
+                Ok(Err(err)) => error!("Watchlist refresh failed: {:?}", err),
+                Err(err) => error!("Watchlist refresh panicked: {:?}", err),
+            }
+        }
+    });
+}
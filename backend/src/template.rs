@@ -10,6 +10,7 @@ lazy_static::lazy_static! {
             }
         };
         tera.autoescape_on(vec![".html", ".sql"]);
+        tera.register_function("csrf_token", crate::csrf::template_function);
         tera
     };
 }
@@ -0,0 +1,162 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// Failures this long after the previous one start counting from one again
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// How long a lockout lasts. Every failure after it locks again, until a login succeeds or an admin unlocks
+pub const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Failures allowed before attempts are spaced out
+const FREE_ATTEMPTS: i32 = 3;
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Counts of failed logins are kept per account and per client address
+#[derive(Clone, Copy, Debug)]
+pub enum Subject<'a> {
+    Account(&'a str),
+    Ip(IpAddr),
+}
+
+impl Subject<'_> {
+    /// Key of the subject's row in `login_throttles`. Unknown emails are tracked the same as real ones,
+    /// so the answers don't reveal which accounts exist
+    pub fn key(&self) -> String {
+        match self {
+            Subject::Account(email) => format!("account:{}", email.trim().to_lowercase()),
+            Subject::Ip(ip) => format!("ip:{}", ip),
+        }
+    }
+
+    /// Failures that lock the subject out. Many people can share an address, so that takes more
+    pub fn lockout_threshold(&self) -> i32 {
+        match self {
+            Subject::Account(_) => 10,
+            Subject::Ip(_) => 50,
+        }
+    }
+}
+
+/// The email address of an `account:` key, for listing locked accounts
+pub fn account_email(key: &str) -> Option<&str> {
+    key.strip_prefix("account:")
+}
+
+/// How long to wait after the last of `failures` failed attempts: nothing for the first few,
+/// then doubling from a second up to [`MAX_DELAY`]
+pub fn delay_after(failures: i32) -> Duration {
+    if failures < FREE_ATTEMPTS {
+        return Duration::ZERO;
+    }
+    let doublings = (failures - FREE_ATTEMPTS).min(16) as u32;
+    Duration::from_secs(1 << doublings).min(MAX_DELAY)
+}
+
+/// A row of `login_throttles`
+#[derive(Clone, Debug, serde_derive::Serialize, sqlx::FromRow)]
+pub struct LoginThrottle {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginThrottle {
+    /// How long until the next attempt is allowed, if it isn't yet
+    pub fn retry_after(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let delayed_until =
+            self.last_failure_at + chrono::Duration::from_std(delay_after(self.failures)).unwrap();
+        let until = match self.locked_until {
+            Some(locked_until) if locked_until > delayed_until => locked_until,
+            _ => delayed_until,
+        };
+
+        (until - now).to_std().ok().filter(|wait| !wait.is_zero())
+    }
+}
+
+/// A locked account, as listed for admins
+#[derive(Debug, serde_derive::Serialize)]
+pub struct LockedAccount {
+    pub email: String,
+    pub failures: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LockedAccount {
+    /// `None` for throttles that aren't an account's
+    pub fn from_throttle(throttle: LoginThrottle) -> Option<Self> {
+        Some(LockedAccount {
+            email: account_email(&throttle.key)?.to_string(),
+            failures: throttle.failures,
+            locked_until: throttle.locked_until,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn throttle(failures: i32, locked_until: Option<DateTime<Utc>>) -> LoginThrottle {
+        LoginThrottle {
+            key: Subject::Account("User@Example.com ").key(),
+            failures,
+            last_failure_at: Utc.with_ymd_and_hms(2024, 3, 20, 12, 0, 0).unwrap(),
+            locked_until,
+        }
+    }
+
+    #[test]
+    fn delays_double_after_the_free_attempts() {
+        assert_eq!(delay_after(1), Duration::ZERO);
+        assert_eq!(delay_after(2), Duration::ZERO);
+        assert_eq!(delay_after(3), Duration::from_secs(1));
+        assert_eq!(delay_after(4), Duration::from_secs(2));
+        assert_eq!(delay_after(7), Duration::from_secs(16));
+        assert_eq!(delay_after(8), MAX_DELAY);
+        assert_eq!(delay_after(1000), MAX_DELAY);
+    }
+
+    #[test]
+    fn retry_after_covers_delays_and_lockouts() {
+        let last = Utc.with_ymd_and_hms(2024, 3, 20, 12, 0, 0).unwrap();
+
+        assert_eq!(throttle(2, None).retry_after(last), None);
+        assert_eq!(
+            throttle(4, None).retry_after(last),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            throttle(4, None).retry_after(last + chrono::Duration::seconds(2)),
+            None
+        );
+
+        let locked = throttle(10, Some(last + chrono::Duration::minutes(15)));
+        assert_eq!(
+            locked.retry_after(last + chrono::Duration::minutes(5)),
+            Some(Duration::from_secs(10 * 60))
+        );
+        assert_eq!(
+            locked.retry_after(last + chrono::Duration::minutes(15)),
+            None
+        );
+    }
+
+    #[test]
+    fn keys_ignore_email_case() {
+        assert_eq!(throttle(1, None).key, "account:user@example.com");
+        assert_eq!(
+            account_email(&throttle(1, None).key),
+            Some("user@example.com")
+        );
+        assert_eq!(
+            Subject::Ip("10.0.0.1".parse().unwrap()).key(),
+            "ip:10.0.0.1"
+        );
+        assert_eq!(account_email("ip:10.0.0.1"), None);
+    }
+}
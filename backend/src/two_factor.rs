@@ -0,0 +1,274 @@
+use std::time::Duration;
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use http::HeaderValue;
+use jsonwebtoken::{decode, encode, Header, Validation};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::error::AppError;
+use crate::models::role::Role;
+use crate::models::user::KEYS;
+use crate::session::cookie_header;
+use crate::{get_timestamp_after, hash_secret_token};
+
+/// Name authenticator apps list the account under
+const ISSUER: &str = "Earths Close Calls";
+
+/// RFC 6238 defaults, the only parameters every authenticator app supports
+const DIGITS: u32 = 6;
+const STEP_SECONDS: u64 = 30;
+/// 160 bits, the length RFC 4226 recommends for HMAC-SHA1
+const SECRET_LENGTH: usize = 20;
+
+/// Codes from one step either side of now are accepted, for phones whose clock is a little off
+const ALLOWED_DRIFT: u64 = 1;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Cookie remembering who got the password right while they enter their code
+pub const LOGIN_COOKIE: &str = "two_factor_login";
+pub const LOGIN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Audience of the login cookie's token, so it's never taken for an access token
+const LOGIN_AUDIENCE: &str = "two_factor_login";
+
+/// Whether `REQUIRE_ADMIN_2FA` makes two-factor authentication mandatory for `role`
+pub fn required_for(role: &Role) -> bool {
+    *role == Role::Admin
+        && std::env::var("REQUIRE_ADMIN_2FA")
+            .map(|value| value == "true")
+            .unwrap_or(false)
+}
+
+/// A new random secret, base32 encoded the way authenticator apps expect it
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// RFC 4226 HOTP value of `secret` for `counter`
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+fn decode_secret(secret: &str) -> Result<Vec<u8>, AppError> {
+    BASE32_NOPAD
+        .decode(secret.as_bytes())
+        .map_err(|_| AppError::Any(anyhow::anyhow!("Stored TOTP secret is not base32")))
+}
+
+/// The code for the 30 second time step `step`
+pub fn code_at(secret: &str, step: u64) -> Result<String, AppError> {
+    Ok(format!(
+        "{:0width$}",
+        hotp(&decode_secret(secret)?, step),
+        width = DIGITS as usize
+    ))
+}
+
+/// The time step `code` belongs to if it's valid around `now` (seconds since the epoch).
+/// Steps up to `last_step` were already used and are rejected, so an overheard code can't be replayed
+pub fn verify_code(
+    secret: &str,
+    code: &str,
+    now: u64,
+    last_step: Option<i64>,
+) -> Result<Option<u64>, AppError> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+
+    let current = now / STEP_SECONDS;
+    for step in current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT {
+        if last_step.is_some_and(|last| step as i64 <= last) {
+            continue;
+        }
+        if code_at(secret, step)? == code {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+/// The `otpauth://` URI authenticator apps enrol from, usually scanned as a QR code
+pub fn provisioning_uri(secret: &str, email: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("otpauth URIs parse");
+    uri.set_path(&format!("{}:{}", ISSUER, email));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    uri.to_string()
+}
+
+/// `uri` as an inline SVG QR code
+pub fn qr_svg(uri: &str) -> Result<String, AppError> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|err| AppError::Any(err.into()))?;
+    Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+/// Fresh recovery codes, `xxxxx-xxxxx` so they are easy to copy down
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = OsRng
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// What is stored of a recovery code. Case, spaces and dashes don't matter when it's typed back in
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_secret_token(&normalized)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    sub: i32,
+    aud: String,
+    exp: u64,
+}
+
+/// Signs the login cookie's token for a user whose password was right
+pub fn sign_pending_login(user_id: i32) -> Result<String, AppError> {
+    let claims = PendingLogin {
+        sub: user_id,
+        aud: LOGIN_AUDIENCE.to_string(),
+        exp: get_timestamp_after(LOGIN_TTL),
+    };
+
+    encode(&Header::default(), &claims, &KEYS.encoding).map_err(|_| AppError::InternalServerError)
+}
+
+/// The id of the user half way through logging in
+pub fn verify_pending_login(token: &str) -> Result<i32, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[LOGIN_AUDIENCE]);
+
+    decode::<PendingLogin>(token, &KEYS.decoding, &validation)
+        .map(|data| data.claims.sub)
+        .map_err(|_| AppError::InvalidToken)
+}
+
+pub fn login_cookie(token: &str) -> HeaderValue {
+    cookie_header(LOGIN_COOKIE, token, LOGIN_TTL)
+}
+
+pub fn cleared_login_cookie() -> HeaderValue {
+    cookie_header(LOGIN_COOKIE, "", Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RFC 6238 appendix B secret for SHA-1, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn codes_match_the_rfc_test_vectors() {
+        // Appendix B lists 8 digit values, the last 6 digits are the 6 digit codes
+        assert_eq!(code_at(RFC_SECRET, 59 / 30).unwrap(), "287082");
+        assert_eq!(code_at(RFC_SECRET, 1111111109 / 30).unwrap(), "081804");
+        assert_eq!(code_at(RFC_SECRET, 1234567890 / 30).unwrap(), "005924");
+        assert_eq!(code_at(RFC_SECRET, 20000000000 / 30).unwrap(), "353130");
+    }
+
+    #[test]
+    fn codes_allow_a_little_drift_but_no_replay() {
+        let now = 1111111109;
+        let step = now / STEP_SECONDS;
+
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", now, None).unwrap(),
+            Some(step)
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081 804", now + 30, None).unwrap(),
+            Some(step)
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", now + 90, None).unwrap(),
+            None
+        );
+        assert_eq!(
+            verify_code(RFC_SECRET, "081804", now, Some(step as i64)).unwrap(),
+            None
+        );
+        assert_eq!(verify_code(RFC_SECRET, "abcdef", now, None).unwrap(), None);
+    }
+
+    #[test]
+    fn secrets_and_uris_are_what_authenticators_expect() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert_ne!(secret, generate_secret());
+        assert!(code_at(&secret, 1).is_ok());
+
+        let uri = provisioning_uri("ABC", "user@example.com");
+        assert!(uri.starts_with("otpauth://totp/Earths%20Close%20Calls:user@example.com?"));
+        assert!(
+            uri.contains("secret=ABC&issuer=Earths+Close+Calls&algorithm=SHA1&digits=6&period=30")
+        );
+        assert!(qr_svg(&uri).unwrap().starts_with("<?xml"));
+    }
+
+    #[test]
+    fn recovery_codes_are_unique_and_forgiving_to_type() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(codes[0].len(), 11);
+        assert_ne!(codes[0], codes[1]);
+
+        assert_eq!(
+            hash_recovery_code("abcde-12345"),
+            hash_recovery_code(" ABCDE 12345 ")
+        );
+        assert_ne!(
+            hash_recovery_code("abcde-12345"),
+            hash_recovery_code("abcde-12346")
+        );
+    }
+
+    #[test]
+    fn pending_logins_are_not_access_tokens() {
+        std::env::set_var("JWT_SECRET", "test secret");
+
+        let token = sign_pending_login(7).unwrap();
+        assert_eq!(verify_pending_login(&token).unwrap(), 7);
+        assert!(crate::session::decode_access_token(&token).is_err());
+        assert!(verify_pending_login("not.a.token").is_err());
+    }
+}